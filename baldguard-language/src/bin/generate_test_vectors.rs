@@ -0,0 +1,26 @@
+//! Prints the language test vectors with the results of the current
+//! interpreter as JSON. After a deliberate change of the semantics, review the
+//! differences and check them in:
+//!
+//! `cargo run -p baldguard-language --bin generate_test_vectors > baldguard-language/test_vectors.json`
+
+use baldguard_language::vectors;
+use std::process::exit;
+
+fn main() {
+    let vectors = match vectors::generate() {
+        Ok(vectors) => vectors,
+        Err(e) => {
+            eprintln!("Failed to generate test vectors: {e}");
+            exit(1)
+        }
+    };
+
+    match serde_json::to_string_pretty(&vectors) {
+        Ok(json) => println!("{json}"),
+        Err(e) => {
+            eprintln!("Failed to serialize test vectors: {e}");
+            exit(1)
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use super::{
+    environment::Sandbox,
+    evaluation::{EvaluationError, EvaluationResult, Value, ValueError, ValueResult},
+};
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, TimeDelta, Timelike, Utc,
+};
+use unicode_normalization::UnicodeNormalization;
+
+const ZERO_WIDTH_CHARACTERS: &[char] = &[
+    '\u{00AD}', '\u{180E}', '\u{200B}', '\u{200C}', '\u{200D}', '\u{2060}', '\u{FEFF}',
+];
+
+const CONFUSABLES: &[(char, char)] = &[
+    // Cyrillic
+    ('а', 'a'),
+    ('в', 'b'),
+    ('е', 'e'),
+    ('к', 'k'),
+    ('м', 'm'),
+    ('н', 'h'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('с', 'c'),
+    ('т', 't'),
+    ('у', 'y'),
+    ('х', 'x'),
+    ('і', 'i'),
+    ('ј', 'j'),
+    ('ѕ', 's'),
+    ('ԁ', 'd'),
+    ('ԛ', 'q'),
+    ('ԝ', 'w'),
+    ('А', 'A'),
+    ('В', 'B'),
+    ('Е', 'E'),
+    ('К', 'K'),
+    ('М', 'M'),
+    ('Н', 'H'),
+    ('О', 'O'),
+    ('Р', 'P'),
+    ('С', 'C'),
+    ('Т', 'T'),
+    ('У', 'Y'),
+    ('Х', 'X'),
+    ('І', 'I'),
+    ('Ј', 'J'),
+    ('Ѕ', 'S'),
+    // Greek
+    ('α', 'a'),
+    ('ο', 'o'),
+    ('ν', 'v'),
+    ('ι', 'i'),
+    ('Α', 'A'),
+    ('Β', 'B'),
+    ('Ε', 'E'),
+    ('Ζ', 'Z'),
+    ('Η', 'H'),
+    ('Ι', 'I'),
+    ('Κ', 'K'),
+    ('Μ', 'M'),
+    ('Ν', 'N'),
+    ('Ο', 'O'),
+    ('Ρ', 'P'),
+    ('Τ', 'T'),
+    ('Υ', 'Y'),
+    ('Χ', 'X'),
+];
+
+fn fold_confusable(ch: char) -> char {
+    match CONFUSABLES.iter().find(|(confusable, _)| *confusable == ch) {
+        Some((_, latin)) => *latin,
+        None => ch,
+    }
+}
+
+fn normalize(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [Value::Str(text)] => Ok(Value::Str(
+            text.nfkc()
+                .filter(|ch| !ZERO_WIDTH_CHARACTERS.contains(ch))
+                .map(fold_confusable)
+                .collect(),
+        )),
+        _ => Err(ValueError::new_invalid_arguments("normalize", arguments)),
+    }
+}
+
+fn word_count(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [Value::Str(text)] => Ok(Value::Int(text.split_whitespace().count() as i64)),
+        _ => Err(ValueError::new_invalid_arguments("word_count", arguments)),
+    }
+}
+
+fn caps_ratio(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [Value::Str(text)] => {
+            let letters = text.chars().filter(|ch| ch.is_alphabetic()).count();
+            let uppercase = text.chars().filter(|ch| ch.is_uppercase()).count();
+            Ok(Value::Int(
+                (uppercase * 100).checked_div(letters).unwrap_or(0) as i64,
+            ))
+        }
+        _ => Err(ValueError::new_invalid_arguments("caps_ratio", arguments)),
+    }
+}
+
+fn is_emoji(ch: char) -> bool {
+    matches!(
+        ch,
+        '\u{1F000}'..='\u{1FAFF}' | '\u{2600}'..='\u{27BF}' | '\u{2B00}'..='\u{2BFF}'
+    )
+}
+
+fn emoji_count(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [Value::Str(text)] => Ok(Value::Int(
+            text.chars().filter(|ch| is_emoji(*ch)).count() as i64
+        )),
+        _ => Err(ValueError::new_invalid_arguments("emoji_count", arguments)),
+    }
+}
+
+fn now(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [] => Ok(Value::DateTime(Utc::now())),
+        _ => Err(ValueError::new_invalid_arguments("now", arguments)),
+    }
+}
+
+fn datetime(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [Value::Str(text)] => match DateTime::parse_from_rfc3339(text) {
+            Ok(value) => Ok(Value::DateTime(value.with_timezone(&Utc))),
+            Err(e) => Err(ValueError::new_other(format!(
+                "invalid datetime \"{text}\": {e}"
+            ))),
+        },
+        _ => Err(ValueError::new_invalid_arguments("datetime", arguments)),
+    }
+}
+
+fn hour(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [Value::DateTime(value)] => Ok(Value::Int(value.hour() as i64)),
+        _ => Err(ValueError::new_invalid_arguments("hour", arguments)),
+    }
+}
+
+fn format_datetime(arguments: &[Value]) -> ValueResult {
+    match arguments {
+        [Value::DateTime(value), Value::Str(format)] => {
+            let items: Vec<Item> = StrftimeItems::new(format).collect();
+            if items.iter().any(|item| matches!(item, Item::Error)) {
+                return Err(ValueError::new_other(format!(
+                    "invalid datetime format \"{format}\""
+                )));
+            }
+
+            Ok(Value::Str(
+                value.format_with_items(items.into_iter()).to_string(),
+            ))
+        }
+        _ => Err(ValueError::new_invalid_arguments(
+            "format_datetime",
+            arguments,
+        )),
+    }
+}
+
+pub fn parse_duration(value: &Value) -> Result<TimeDelta, ValueError> {
+    let invalid = || ValueError::new_other(format!("invalid duration \"{value}\""));
+
+    match value {
+        Value::Int(seconds) => TimeDelta::try_seconds(*seconds).ok_or_else(invalid),
+        Value::Str(text) => {
+            let split = text
+                .find(|ch: char| !ch.is_ascii_digit())
+                .ok_or_else(invalid)?;
+            let (amount, unit) = text.split_at(split);
+            let amount: i64 = amount.parse().map_err(|_| invalid())?;
+            let delta = match unit {
+                "s" => TimeDelta::try_seconds(amount),
+                "m" => TimeDelta::try_minutes(amount),
+                "h" => TimeDelta::try_hours(amount),
+                "d" => TimeDelta::try_days(amount),
+                _ => None,
+            };
+            delta.ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+fn count(arguments: &[Value], sandbox: &Sandbox) -> ValueResult {
+    match arguments {
+        [Value::Str(event), window @ (Value::Int(_) | Value::Str(_))] => {
+            Ok(Value::Int(sandbox.count(event, parse_duration(window)?)?))
+        }
+        _ => Err(ValueError::new_invalid_arguments("count", arguments)),
+    }
+}
+
+fn cooldown(arguments: &[Value], sandbox: &Sandbox) -> ValueResult {
+    match arguments {
+        [key @ (Value::Int(_) | Value::Str(_)), duration @ (Value::Int(_) | Value::Str(_))] => Ok(
+            Value::Bool(sandbox.cooldown(&key.to_string(), parse_duration(duration)?)?),
+        ),
+        _ => Err(ValueError::new_invalid_arguments("cooldown", arguments)),
+    }
+}
+
+fn counter_name(arguments: &[Value]) -> Option<String> {
+    match arguments {
+        [Value::Str(name)] => Some(name.clone()),
+        [Value::Str(name), key @ (Value::Int(_) | Value::Str(_))] => Some(format!("{name}:{key}")),
+        _ => None,
+    }
+}
+
+fn inc(arguments: &[Value], sandbox: &Sandbox) -> ValueResult {
+    match counter_name(arguments) {
+        Some(name) => Ok(Value::Int(sandbox.increment_counter(&name)?)),
+        None => Err(ValueError::new_invalid_arguments("inc", arguments)),
+    }
+}
+
+fn get_counter(arguments: &[Value], sandbox: &Sandbox) -> ValueResult {
+    match counter_name(arguments) {
+        Some(name) => Ok(Value::Int(sandbox.counter(&name)?)),
+        None => Err(ValueError::new_invalid_arguments("get_counter", arguments)),
+    }
+}
+
+pub fn call(function: &str, arguments: &[Value], sandbox: &Sandbox) -> EvaluationResult {
+    match function {
+        "normalize" => Ok(normalize(arguments)?),
+        "word_count" => Ok(word_count(arguments)?),
+        "caps_ratio" => Ok(caps_ratio(arguments)?),
+        "emoji_count" => Ok(emoji_count(arguments)?),
+        "now" => Ok(now(arguments)?),
+        "datetime" => Ok(datetime(arguments)?),
+        "hour" => Ok(hour(arguments)?),
+        "format_datetime" => Ok(format_datetime(arguments)?),
+        "count" => Ok(count(arguments, sandbox)?),
+        "cooldown" => Ok(cooldown(arguments, sandbox)?),
+        "inc" => Ok(inc(arguments, sandbox)?),
+        "get_counter" => Ok(get_counter(arguments, sandbox)?),
+        _ => Err(EvaluationError::UndeclaredFunction(function.to_string())),
+    }
+}
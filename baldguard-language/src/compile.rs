@@ -0,0 +1,448 @@
+use super::{
+    environment::Sandbox,
+    evaluation::{
+        apply_binary, apply_call, apply_comparisons, apply_logical, apply_unary, is_logical,
+        logical_operand, no_match_case, take_step, EvaluationError, EvaluationOptions,
+        EvaluationResult, Value, Variables,
+    },
+    tree::{Expression, Literal, Operator},
+};
+use regex::{Regex, RegexSet};
+use std::{cell::Cell, collections::HashMap};
+
+/// Expression prepared for repeated evaluation: identifiers are resolved to
+/// slots looked up once per evaluation, literals are converted to values and
+/// regexes given as literals are compiled ahead of time.
+///
+/// Evaluating a compiled expression gives the same results and errors as
+/// `evaluate_in_sandbox` on the expression it was compiled from, and takes
+/// the same steps of the budget.
+#[derive(Debug, Clone)]
+pub struct CompiledExpression {
+    root: Node,
+    slots: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Slot(usize),
+    Constant(Value),
+    BinaryOp {
+        left: Box<Node>,
+        operator: Operator,
+        right: Box<Node>,
+    },
+    UnaryOp {
+        operand: Box<Node>,
+        operator: Operator,
+    },
+    Matches {
+        left: Box<Node>,
+        pattern: String,
+        regex: Regex,
+    },
+    MatchesAny {
+        left: Box<Node>,
+        patterns: Vec<String>,
+        set: RegexSet,
+    },
+    Call {
+        function: String,
+        arguments: Vec<Node>,
+    },
+    List(Vec<Node>),
+    Match {
+        expression: Box<Node>,
+        cases: Vec<(Value, Node)>,
+        default: Option<Box<Node>>,
+    },
+    Comparison {
+        first: Box<Node>,
+        comparisons: Vec<(Operator, Node)>,
+    },
+}
+
+struct Compiler {
+    slots: Vec<String>,
+    slot_indices: HashMap<String, usize>,
+}
+
+impl Compiler {
+    fn slot(&mut self, identifier: &str) -> usize {
+        if let Some(index) = self.slot_indices.get(identifier) {
+            return *index;
+        }
+
+        let index = self.slots.len();
+        self.slots.push(identifier.to_string());
+        self.slot_indices.insert(identifier.to_string(), index);
+        index
+    }
+
+    fn compile(&mut self, expression: &Expression) -> Node {
+        match expression {
+            Expression::Identifier(identifier) => Node::Slot(self.slot(identifier)),
+            Expression::Literal(literal) => Node::Constant(Value::from(literal.clone())),
+            Expression::BinaryOp {
+                left,
+                operator: Operator::Matches,
+                right,
+            } => match right.as_ref() {
+                Expression::Literal(Literal::Str(pattern)) => match Regex::new(pattern) {
+                    Ok(regex) => Node::Matches {
+                        left: Box::new(self.compile(left)),
+                        pattern: pattern.clone(),
+                        regex,
+                    },
+                    Err(_) => self.compile_binary(left, &Operator::Matches, right),
+                },
+                _ => self.compile_binary(left, &Operator::Matches, right),
+            },
+            Expression::BinaryOp {
+                left,
+                operator: Operator::MatchesAny,
+                right,
+            } => match literal_strings(right) {
+                Some(patterns) => match RegexSet::new(&patterns) {
+                    Ok(set) => Node::MatchesAny {
+                        left: Box::new(self.compile(left)),
+                        patterns,
+                        set,
+                    },
+                    Err(_) => self.compile_binary(left, &Operator::MatchesAny, right),
+                },
+                None => self.compile_binary(left, &Operator::MatchesAny, right),
+            },
+            Expression::BinaryOp {
+                left,
+                operator,
+                right,
+            } => self.compile_binary(left, operator, right),
+            Expression::UnaryOp {
+                expression,
+                operator,
+            } => Node::UnaryOp {
+                operand: Box::new(self.compile(expression)),
+                operator: operator.clone(),
+            },
+            Expression::Call {
+                function,
+                arguments,
+            } => Node::Call {
+                function: function.clone(),
+                arguments: arguments
+                    .iter()
+                    .map(|argument| self.compile(argument))
+                    .collect(),
+            },
+            Expression::List(elements) => Node::List(
+                elements
+                    .iter()
+                    .map(|element| self.compile(element))
+                    .collect(),
+            ),
+            Expression::Match {
+                expression,
+                cases,
+                default,
+            } => Node::Match {
+                expression: Box::new(self.compile(expression)),
+                cases: cases
+                    .iter()
+                    .map(|(pattern, case)| (Value::from(pattern.clone()), self.compile(case)))
+                    .collect(),
+                default: default
+                    .as_ref()
+                    .map(|default| Box::new(self.compile(default))),
+            },
+            Expression::Comparison { first, comparisons } => Node::Comparison {
+                first: Box::new(self.compile(first)),
+                comparisons: comparisons
+                    .iter()
+                    .map(|(operator, operand)| (operator.clone(), self.compile(operand)))
+                    .collect(),
+            },
+        }
+    }
+
+    fn compile_binary(
+        &mut self,
+        left: &Expression,
+        operator: &Operator,
+        right: &Expression,
+    ) -> Node {
+        Node::BinaryOp {
+            left: Box::new(self.compile(left)),
+            operator: operator.clone(),
+            right: Box::new(self.compile(right)),
+        }
+    }
+}
+
+fn literal_strings(expression: &Expression) -> Option<Vec<String>> {
+    match expression {
+        Expression::List(elements) => elements
+            .iter()
+            .map(|element| match element {
+                Expression::Literal(Literal::Str(pattern)) => Some(pattern.clone()),
+                _ => None,
+            })
+            .collect(),
+        _ => None,
+    }
+}
+
+impl CompiledExpression {
+    pub fn compile(expression: &Expression) -> Self {
+        let mut compiler = Compiler {
+            slots: Vec::new(),
+            slot_indices: HashMap::new(),
+        };
+        let root = compiler.compile(expression);
+
+        CompiledExpression {
+            root,
+            slots: compiler.slots,
+        }
+    }
+
+    pub fn evaluate(
+        &self,
+        v: &Variables,
+        o: &EvaluationOptions,
+        sandbox: &Sandbox,
+    ) -> EvaluationResult {
+        let values: Vec<Option<&Value>> = self.slots.iter().map(|slot| v.get(slot)).collect();
+        let context = Context {
+            slots: &self.slots,
+            values: &values,
+            options: o,
+            sandbox,
+            steps: Cell::new(0),
+        };
+
+        context.evaluate(&self.root)
+    }
+}
+
+struct Context<'a> {
+    slots: &'a [String],
+    values: &'a [Option<&'a Value>],
+    options: &'a EvaluationOptions,
+    sandbox: &'a Sandbox<'a>,
+    steps: Cell<u64>,
+}
+
+impl Context<'_> {
+    /// Evaluates the left operand of a precompiled `matches`, then takes the
+    /// `pattern_steps` the interpreter takes for evaluating the patterns.
+    fn evaluate_pattern_operand(&self, left: &Node, pattern_steps: usize) -> EvaluationResult {
+        let left = self.evaluate(left)?;
+        for _ in 0..pattern_steps {
+            take_step(&self.steps, self.options)?;
+        }
+
+        Ok(left)
+    }
+
+    fn evaluate(&self, node: &Node) -> EvaluationResult {
+        let o = self.options;
+        take_step(&self.steps, o)?;
+
+        match node {
+            Node::Slot(index) => match self.values[*index] {
+                Some(value) => Ok(value.clone()),
+                None if o.lenient_identifiers => Ok(Value::Empty),
+                None => Err(EvaluationError::UndeclaredIndentifier(
+                    self.slots[*index].clone(),
+                )),
+            },
+            Node::Constant(value) => Ok(value.clone()),
+            Node::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                if is_logical(operator) {
+                    let left = logical_operand(self.evaluate(left)?, o);
+                    apply_logical(operator, left, || {
+                        Ok(logical_operand(self.evaluate(right)?, o))
+                    })
+                } else {
+                    apply_binary(operator, self.evaluate(left)?, self.evaluate(right)?, o)
+                }
+            }
+            Node::UnaryOp { operand, operator } => {
+                apply_unary(operator, self.evaluate(operand)?, o)
+            }
+            Node::Matches {
+                left,
+                pattern,
+                regex,
+            } => match self.evaluate_pattern_operand(left, 1)? {
+                Value::Str(text) => Ok(Value::Bool(regex.is_match(&text))),
+                left => apply_binary(&Operator::Matches, left, Value::Str(pattern.clone()), o),
+            },
+            Node::MatchesAny {
+                left,
+                patterns,
+                set,
+            } => match self.evaluate_pattern_operand(left, 1 + patterns.len())? {
+                Value::Str(text) => Ok(Value::Bool(set.is_match(&text))),
+                left => apply_binary(
+                    &Operator::MatchesAny,
+                    left,
+                    Value::List(patterns.iter().cloned().map(Value::Str).collect()),
+                    o,
+                ),
+            },
+            Node::Call {
+                function,
+                arguments,
+            } => {
+                let arguments = arguments
+                    .iter()
+                    .map(|argument| self.evaluate(argument))
+                    .collect::<Result<Vec<Value>, EvaluationError>>()?;
+
+                apply_call(function, &arguments, o, self.sandbox)
+            }
+            Node::List(elements) => Ok(Value::List(
+                elements
+                    .iter()
+                    .map(|element| self.evaluate(element))
+                    .collect::<Result<Vec<Value>, EvaluationError>>()?,
+            )),
+            Node::Match {
+                expression,
+                cases,
+                default,
+            } => {
+                let value = self.evaluate(expression)?;
+                for (pattern, case) in cases {
+                    if let Value::Bool(true) = value.equal(pattern)? {
+                        return self.evaluate(case);
+                    }
+                }
+
+                match default {
+                    Some(default) => self.evaluate(default),
+                    None => Err(no_match_case(&value)),
+                }
+            }
+            Node::Comparison { first, comparisons } => apply_comparisons(
+                self.evaluate(first)?,
+                comparisons,
+                |node| self.evaluate(node),
+                o,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grammar::ExpressionParser;
+    use crate::{environment::EmptyEnvironment, evaluation::evaluate_in_sandbox};
+
+    fn str(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+
+    /// Evaluates `text` both ways with every step budget up to the one it
+    /// needs, and checks the outcomes are the same each time.
+    fn assert_equivalent(text: &str, variables: &[(&str, Value)], lenient: bool, null_safe: bool) {
+        let expression = ExpressionParser::new().parse(text).unwrap();
+        let compiled = CompiledExpression::compile(&expression);
+        let mut v = Variables::new();
+        for (name, value) in variables {
+            v.put(name.to_string(), value.clone());
+        }
+        let sandbox = Sandbox::new(&EmptyEnvironment);
+
+        for budget in (0..64).map(Some).chain([None]) {
+            let o = EvaluationOptions {
+                lenient_identifiers: lenient,
+                null_safe,
+                max_steps: budget,
+            };
+            let evaluated = format!("{:?}", evaluate_in_sandbox(&expression, &v, &o, &sandbox));
+            let compiled = format!("{:?}", compiled.evaluate(&v, &o, &sandbox));
+            assert_eq!(evaluated, compiled, "{text} with a budget of {budget:?}");
+        }
+    }
+
+    #[test]
+    fn regexes_are_equivalent() {
+        let text = [("text", str("spam and eggs"))];
+        assert_equivalent("text matches \"^spam\"", &text, false, false);
+        assert_equivalent("text matches \"^eggs\"", &text, false, false);
+        assert_equivalent("1 matches \"^1\"", &[], false, false);
+        assert_equivalent("text matches any [\"eggs$\", \"ham\"]", &text, false, false);
+        assert_equivalent("text matches any [\"ham\", \"bacon\"]", &text, false, false);
+        assert_equivalent("1 matches any [\"ham\", \"bacon\"]", &[], false, false);
+        assert_equivalent("text matches any []", &text, false, false);
+    }
+
+    #[test]
+    fn regex_fallbacks_are_equivalent() {
+        let variables = [("text", str("spam")), ("pattern", str("^s"))];
+        assert_equivalent("text matches \"(\"", &variables, false, false);
+        assert_equivalent("text matches pattern", &variables, false, false);
+        assert_equivalent("text matches any [\"a\", \"(\"]", &variables, false, false);
+        assert_equivalent(
+            "text matches any [\"(\", pattern]",
+            &variables,
+            false,
+            false,
+        );
+        assert_equivalent("text matches any [pattern, 1]", &variables, false, false);
+    }
+
+    #[test]
+    fn undeclared_slots_are_equivalent() {
+        for (lenient, null_safe) in [(false, false), (true, false), (true, true)] {
+            assert_equivalent("missing", &[], lenient, null_safe);
+            assert_equivalent("missing = empty", &[], lenient, null_safe);
+            assert_equivalent("missing + 1", &[], lenient, null_safe);
+            assert_equivalent("missing > 1 or true", &[], lenient, null_safe);
+            assert_equivalent("not missing", &[], lenient, null_safe);
+            assert_equivalent("missing matches \"a\"", &[], lenient, null_safe);
+            assert_equivalent("missing matches any [\"a\"]", &[], lenient, null_safe);
+            assert_equivalent("missing + missing", &[], lenient, null_safe);
+        }
+    }
+
+    #[test]
+    fn match_is_equivalent() {
+        let text = "match n { 1 => \"one\", 2 => \"two\", _ => \"many\" }";
+        for n in 1..4 {
+            assert_equivalent(text, &[("n", Value::Int(n))], false, false);
+        }
+        assert_equivalent(
+            "match n { 1 => \"one\" }",
+            &[("n", Value::Int(5))],
+            false,
+            false,
+        );
+        assert_equivalent("match n { 1 => \"one\" }", &[("n", str("1"))], false, false);
+        assert_equivalent(
+            "match n { 1 => 1 / 0, _ => 0 }",
+            &[("n", Value::Int(1))],
+            false,
+            false,
+        );
+    }
+
+    #[test]
+    fn operators_are_equivalent() {
+        let n = [("n", Value::Int(3))];
+        assert_equivalent("1 < n <= 3 < 4", &n, false, false);
+        assert_equivalent("5 < n < 1 / 0", &n, false, false);
+        assert_equivalent("n > 2 and n / 0 = 1", &n, false, false);
+        assert_equivalent("n > 5 and n / 0 = 1", &n, false, false);
+        assert_equivalent("[n, n + 1, -n] = [3, 4, -3]", &n, false, false);
+        assert_equivalent("word_count(\"a b\") + n", &n, false, false);
+    }
+}
@@ -0,0 +1,169 @@
+use super::{
+    evaluation::EvaluationError,
+    tree::{Expression, Literal, Operator},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map, HashMap};
+
+/// Largest expression the definitions may expand to, in nodes.
+pub const MAX_EXPANDED_NODES: usize = 100_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Definition {
+    pub text: String,
+    pub expression: Expression,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DefinedExpressions {
+    definitions: HashMap<String, Definition>,
+}
+
+impl DefinedExpressions {
+    pub fn new() -> Self {
+        DefinedExpressions {
+            definitions: HashMap::new(),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.definitions.len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Definition> {
+        self.definitions.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.definitions.contains_key(name)
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, String, Definition> {
+        self.definitions.iter()
+    }
+
+    pub fn put(&mut self, name: String, definition: Definition) -> Result<(), EvaluationError> {
+        let previous = self.definitions.insert(name.clone(), definition);
+        if let Err(e) = self.expand(&Expression::Identifier(name.clone())) {
+            match previous {
+                Some(previous) => self.definitions.insert(name, previous),
+                None => self.definitions.remove(&name),
+            };
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.definitions.remove(name).is_some()
+    }
+
+    pub fn show(&self) -> String {
+        let mut res = String::with_capacity(500);
+        for (name, definition) in &self.definitions {
+            res.push_str(&format!("{name} := {}\n", definition.text));
+        }
+
+        res
+    }
+
+    pub fn expand(&self, expression: &Expression) -> Result<Expression, EvaluationError> {
+        self.expand_with_stack(expression, &mut Vec::new(), &mut 0)
+    }
+
+    /// `nodes` counts the nodes of the expansion so far, definitions that
+    /// refer to each other several times can otherwise make it grow
+    /// exponentially.
+    fn expand_with_stack(
+        &self,
+        expression: &Expression,
+        stack: &mut Vec<String>,
+        nodes: &mut usize,
+    ) -> Result<Expression, EvaluationError> {
+        *nodes += 1;
+        if *nodes > MAX_EXPANDED_NODES {
+            return Err(EvaluationError::ExpansionTooLarge(MAX_EXPANDED_NODES));
+        }
+
+        match expression {
+            Expression::Identifier(identifier) => match self.definitions.get(identifier) {
+                Some(definition) => {
+                    if stack.contains(identifier) {
+                        let mut cycle = stack.clone();
+                        cycle.push(identifier.clone());
+                        return Err(EvaluationError::CyclicDefinition(cycle));
+                    }
+
+                    stack.push(identifier.clone());
+                    let result = self.expand_with_stack(&definition.expression, stack, nodes);
+                    stack.pop();
+                    result
+                }
+                None => Ok(expression.clone()),
+            },
+            Expression::Literal(_) => Ok(expression.clone()),
+            Expression::BinaryOp {
+                left,
+                operator,
+                right,
+            } => Ok(Expression::BinaryOp {
+                left: Box::new(self.expand_with_stack(left, stack, nodes)?),
+                operator: operator.clone(),
+                right: Box::new(self.expand_with_stack(right, stack, nodes)?),
+            }),
+            Expression::UnaryOp {
+                expression,
+                operator,
+            } => Ok(Expression::UnaryOp {
+                expression: Box::new(self.expand_with_stack(expression, stack, nodes)?),
+                operator: operator.clone(),
+            }),
+            Expression::Call {
+                function,
+                arguments,
+            } => Ok(Expression::Call {
+                function: function.clone(),
+                arguments: arguments
+                    .iter()
+                    .map(|argument| self.expand_with_stack(argument, stack, nodes))
+                    .collect::<Result<Vec<Expression>, EvaluationError>>()?,
+            }),
+            Expression::List(elements) => Ok(Expression::List(
+                elements
+                    .iter()
+                    .map(|element| self.expand_with_stack(element, stack, nodes))
+                    .collect::<Result<Vec<Expression>, EvaluationError>>()?,
+            )),
+            Expression::Match {
+                expression,
+                cases,
+                default,
+            } => Ok(Expression::Match {
+                expression: Box::new(self.expand_with_stack(expression, stack, nodes)?),
+                cases: cases
+                    .iter()
+                    .map(|(pattern, case)| {
+                        Ok((pattern.clone(), self.expand_with_stack(case, stack, nodes)?))
+                    })
+                    .collect::<Result<Vec<(Literal, Expression)>, EvaluationError>>()?,
+                default: match default {
+                    Some(default) => Some(Box::new(self.expand_with_stack(default, stack, nodes)?)),
+                    None => None,
+                },
+            }),
+            Expression::Comparison { first, comparisons } => Ok(Expression::Comparison {
+                first: Box::new(self.expand_with_stack(first, stack, nodes)?),
+                comparisons: comparisons
+                    .iter()
+                    .map(|(operator, operand)| {
+                        Ok((
+                            operator.clone(),
+                            self.expand_with_stack(operand, stack, nodes)?,
+                        ))
+                    })
+                    .collect::<Result<Vec<(Operator, Expression)>, EvaluationError>>()?,
+            }),
+        }
+    }
+}
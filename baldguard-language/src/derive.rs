@@ -0,0 +1 @@
+pub use baldguard_macros::{ContainsVariable, FromVariables, SetFromAssignment, ToVariables};
@@ -0,0 +1,123 @@
+use super::evaluation::ValueError;
+use chrono::TimeDelta;
+use std::{cell::RefCell, fmt::Display};
+
+/// Read-only view of the state stateful built-ins depend on. Built-ins never
+/// change it directly, they record an `Effect` in the `Sandbox` instead.
+pub trait Environment {
+    fn count(&self, event: &str, window: TimeDelta) -> Result<i64, ValueError>;
+
+    fn is_cooling_down(&self, key: &str) -> Result<bool, ValueError>;
+
+    fn counter(&self, name: &str) -> Result<i64, ValueError>;
+}
+
+pub struct EmptyEnvironment;
+
+impl Environment for EmptyEnvironment {
+    fn count(&self, _event: &str, _window: TimeDelta) -> Result<i64, ValueError> {
+        Err(ValueError::new_other(
+            "event counters are not available here".to_string(),
+        ))
+    }
+
+    fn is_cooling_down(&self, _key: &str) -> Result<bool, ValueError> {
+        Err(ValueError::new_other(
+            "cooldowns are not available here".to_string(),
+        ))
+    }
+
+    fn counter(&self, _name: &str) -> Result<i64, ValueError> {
+        Err(ValueError::new_other(
+            "counters are not available here".to_string(),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+    StartCooldown { key: String, duration: TimeDelta },
+    IncrementCounter { name: String },
+}
+
+impl Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Effect::StartCooldown { key, duration } => write!(
+                f,
+                "start cooldown \"{key}\" for {}s",
+                duration.num_seconds()
+            ),
+            Effect::IncrementCounter { name } => write!(f, "increment counter \"{name}\""),
+        }
+    }
+}
+
+/// Collects the effects of an evaluation on top of an `Environment`. Later
+/// calls in the same evaluation see the effects recorded by earlier ones, but
+/// nothing is applied until the caller commits `into_effects`.
+pub struct Sandbox<'a> {
+    environment: &'a dyn Environment,
+    effects: RefCell<Vec<Effect>>,
+}
+
+impl<'a> Sandbox<'a> {
+    pub fn new(environment: &'a dyn Environment) -> Self {
+        Sandbox {
+            environment,
+            effects: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn into_effects(self) -> Vec<Effect> {
+        self.effects.into_inner()
+    }
+
+    pub fn count(&self, event: &str, window: TimeDelta) -> Result<i64, ValueError> {
+        self.environment.count(event, window)
+    }
+
+    pub fn cooldown(&self, key: &str, duration: TimeDelta) -> Result<bool, ValueError> {
+        if duration <= TimeDelta::zero() {
+            return Err(ValueError::new_other(
+                "cooldown duration must be positive".to_string(),
+            ));
+        }
+
+        let started = self.effects.borrow().iter().any(
+            |effect| matches!(effect, Effect::StartCooldown { key: started, .. } if started == key),
+        );
+        if started || self.environment.is_cooling_down(key)? {
+            return Ok(false);
+        }
+
+        self.effects.borrow_mut().push(Effect::StartCooldown {
+            key: key.to_string(),
+            duration,
+        });
+        Ok(true)
+    }
+
+    pub fn increment_counter(&self, name: &str) -> Result<i64, ValueError> {
+        let value = self
+            .counter(name)?
+            .checked_add(1)
+            .ok_or_else(|| ValueError::new_other(format!("counter \"{name}\" overflowed")))?;
+
+        self.effects.borrow_mut().push(Effect::IncrementCounter {
+            name: name.to_string(),
+        });
+        Ok(value)
+    }
+
+    pub fn counter(&self, name: &str) -> Result<i64, ValueError> {
+        let pending = self
+            .effects
+            .borrow()
+            .iter()
+            .filter(|effect| matches!(effect, Effect::IncrementCounter { name: incremented } if incremented == name))
+            .count() as i64;
+
+        Ok(self.environment.counter(name)? + pending)
+    }
+}
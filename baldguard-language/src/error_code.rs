@@ -0,0 +1,57 @@
+/// Stable numbers of user-facing error codes, so replies can be looked up in
+/// the documentation. Numbers are never reused: E0xx are parse errors, E1xx
+/// evaluation errors and E2xx errors of the bot commands.
+const ERROR_NUMBERS: &[(&str, &str)] = &[
+    ("parse", "E001"),
+    ("integer_overflow", "E002"),
+    ("invalid_escape_sequence", "E003"),
+    ("undeclared_identifier", "E101"),
+    ("undeclared_function", "E102"),
+    ("type_mismatch", "E103"),
+    ("binary_op", "E104"),
+    ("unary_op", "E105"),
+    ("division_by_zero", "E106"),
+    ("invalid_regex", "E107"),
+    ("invalid_arguments", "E108"),
+    ("cyclic_definition", "E109"),
+    ("step_budget_exceeded", "E110"),
+    ("conflicting_variable", "E111"),
+    ("invalid_value", "E112"),
+    ("expansion_too_large", "E113"),
+    ("other", "E199"),
+    ("invalid_command", INVALID_COMMAND),
+    ("invalid_arguments_of_command", INVALID_ARGUMENTS),
+    ("permission_denied", PERMISSION_DENIED),
+    ("no_reply_message", NO_REPLY_MESSAGE),
+    ("not_found", NOT_FOUND),
+    ("invalid_input", INVALID_INPUT),
+    ("limit_exceeded", LIMIT_EXCEEDED),
+    ("invalid_config", INVALID_CONFIG),
+    ("request_failed", REQUEST_FAILED),
+];
+
+pub const INVALID_COMMAND: &str = "E201";
+/// A command got no argument, or one it doesn't take, or too few words.
+pub const INVALID_ARGUMENTS: &str = "E202";
+pub const PERMISSION_DENIED: &str = "E203";
+/// The command works on a replied or forwarded message and there is none.
+pub const NO_REPLY_MESSAGE: &str = "E204";
+/// No filter, alias, preset, test case or option with that name.
+pub const NOT_FOUND: &str = "E205";
+/// An argument isn't a valid name, user id, domain, time or number.
+pub const INVALID_INPUT: &str = "E206";
+/// The chat would have more filters, aliases or blocked items than allowed.
+pub const LIMIT_EXCEEDED: &str = "E207";
+/// An imported config is malformed or breaks a rule the commands enforce.
+pub const INVALID_CONFIG: &str = "E208";
+/// The database or Telegram failed to carry out the command.
+pub const REQUEST_FAILED: &str = "E209";
+
+/// Number of the error `code`, E199 for codes without one.
+pub fn number(code: &str) -> &'static str {
+    ERROR_NUMBERS
+        .iter()
+        .find(|(name, _)| *name == code)
+        .map(|(_, number)| *number)
+        .unwrap_or("E199")
+}
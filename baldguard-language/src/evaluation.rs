@@ -1,16 +1,78 @@
-use super::tree::{Assignment, Expression, Literal, Operator};
-use regex::Regex;
+use super::{
+    builtins::call,
+    environment::{EmptyEnvironment, Sandbox},
+    error_code,
+    tree::{Assignment, Expression, Literal, Operator},
+};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use regex::{Regex, RegexSet};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, convert::From, fmt::Display, result::Result};
+use std::{
+    cell::Cell,
+    cmp::Ordering,
+    collections::{hash_map, HashMap},
+    convert::From,
+    error::Error,
+    fmt::Display,
+    result::Result,
+};
 
 pub type SetFromAssignmentResult = Result<(), EvaluationError>;
 
 pub trait SetFromAssignment {
+    /// Names and types of the variables that can be assigned.
+    const VARIABLE_NAMES: &'static [(&'static str, &'static str)];
+
     fn set_from_assignment(
         &mut self,
         assignment: &Assignment,
         variables: &Variables,
     ) -> SetFromAssignmentResult;
+
+    /// Checks the current values against the `#[assignment(...)]`
+    /// constraints, for values that were not assigned, like deserialized ones.
+    fn check_constraints(&self) -> Result<(), ValueError>;
+}
+
+/// Checks an assigned integer against the bounds of
+/// `#[assignment(min = ..., max = ...)]`.
+pub fn check_range(
+    variable: &str,
+    value: i64,
+    min: Option<i64>,
+    max: Option<i64>,
+) -> Result<(), ValueError> {
+    let constraint = match (min, max) {
+        (Some(min), Some(max)) if !(min..=max).contains(&value) => {
+            format!("must be between {min} and {max}")
+        }
+        (Some(min), _) if value < min => format!("must be at least {min}"),
+        (_, Some(max)) if value > max => format!("must be at most {max}"),
+        _ => return Ok(()),
+    };
+
+    Err(ValueError::new_invalid_value(
+        variable,
+        Value::Int(value),
+        constraint,
+    ))
+}
+
+/// Checks an assigned string against the regex of
+/// `#[assignment(matches = "...")]`.
+pub fn check_pattern(variable: &str, value: &str, pattern: &str) -> Result<(), ValueError> {
+    match Regex::new(pattern) {
+        Ok(regex) if regex.is_match(value) => Ok(()),
+        Ok(_) => Err(ValueError::new_invalid_value(
+            variable,
+            Value::Str(value.to_string()),
+            format!("must match \"{pattern}\""),
+        )),
+        Err(e) => Err(ValueError::new_invalid_regex(
+            pattern.to_string(),
+            format!("{e}"),
+        )),
+    }
 }
 
 pub trait ContainsVariable {
@@ -22,6 +84,8 @@ pub enum Value {
     Int(i64),
     Str(String),
     Bool(bool),
+    DateTime(DateTime<Utc>),
+    List(Vec<Value>),
     Empty,
 }
 
@@ -31,6 +95,8 @@ impl Value {
             Value::Int(_) => "int",
             Value::Str(_) => "str",
             Value::Bool(_) => "bool",
+            Value::DateTime(_) => "datetime",
+            Value::List(_) => "list",
             Value::Empty => "empty",
         }
     }
@@ -53,11 +119,89 @@ impl Display for Value {
             Value::Int(value) => write!(f, "{value}"),
             Value::Str(value) => write!(f, "{value}"),
             Value::Bool(value) => write!(f, "{}", if *value { "true" } else { "false" }),
+            Value::DateTime(value) => {
+                write!(f, "{}", value.to_rfc3339_opts(SecondsFormat::Secs, true))
+            }
+            Value::List(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
             Value::Empty => write!(f, "empty"),
         }
     }
 }
 
+/// Hints shown after error messages, keyed by error code. Keys may be
+/// refined with the types involved (`binary_op:int:str`), the most specific
+/// key present wins.
+const HINTS: &[(&str, &str)] = &[
+    (
+        "binary_op:int:str",
+        "did you mean to compare with a number? numbers must not be quoted",
+    ),
+    (
+        "binary_op:str:int",
+        "did you mean to compare text with a string literal? strings need quotes",
+    ),
+    (
+        "binary_op:empty",
+        "the variable is empty for this message, check it with has_* first or enable null_safe",
+    ),
+    (
+        "unary_op:empty",
+        "the variable is empty for this message, check it with has_* first or enable null_safe",
+    ),
+    (
+        "division_by_zero",
+        "make sure the divisor is not 0 before dividing",
+    ),
+    (
+        "invalid_regex",
+        "backslashes in a regex have to be doubled inside a string literal",
+    ),
+    (
+        "invalid_arguments",
+        "/help lists the arguments each function takes",
+    ),
+    (
+        "type_mismatch",
+        "/get_options and /get_variables show the current values and their types",
+    ),
+    (
+        "invalid_value",
+        "/get_options shows the current values, /help describes the allowed ones",
+    ),
+    (
+        "undeclared_identifier",
+        "names are case sensitive and strings need quotes, /get_message_variables lists the message variables",
+    ),
+    (
+        "undeclared_function",
+        "/help lists the available functions",
+    ),
+    (
+        "cyclic_definition",
+        "a definition must not refer to itself, directly or through other definitions",
+    ),
+    (
+        "step_budget_exceeded",
+        "simplify the expression or raise the max_evaluation_steps option",
+    ),
+    (
+        "expansion_too_large",
+        "a definition used several times is copied each time, use it in fewer places",
+    ),
+];
+
+fn find_hint(keys: &[String]) -> Option<&'static str> {
+    keys.iter().find_map(|key| {
+        HINTS
+            .iter()
+            .find(|(hint_key, _)| hint_key == key)
+            .map(|(_, hint)| *hint)
+    })
+}
+
 #[derive(Debug, Clone)]
 pub enum ValueError {
     BinaryOp {
@@ -76,6 +220,20 @@ pub enum ValueError {
         regex: String,
         message: String,
     },
+    InvalidArguments {
+        function: &'static str,
+        arguments: Vec<Value>,
+    },
+    TypeMismatch {
+        variable: String,
+        expected: &'static str,
+        value: Value,
+    },
+    InvalidValue {
+        variable: String,
+        value: Value,
+        constraint: String,
+    },
     Other {
         message: String,
     },
@@ -102,11 +260,76 @@ impl ValueError {
         ValueError::InvalidRegex { regex, message }
     }
 
+    pub fn new_invalid_arguments(function: &'static str, arguments: &[Value]) -> Self {
+        ValueError::InvalidArguments {
+            function,
+            arguments: arguments.to_vec(),
+        }
+    }
+
+    pub fn new_type_mismatch(variable: &str, expected: &'static str, value: Value) -> Self {
+        ValueError::TypeMismatch {
+            variable: variable.to_string(),
+            expected,
+            value,
+        }
+    }
+
+    pub fn new_invalid_value(variable: &str, value: Value, constraint: String) -> Self {
+        ValueError::InvalidValue {
+            variable: variable.to_string(),
+            value,
+            constraint,
+        }
+    }
+
     pub fn new_other(message: String) -> Self {
         ValueError::Other { message }
     }
+
+    /// Stable machine-readable name of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValueError::BinaryOp { .. } => "binary_op",
+            ValueError::UnaryOp { .. } => "unary_op",
+            ValueError::DivisionByZero { .. } => "division_by_zero",
+            ValueError::InvalidRegex { .. } => "invalid_regex",
+            ValueError::InvalidArguments { .. } => "invalid_arguments",
+            ValueError::TypeMismatch { .. } => "type_mismatch",
+            ValueError::InvalidValue { .. } => "invalid_value",
+            ValueError::Other { .. } => "other",
+        }
+    }
+
+    /// Suggestion on how to fix the error, if there is one.
+    pub fn hint(&self) -> Option<&'static str> {
+        let code = self.code();
+        let mut keys = Vec::with_capacity(3);
+        match self {
+            ValueError::BinaryOp { left, right, .. } => {
+                keys.push(format!("{code}:{}:{}", left.type_str(), right.type_str()));
+                if matches!(left, Value::Empty) || matches!(right, Value::Empty) {
+                    keys.push(format!("{code}:empty"));
+                }
+            }
+            ValueError::UnaryOp { value, .. } => {
+                keys.push(format!("{code}:{}", value.type_str()));
+            }
+            _ => {}
+        }
+        keys.push(code.to_string());
+
+        find_hint(&keys)
+    }
+
+    /// Stable number of the error code, see `error_code`.
+    pub fn number(&self) -> &'static str {
+        error_code::number(self.code())
+    }
 }
 
+impl Error for ValueError {}
+
 impl Display for ValueError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -119,18 +342,43 @@ impl Display for ValueError {
                 "unsupported operation {} {operator} {}",
                 left.type_str(),
                 right.type_str()
-            ),
+            )?,
             ValueError::UnaryOp { value, operator } => {
-                write!(f, "unsupported operation {operator} {}", value.type_str())
-            }
-            ValueError::DivisionByZero { value } => {
-                write!(f, "division by zero ({value} / 0)")
+                write!(f, "unsupported operation {operator} {}", value.type_str())?
             }
+            ValueError::DivisionByZero { value } => write!(f, "division by zero ({value} / 0)")?,
             ValueError::InvalidRegex { regex, message } => {
-                write!(f, "invalid regex \"{regex}\": {message}")
+                write!(f, "invalid regex \"{regex}\": {message}")?
+            }
+            ValueError::InvalidArguments {
+                function,
+                arguments,
+            } => {
+                let types: Vec<&str> = arguments.iter().map(Value::type_str).collect();
+                write!(f, "unsupported arguments {function}({})", types.join(", "))?
             }
-            ValueError::Other { message } => write!(f, "{message}"),
+            ValueError::TypeMismatch {
+                variable,
+                expected,
+                value,
+            } => write!(
+                f,
+                "variable {variable} should be of type {expected}, not {}",
+                value.type_str()
+            )?,
+            ValueError::InvalidValue {
+                variable,
+                value,
+                constraint,
+            } => write!(f, "invalid value {value} for {variable}: {constraint}")?,
+            ValueError::Other { message } => write!(f, "{message}")?,
         }
+
+        if let Some(hint) = self.hint() {
+            write!(f, "\nhint: {hint}")?;
+        }
+
+        Ok(())
     }
 }
 
@@ -251,6 +499,26 @@ impl Value {
                 Value::Empty => Ok(Value::Bool(false)),
                 _ => Err(ValueError::new_binary(self.clone(), "=", other.clone())),
             },
+            Value::DateTime(l) => match other {
+                Value::DateTime(r) => Ok(Value::Bool(*l == *r)),
+                Value::Empty => Ok(Value::Bool(false)),
+                _ => Err(ValueError::new_binary(self.clone(), "=", other.clone())),
+            },
+            Value::List(l) => match other {
+                Value::List(r) => {
+                    if l.len() != r.len() {
+                        return Ok(Value::Bool(false));
+                    }
+                    for (l, r) in l.iter().zip(r) {
+                        if let Value::Bool(false) = l.equal(r)? {
+                            return Ok(Value::Bool(false));
+                        }
+                    }
+                    Ok(Value::Bool(true))
+                }
+                Value::Empty => Ok(Value::Bool(false)),
+                _ => Err(ValueError::new_binary(self.clone(), "=", other.clone())),
+            },
             Value::Empty => match other {
                 Value::Empty => Ok(Value::Bool(true)),
                 _ => Ok(Value::Bool(false)),
@@ -275,6 +543,16 @@ impl Value {
                 Value::Empty => Ok(Value::Bool(true)),
                 _ => Err(ValueError::new_binary(self.clone(), "!=", other.clone())),
             },
+            Value::DateTime(l) => match other {
+                Value::DateTime(r) => Ok(Value::Bool(*l != *r)),
+                Value::Empty => Ok(Value::Bool(true)),
+                _ => Err(ValueError::new_binary(self.clone(), "!=", other.clone())),
+            },
+            Value::List(_) => match other {
+                Value::List(_) => self.equal(other)?.not(),
+                Value::Empty => Ok(Value::Bool(true)),
+                _ => Err(ValueError::new_binary(self.clone(), "!=", other.clone())),
+            },
             Value::Empty => match other {
                 Value::Empty => Ok(Value::Bool(false)),
                 _ => Ok(Value::Bool(true)),
@@ -282,6 +560,55 @@ impl Value {
         }
     }
 
+    fn compare(&self, other: &Self, operator: &'static str) -> Result<Ordering, ValueError> {
+        match self {
+            Value::Int(l) => match other {
+                Value::Int(r) => Ok(l.cmp(r)),
+                _ => Err(ValueError::new_binary(
+                    self.clone(),
+                    operator,
+                    other.clone(),
+                )),
+            },
+            Value::DateTime(l) => match other {
+                Value::DateTime(r) => Ok(l.cmp(r)),
+                _ => Err(ValueError::new_binary(
+                    self.clone(),
+                    operator,
+                    other.clone(),
+                )),
+            },
+            _ => Err(ValueError::new_binary(
+                self.clone(),
+                operator,
+                other.clone(),
+            )),
+        }
+    }
+
+    pub fn less(&self, other: &Self) -> ValueResult {
+        Ok(Value::Bool(self.compare(other, "<")?.is_lt()))
+    }
+
+    pub fn less_equal(&self, other: &Self) -> ValueResult {
+        Ok(Value::Bool(self.compare(other, "<=")?.is_le()))
+    }
+
+    pub fn greater(&self, other: &Self) -> ValueResult {
+        Ok(Value::Bool(self.compare(other, ">")?.is_gt()))
+    }
+
+    pub fn greater_equal(&self, other: &Self) -> ValueResult {
+        Ok(Value::Bool(self.compare(other, ">=")?.is_ge()))
+    }
+
+    fn shift(datetime: &DateTime<Utc>, seconds: i64) -> ValueResult {
+        match TimeDelta::try_seconds(seconds).and_then(|delta| datetime.checked_add_signed(delta)) {
+            Some(value) => Ok(Value::DateTime(value)),
+            None => Err(ValueError::new_other("datetime out of range".to_string())),
+        }
+    }
+
     pub fn plus(&self, other: &Self) -> ValueResult {
         match self {
             Value::Int(l) => match other {
@@ -296,6 +623,10 @@ impl Value {
                 }
                 _ => Err(ValueError::new_binary(self.clone(), "+", other.clone())),
             },
+            Value::DateTime(l) => match other {
+                Value::Int(r) => Value::shift(l, *r),
+                _ => Err(ValueError::new_binary(self.clone(), "+", other.clone())),
+            },
             _ => Err(ValueError::new_binary(self.clone(), "+", other.clone())),
         }
     }
@@ -313,6 +644,14 @@ impl Value {
                 Value::Int(r) => Ok(Value::Int(*l - *r)),
                 _ => Err(ValueError::new_binary(self.clone(), "-", other.clone())),
             },
+            Value::DateTime(l) => match other {
+                Value::DateTime(r) => Ok(Value::Int((*l - *r).num_seconds())),
+                Value::Int(r) => match r.checked_neg() {
+                    Some(r) => Value::shift(l, r),
+                    None => Err(ValueError::new_other("datetime out of range".to_string())),
+                },
+                _ => Err(ValueError::new_binary(self.clone(), "-", other.clone())),
+            },
             _ => Err(ValueError::new_binary(self.clone(), "-", other.clone())),
         }
     }
@@ -370,45 +709,216 @@ impl Value {
             )),
         }
     }
+
+    pub fn matches_any(&self, other: &Self) -> ValueResult {
+        let error = || ValueError::new_binary(self.clone(), "matches any", other.clone());
+
+        match self {
+            Value::Str(l) => match other {
+                Value::List(r) => {
+                    let patterns = r
+                        .iter()
+                        .map(|pattern| match pattern {
+                            Value::Str(pattern) => Ok(pattern.as_str()),
+                            _ => Err(error()),
+                        })
+                        .collect::<Result<Vec<&str>, ValueError>>()?;
+
+                    match RegexSet::new(&patterns) {
+                        Ok(set) => Ok(Value::Bool(set.is_match(l))),
+                        Err(e) => {
+                            let regex = patterns
+                                .iter()
+                                .find(|pattern| Regex::new(pattern).is_err())
+                                .map(|pattern| pattern.to_string())
+                                .unwrap_or_else(|| patterns.join(", "));
+                            Err(ValueError::new_invalid_regex(regex, format!("{e}")))
+                        }
+                    }
+                }
+                _ => Err(error()),
+            },
+            _ => Err(error()),
+        }
+    }
 }
 
+pub const NAMESPACE_SEPARATOR: char = '.';
+
+/// Named values an expression is evaluated with. Names may be qualified with
+/// a namespace (`msg.text`); unqualified names that are not set directly are
+/// looked up in the namespaces in the order they were added.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Variables {
     values: HashMap<String, Value>,
+    #[serde(skip)]
+    namespaces: Vec<String>,
+}
+
+/// How `Variables::merge` resolves a name set on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    ErrorOnConflict,
+    PreferLeft,
+    PreferRight,
 }
 
 pub trait ToVariables {
     fn to_variables(self) -> Variables;
+
+    /// Names and types of the variables `to_variables` produces.
+    fn variable_names() -> Vec<(String, String)>;
+}
+
+pub trait FromVariables: Sized {
+    fn from_variables(variables: &Variables) -> Result<Self, EvaluationError>;
 }
 
 impl Variables {
     pub fn new() -> Self {
         Variables {
             values: HashMap::new(),
+            namespaces: Vec::new(),
+        }
+    }
+
+    /// Moves every variable into `namespace`, so `name` becomes
+    /// `namespace.name`.
+    pub fn into_namespace(self, namespace: &str) -> Self {
+        Variables {
+            values: self
+                .values
+                .into_iter()
+                .map(|(name, value)| (format!("{namespace}{NAMESPACE_SEPARATOR}{name}"), value))
+                .collect(),
+            namespaces: vec![namespace.to_string()],
         }
     }
 
-    pub fn count(&self) -> usize {
+    /// Adds the variables of `other`, its namespaces are searched after the
+    /// ones already present. Nothing is added if `policy` rejects a conflict.
+    pub fn merge(&mut self, other: Variables, policy: MergePolicy) -> Result<(), EvaluationError> {
+        match policy {
+            MergePolicy::ErrorOnConflict => {
+                if let Some(name) = other
+                    .values
+                    .keys()
+                    .find(|name| self.values.contains_key(*name))
+                {
+                    return Err(EvaluationError::ConflictingVariable(name.clone()));
+                }
+                self.values.extend(other.values);
+            }
+            MergePolicy::PreferLeft => {
+                for (name, value) in other.values {
+                    self.values.entry(name).or_insert(value);
+                }
+            }
+            MergePolicy::PreferRight => self.values.extend(other.values),
+        }
+
+        for namespace in other.namespaces {
+            if !self.namespaces.contains(&namespace) {
+                self.namespaces.push(namespace);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Searches `namespace` for unqualified names after the namespaces
+    /// already present, for variables whose namespaces weren't serialized.
+    pub fn add_namespace(&mut self, namespace: &str) {
+        if !self.namespaces.iter().any(|present| present == namespace) {
+            self.namespaces.push(namespace.to_string());
+        }
+    }
+
+    pub fn len(&self) -> usize {
         self.values.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<'_, String, Value> {
+        self.values.iter()
+    }
+
+    pub fn keys(&self) -> hash_map::Keys<'_, String, Value> {
+        self.values.keys()
+    }
+
     pub fn put(&mut self, name: String, value: Value) {
         self.values.insert(name, value);
     }
 
     pub fn remove(&mut self, name: &str) -> bool {
-        match self.values.remove(name) {
-            Some(_) => true,
-            None => false,
-        }
+        self.values.remove(name).is_some()
     }
 
     pub fn get(&self, name: &str) -> Option<&Value> {
-        self.values.get(name)
+        if let Some(value) = self.values.get(name) {
+            return Some(value);
+        }
+
+        if name.contains(NAMESPACE_SEPARATOR) {
+            return None;
+        }
+
+        self.namespaces.iter().find_map(|namespace| {
+            self.values
+                .get(&format!("{namespace}{NAMESPACE_SEPARATOR}{name}"))
+        })
+    }
+
+    pub fn try_get(&self, name: &str) -> Result<&Value, EvaluationError> {
+        self.get(name)
+            .ok_or_else(|| EvaluationError::UndeclaredIndentifier(name.to_string()))
+    }
+
+    pub fn get_int(&self, name: &str) -> Result<i64, EvaluationError> {
+        match self.try_get(name)? {
+            Value::Int(value) => Ok(*value),
+            value => Err(ValueError::new_type_mismatch(name, "int", value.clone()).into()),
+        }
+    }
+
+    pub fn get_str(&self, name: &str) -> Result<&str, EvaluationError> {
+        match self.try_get(name)? {
+            Value::Str(value) => Ok(value),
+            value => Err(ValueError::new_type_mismatch(name, "str", value.clone()).into()),
+        }
     }
 
-    pub fn extend(&mut self, other: Self) {
-        self.values.extend(other.values);
+    pub fn get_bool(&self, name: &str) -> Result<bool, EvaluationError> {
+        match self.try_get(name)? {
+            Value::Bool(value) => Ok(*value),
+            value => Err(ValueError::new_type_mismatch(name, "bool", value.clone()).into()),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, ValueError> {
+        serde_json::to_string_pretty(&self.values)
+            .map_err(|e| ValueError::new_other(format!("failed to export variables: {e}")))
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ValueError> {
+        match serde_json::from_str(json) {
+            Ok(values) => Ok(Variables {
+                values,
+                namespaces: Vec::new(),
+            }),
+            Err(e) => Err(ValueError::new_other(format!("invalid variables: {e}"))),
+        }
+    }
+
+    pub fn get_datetime(&self, name: &str) -> Result<DateTime<Utc>, EvaluationError> {
+        match self.try_get(name)? {
+            Value::DateTime(value) => Ok(*value),
+            value => Err(ValueError::new_type_mismatch(name, "datetime", value.clone()).into()),
+        }
     }
 
     pub fn show(&self, omit_empty: bool) -> String {
@@ -424,7 +934,46 @@ impl Variables {
             res.push_str(&variable);
         }
 
-        return res;
+        res
+    }
+}
+
+impl Default for Variables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IntoIterator for Variables {
+    type Item = (String, Value);
+    type IntoIter = hash_map::IntoIter<String, Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Variables {
+    type Item = (&'a String, &'a Value);
+    type IntoIter = hash_map::Iter<'a, String, Value>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.values.iter()
+    }
+}
+
+impl Extend<(String, Value)> for Variables {
+    fn extend<T: IntoIterator<Item = (String, Value)>>(&mut self, iter: T) {
+        self.values.extend(iter);
+    }
+}
+
+impl FromIterator<(String, Value)> for Variables {
+    fn from_iter<T: IntoIterator<Item = (String, Value)>>(iter: T) -> Self {
+        Variables {
+            values: HashMap::from_iter(iter),
+            namespaces: Vec::new(),
+        }
     }
 }
 
@@ -444,6 +993,9 @@ where
 }
 
 impl SetFromAssignment for Variables {
+    /// Any variable can be assigned.
+    const VARIABLE_NAMES: &'static [(&'static str, &'static str)] = &[];
+
     fn set_from_assignment(
         &mut self,
         assignment: &Assignment,
@@ -457,16 +1009,26 @@ impl SetFromAssignment for Variables {
             Err(e) => Err(e),
         }
     }
+
+    fn check_constraints(&self) -> Result<(), ValueError> {
+        Ok(())
+    }
 }
 
 impl ContainsVariable for Variables {
     fn contains_variable(&self, identifier: &str) -> bool {
-        self.values.contains_key(identifier)
+        self.get(identifier).is_some()
     }
 }
 
+#[derive(Debug, Clone)]
 pub enum EvaluationError {
     UndeclaredIndentifier(String),
+    UndeclaredFunction(String),
+    CyclicDefinition(Vec<String>),
+    StepBudgetExceeded(u64),
+    ExpansionTooLarge(usize),
+    ConflictingVariable(String),
     ValueError(ValueError),
 }
 
@@ -474,9 +1036,68 @@ impl Display for EvaluationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             EvaluationError::UndeclaredIndentifier(i) => {
-                write!(f, "undeclared identifier \"{i}\"")
+                write!(f, "undeclared identifier \"{i}\"")?
+            }
+            EvaluationError::UndeclaredFunction(function) => {
+                write!(f, "undeclared function \"{function}\"")?
             }
-            EvaluationError::ValueError(e) => write!(f, "value error: {e}"),
+            EvaluationError::CyclicDefinition(cycle) => {
+                write!(f, "cyclic definition {}", cycle.join(" -> "))?
+            }
+            EvaluationError::StepBudgetExceeded(budget) => {
+                write!(f, "evaluation exceeded the budget of {budget} steps")?
+            }
+            EvaluationError::ExpansionTooLarge(limit) => {
+                write!(f, "definitions expand to more than {limit} nodes")?
+            }
+            EvaluationError::ConflictingVariable(name) => {
+                write!(f, "variable \"{name}\" is set more than once")?
+            }
+            EvaluationError::ValueError(e) => return write!(f, "value error: {e}"),
+        }
+
+        if let Some(hint) = self.hint() {
+            write!(f, "\nhint: {hint}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EvaluationError {
+    /// Stable machine-readable name of the error kind, value errors are
+    /// reported with the code of the underlying `ValueError`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            EvaluationError::UndeclaredIndentifier(_) => "undeclared_identifier",
+            EvaluationError::UndeclaredFunction(_) => "undeclared_function",
+            EvaluationError::CyclicDefinition(_) => "cyclic_definition",
+            EvaluationError::StepBudgetExceeded(_) => "step_budget_exceeded",
+            EvaluationError::ExpansionTooLarge(_) => "expansion_too_large",
+            EvaluationError::ConflictingVariable(_) => "conflicting_variable",
+            EvaluationError::ValueError(e) => e.code(),
+        }
+    }
+
+    /// Suggestion on how to fix the error, if there is one.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            EvaluationError::ValueError(e) => e.hint(),
+            _ => find_hint(&[self.code().to_string()]),
+        }
+    }
+
+    /// Stable number of the error code, see `error_code`.
+    pub fn number(&self) -> &'static str {
+        error_code::number(self.code())
+    }
+}
+
+impl Error for EvaluationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EvaluationError::ValueError(e) => Some(e),
+            _ => None,
         }
     }
 }
@@ -489,10 +1110,205 @@ impl From<ValueError> for EvaluationError {
 
 pub type EvaluationResult = Result<Value, EvaluationError>;
 
+#[derive(Debug, Clone, Default)]
+pub struct EvaluationOptions {
+    pub lenient_identifiers: bool,
+    pub null_safe: bool,
+    /// Maximum number of expression nodes evaluated, unlimited if `None`.
+    pub max_steps: Option<u64>,
+}
+
+fn null_safe_binary(operator: &Operator) -> Option<Value> {
+    match operator {
+        Operator::Equal | Operator::NotEqual => None,
+        Operator::Less
+        | Operator::LessEqual
+        | Operator::Greater
+        | Operator::GreaterEqual
+        | Operator::Matches
+        | Operator::MatchesAny => Some(Value::Bool(false)),
+        _ => Some(Value::Empty),
+    }
+}
+
+pub(crate) fn take_step(steps: &Cell<u64>, o: &EvaluationOptions) -> Result<(), EvaluationError> {
+    let taken = steps.get() + 1;
+    steps.set(taken);
+
+    match o.max_steps {
+        Some(budget) if taken > budget => Err(EvaluationError::StepBudgetExceeded(budget)),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn is_logical(operator: &Operator) -> bool {
+    matches!(
+        operator,
+        Operator::And | Operator::Nand | Operator::Or | Operator::Nor | Operator::Xor
+    )
+}
+
+/// Under `null_safe` logical operators treat empty as false.
+pub(crate) fn logical_operand(value: Value, o: &EvaluationOptions) -> Value {
+    match value {
+        Value::Empty if o.null_safe => Value::Bool(false),
+        value => value,
+    }
+}
+
+/// Applies a logical operator, the right operand is only evaluated when the
+/// left one does not decide the result on its own.
+pub(crate) fn apply_logical(
+    operator: &Operator,
+    left: Value,
+    right: impl FnOnce() -> EvaluationResult,
+) -> EvaluationResult {
+    let short_circuit = match operator {
+        Operator::And => left.and_short_circuit(),
+        Operator::Nand => left.nand_short_circuit(),
+        Operator::Or => left.or_short_circuit(),
+        Operator::Nor => left.nor_short_circuit(),
+        _ => None,
+    };
+    if let Some(value) = short_circuit {
+        return Ok(value);
+    }
+
+    let right = right()?;
+    match operator {
+        Operator::And => Ok(left.and(&right)?),
+        Operator::Nand => Ok(left.nand(&right)?),
+        Operator::Or => Ok(left.or(&right)?),
+        Operator::Nor => Ok(left.nor(&right)?),
+        Operator::Xor => Ok(left.xor(&right)?),
+        _ => panic!("invalid logical operation {:?}", operator),
+    }
+}
+
+pub(crate) fn apply_binary(
+    operator: &Operator,
+    left: Value,
+    right: Value,
+    o: &EvaluationOptions,
+) -> EvaluationResult {
+    if o.null_safe && (matches!(left, Value::Empty) || matches!(right, Value::Empty)) {
+        if let Some(value) = null_safe_binary(operator) {
+            return Ok(value);
+        }
+    }
+
+    match operator {
+        Operator::Equal => Ok(left.equal(&right)?),
+        Operator::NotEqual => Ok(left.not_equal(&right)?),
+        Operator::Less => Ok(left.less(&right)?),
+        Operator::LessEqual => Ok(left.less_equal(&right)?),
+        Operator::Greater => Ok(left.greater(&right)?),
+        Operator::GreaterEqual => Ok(left.greater_equal(&right)?),
+        Operator::Plus => Ok(left.plus(&right)?),
+        Operator::Minus => Ok(left.minus(&right)?),
+        Operator::Multiply => Ok(left.multiply(&right)?),
+        Operator::Divide => Ok(left.divide(&right)?),
+        Operator::Matches => Ok(left.matches(&right)?),
+        Operator::MatchesAny => Ok(left.matches_any(&right)?),
+        _ => panic!("invalid binary operation {:?}", operator),
+    }
+}
+
+pub(crate) fn apply_unary(
+    operator: &Operator,
+    value: Value,
+    o: &EvaluationOptions,
+) -> EvaluationResult {
+    if o.null_safe && matches!(value, Value::Empty) {
+        return match operator {
+            Operator::Not => Ok(Value::Bool(true)),
+            _ => Ok(Value::Empty),
+        };
+    }
+
+    match operator {
+        Operator::Not => Ok(value.not()?),
+        Operator::Plus => Ok(value.unary_plus()?),
+        Operator::Minus => Ok(value.unary_minus()?),
+        _ => panic!("invalid unary operation {:?}", operator),
+    }
+}
+
+pub(crate) fn apply_call(
+    function: &str,
+    arguments: &[Value],
+    o: &EvaluationOptions,
+    sandbox: &Sandbox,
+) -> EvaluationResult {
+    if o.null_safe && arguments.iter().any(|a| matches!(a, Value::Empty)) {
+        return Ok(Value::Empty);
+    }
+
+    call(function, arguments, sandbox)
+}
+
+/// Applies the comparisons of a chain like `a < b < c` to `first` and the
+/// operands `evaluate` gives, evaluating each operand once and none after the
+/// first false comparison.
+pub(crate) fn apply_comparisons<'e, E>(
+    first: Value,
+    comparisons: &'e [(Operator, E)],
+    mut evaluate: impl FnMut(&'e E) -> EvaluationResult,
+    o: &EvaluationOptions,
+) -> EvaluationResult {
+    let mut left = first;
+    for (operator, operand) in comparisons {
+        let right = evaluate(operand)?;
+        let result = apply_binary(operator, left, right.clone(), o)?;
+        if let Some(value) = result.and_short_circuit() {
+            return Ok(value);
+        }
+        left = right;
+    }
+
+    Ok(Value::Bool(true))
+}
+
+pub(crate) fn no_match_case(value: &Value) -> EvaluationError {
+    EvaluationError::ValueError(ValueError::new_other(format!("no match case for {value}")))
+}
+
 pub fn evaluate(e: &Expression, v: &Variables) -> EvaluationResult {
+    evaluate_with_options(e, v, &EvaluationOptions::default())
+}
+
+pub fn evaluate_with_options(
+    e: &Expression,
+    v: &Variables,
+    o: &EvaluationOptions,
+) -> EvaluationResult {
+    evaluate_in_sandbox(e, v, o, &Sandbox::new(&EmptyEnvironment))
+}
+
+pub fn evaluate_in_sandbox(
+    e: &Expression,
+    v: &Variables,
+    o: &EvaluationOptions,
+    sandbox: &Sandbox,
+) -> EvaluationResult {
+    evaluate_counting_steps(e, v, o, sandbox, &Cell::new(0))
+}
+
+fn evaluate_counting_steps(
+    e: &Expression,
+    v: &Variables,
+    o: &EvaluationOptions,
+    sandbox: &Sandbox,
+    steps: &Cell<u64>,
+) -> EvaluationResult {
+    let evaluate = |e: &Expression, v: &Variables| evaluate_counting_steps(e, v, o, sandbox, steps);
+
+    take_step(steps, o)?;
+
     match e {
-        Expression::Identifier(identifier) => match v.get(&identifier) {
+        Expression::Identifier(identifier) => match v.get(identifier) {
             Some(value) => Ok(value.clone()),
+            None if o.lenient_identifiers => Ok(Value::Empty),
             None => Err(EvaluationError::UndeclaredIndentifier(identifier.clone())),
         },
         Expression::Literal(literal) => Ok(Value::from(literal.clone())),
@@ -501,48 +1317,55 @@ pub fn evaluate(e: &Expression, v: &Variables) -> EvaluationResult {
             operator,
             right,
         } => {
-            let left = evaluate(left, v)?;
-
-            match operator {
-                Operator::And => match left.and_short_circuit() {
-                    Some(value) => Ok(value),
-                    None => Ok(left.and(&evaluate(right, v)?)?),
-                },
-                Operator::Nand => match left.nand_short_circuit() {
-                    Some(value) => Ok(value),
-                    None => Ok(left.nand(&evaluate(right, v)?)?),
-                },
-                Operator::Or => match left.or_short_circuit() {
-                    Some(value) => Ok(value),
-                    None => Ok(left.or(&evaluate(right, v)?)?),
-                },
-                Operator::Nor => match left.nor_short_circuit() {
-                    Some(value) => Ok(value),
-                    None => Ok(left.nor(&evaluate(right, v)?)?),
-                },
-                Operator::Xor => Ok(left.xor(&evaluate(right, v)?)?),
-                Operator::Equal => Ok(left.equal(&evaluate(right, v)?)?),
-                Operator::NotEqual => Ok(left.not_equal(&evaluate(right, v)?)?),
-                Operator::Plus => Ok(left.plus(&evaluate(right, v)?)?),
-                Operator::Minus => Ok(left.minus(&evaluate(right, v)?)?),
-                Operator::Multiply => Ok(left.multiply(&evaluate(right, v)?)?),
-                Operator::Divide => Ok(left.divide(&evaluate(right, v)?)?),
-                Operator::Matches => Ok(left.matches(&evaluate(right, v)?)?),
-                _ => panic!("invalid binary operation {:?}", operator),
+            if is_logical(operator) {
+                let left = logical_operand(evaluate(left, v)?, o);
+                apply_logical(operator, left, || {
+                    Ok(logical_operand(evaluate(right, v)?, o))
+                })
+            } else {
+                apply_binary(operator, evaluate(left, v)?, evaluate(right, v)?, o)
             }
         }
         Expression::UnaryOp {
             expression,
             operator,
+        } => apply_unary(operator, evaluate(expression, v)?, o),
+        Expression::Call {
+            function,
+            arguments,
+        } => {
+            let arguments = arguments
+                .iter()
+                .map(|argument| evaluate(argument, v))
+                .collect::<Result<Vec<Value>, EvaluationError>>()?;
+
+            apply_call(function, &arguments, o, sandbox)
+        }
+        Expression::List(elements) => Ok(Value::List(
+            elements
+                .iter()
+                .map(|element| evaluate(element, v))
+                .collect::<Result<Vec<Value>, EvaluationError>>()?,
+        )),
+        Expression::Match {
+            expression,
+            cases,
+            default,
         } => {
             let value = evaluate(expression, v)?;
+            for (pattern, case) in cases {
+                if let Value::Bool(true) = value.equal(&Value::from(pattern.clone()))? {
+                    return evaluate(case, v);
+                }
+            }
 
-            match operator {
-                Operator::Not => Ok(value.not()?),
-                Operator::Plus => Ok(value.unary_plus()?),
-                Operator::Minus => Ok(value.unary_minus()?),
-                _ => panic!("invalid unary operation {:?}", operator),
+            match default {
+                Some(default) => evaluate(default, v),
+                None => Err(no_match_case(&value)),
             }
         }
+        Expression::Comparison { first, comparisons } => {
+            apply_comparisons(evaluate(first, v)?, comparisons, |e| evaluate(e, v), o)
+        }
     }
 }
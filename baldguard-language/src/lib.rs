@@ -1,6 +1,19 @@
 use lalrpop_util::lalrpop_mod;
 
+pub mod builtins;
+pub mod compile;
+pub mod definitions;
+pub mod derive;
+pub mod environment;
+pub mod error_code;
 pub mod evaluation;
+pub mod lint;
 pub mod parse_error;
+pub mod trace;
 pub mod tree;
+pub mod vectors;
 lalrpop_mod!(pub grammar, "/grammar.rs");
+
+/// Version of the expression grammar. Bump it whenever a change to the grammar
+/// could make stored expression trees parse differently from their text.
+pub const LANGUAGE_VERSION: i64 = 2;
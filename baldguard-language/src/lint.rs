@@ -0,0 +1,180 @@
+use super::tree::{walk_expression, Expression, Literal, Operator, Visitor};
+use regex::Regex;
+use std::fmt::Display;
+
+/// Likely mistake in an expression that still evaluates.
+#[derive(Debug, Clone)]
+pub enum Warning {
+    AlwaysTrue(String),
+    AlwaysFalse(String),
+    /// Comparison of an expression with itself, whose result depends on the
+    /// type of the value.
+    SelfComparison(String),
+    InvalidRegex {
+        regex: String,
+        message: String,
+    },
+    UnknownIdentifier(String),
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::AlwaysTrue(expression) => write!(f, "{expression} is always true"),
+            Warning::AlwaysFalse(expression) => write!(f, "{expression} is always false"),
+            Warning::SelfComparison(expression) => {
+                write!(f, "{expression} compares an expression with itself")
+            }
+            Warning::InvalidRegex { regex, message } => {
+                write!(
+                    f,
+                    "regex \"{regex}\" is invalid and never matches: {message}"
+                )
+            }
+            Warning::UnknownIdentifier(identifier) => {
+                write!(f, "no message provides \"{identifier}\"")
+            }
+        }
+    }
+}
+
+/// Looks for parts of `expression` that are always true or false, regexes
+/// that can never match and identifiers for which `is_known` is false.
+pub fn lint(expression: &Expression, is_known: impl Fn(&str) -> bool) -> Vec<Warning> {
+    let mut linter = Linter {
+        is_known,
+        warnings: Vec::new(),
+    };
+    linter.visit_expression(expression);
+    linter.warnings
+}
+
+struct Linter<F> {
+    is_known: F,
+    warnings: Vec<Warning>,
+}
+
+impl<F> Linter<F> {
+    fn check_constant(&mut self, left: &Expression, operator: &Operator, right: &Expression) {
+        let text = || {
+            format!(
+                "({} {operator} {})",
+                left.to_canonical_string(),
+                right.to_canonical_string()
+            )
+        };
+        match constant_outcome(left, operator, right) {
+            Some(true) => self.warnings.push(Warning::AlwaysTrue(text())),
+            Some(false) => self.warnings.push(Warning::AlwaysFalse(text())),
+            None if is_self_comparison(left, operator, right) => {
+                self.warnings.push(Warning::SelfComparison(text()))
+            }
+            None => {}
+        }
+    }
+}
+
+impl<'a, F: Fn(&str) -> bool> Visitor<'a> for Linter<F> {
+    fn visit_expression(&mut self, expression: &'a Expression) {
+        if let Expression::Comparison { first, comparisons } = expression {
+            let mut left = first.as_ref();
+            for (operator, right) in comparisons {
+                self.check_constant(left, operator, right);
+                left = right;
+            }
+        }
+
+        if let Expression::BinaryOp {
+            left,
+            operator,
+            right,
+        } = expression
+        {
+            self.check_constant(left, operator, right);
+
+            let patterns: Vec<&str> = match (operator, right.as_ref()) {
+                (Operator::Matches, Expression::Literal(Literal::Str(pattern))) => vec![pattern],
+                (Operator::MatchesAny, Expression::List(elements)) => elements
+                    .iter()
+                    .filter_map(|element| match element {
+                        Expression::Literal(Literal::Str(pattern)) => Some(pattern.as_str()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            for pattern in patterns {
+                if let Err(e) = Regex::new(pattern) {
+                    self.warnings.push(Warning::InvalidRegex {
+                        regex: pattern.to_string(),
+                        message: e.to_string(),
+                    });
+                }
+            }
+        }
+
+        walk_expression(self, expression);
+    }
+
+    fn visit_identifier(&mut self, identifier: &'a str) {
+        if !(self.is_known)(identifier) {
+            self.warnings
+                .push(Warning::UnknownIdentifier(identifier.to_string()));
+        }
+    }
+}
+
+/// Value a binary operation has regardless of its operands, if any.
+/// Comparisons of an expression with itself only have one when it is an int
+/// or string literal, other values may not support the operator.
+fn constant_outcome(left: &Expression, operator: &Operator, right: &Expression) -> Option<bool> {
+    let is_literal = |expression: &Expression, value: bool| matches!(expression, Expression::Literal(Literal::Bool(literal)) if *literal == value);
+    let negates = |a: &Expression, b: &Expression| {
+        matches!(a, Expression::UnaryOp { expression, operator: Operator::Not }
+            if is_pure(b) && expression.to_canonical_string() == b.to_canonical_string())
+    };
+    let same = matches!(
+        left,
+        Expression::Literal(Literal::Int(_)) | Expression::Literal(Literal::Str(_))
+    ) && is_self_comparison(left, operator, right);
+
+    match operator {
+        Operator::Or if is_literal(left, true) || is_literal(right, true) => Some(true),
+        Operator::Or if negates(left, right) || negates(right, left) => Some(true),
+        Operator::And if is_literal(left, false) || is_literal(right, false) => Some(false),
+        Operator::And if negates(left, right) || negates(right, left) => Some(false),
+        Operator::Equal | Operator::LessEqual | Operator::GreaterEqual if same => Some(true),
+        Operator::NotEqual | Operator::Less | Operator::Greater if same => Some(false),
+        _ => None,
+    }
+}
+
+fn is_self_comparison(left: &Expression, operator: &Operator, right: &Expression) -> bool {
+    matches!(
+        operator,
+        Operator::Equal
+            | Operator::NotEqual
+            | Operator::Less
+            | Operator::LessEqual
+            | Operator::Greater
+            | Operator::GreaterEqual
+            | Operator::Xor
+    ) && is_pure(left)
+        && left.to_canonical_string() == right.to_canonical_string()
+}
+
+/// Whether evaluating the expression twice gives the same value, calls may
+/// have effects or depend on time.
+fn is_pure(expression: &Expression) -> bool {
+    struct CallFinder(bool);
+
+    impl<'a> Visitor<'a> for CallFinder {
+        fn visit_call(&mut self, _function: &'a str, _arguments: &'a [Expression]) {
+            self.0 = true;
+        }
+    }
+
+    let mut finder = CallFinder(false);
+    finder.visit_expression(expression);
+    !finder.0
+}
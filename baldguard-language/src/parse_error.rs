@@ -1,3 +1,4 @@
+use super::error_code;
 use std::fmt::Display;
 
 #[derive(Debug, Clone)]
@@ -6,6 +7,29 @@ pub enum ParseError {
     InvalidEscapeSequence(String),
 }
 
+impl ParseError {
+    /// Stable machine-readable name of the error kind.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ParseError::IntegerOverflow(_) => "integer_overflow",
+            ParseError::InvalidEscapeSequence(_) => "invalid_escape_sequence",
+        }
+    }
+}
+
+/// Code of an error returned by the generated parsers.
+pub fn code<L, T>(e: &lalrpop_util::ParseError<L, T, ParseError>) -> &'static str {
+    match e {
+        lalrpop_util::ParseError::User { error } => error.code(),
+        _ => "parse",
+    }
+}
+
+/// Number of an error returned by the generated parsers, see `error_code`.
+pub fn number<L, T>(e: &lalrpop_util::ParseError<L, T, ParseError>) -> &'static str {
+    error_code::number(code(e))
+}
+
 impl Display for ParseError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -0,0 +1,80 @@
+use super::{
+    evaluation::{EvaluationResult, Value, Variables},
+    tree::{Expression, Operator},
+};
+
+/// Evaluation of an expression split into the operands of its top-level `and`
+/// or `or`, to explain the value it gave.
+pub struct Trace<'a> {
+    /// Operator joining the steps, `None` if the expression has a single one.
+    pub operator: Option<Operator>,
+    pub steps: Vec<TraceStep<'a>>,
+}
+
+/// One operand with its value and the variables it reads.
+pub struct TraceStep<'a> {
+    pub expression: &'a Expression,
+    pub result: EvaluationResult,
+    /// Identifiers found in the variables, definitions aren't expanded.
+    pub variables: Vec<(&'a str, Value)>,
+}
+
+/// Evaluates every top-level operand of `expression` with `evaluate`, without
+/// short-circuiting, so each one shows up in the trace.
+pub fn trace<'a>(
+    expression: &'a Expression,
+    variables: &Variables,
+    mut evaluate: impl FnMut(&Expression) -> EvaluationResult,
+) -> Trace<'a> {
+    let operator = match expression {
+        Expression::BinaryOp {
+            operator: operator @ (Operator::And | Operator::Or),
+            ..
+        } => Some(operator.clone()),
+        _ => None,
+    };
+
+    let mut operands = Vec::new();
+    match &operator {
+        Some(operator) => collect_operands(expression, operator, &mut operands),
+        None => operands.push(expression),
+    }
+
+    let steps = operands
+        .into_iter()
+        .map(|operand| TraceStep {
+            expression: operand,
+            result: evaluate(operand),
+            variables: operand
+                .identifiers()
+                .into_iter()
+                .filter_map(|identifier| {
+                    variables
+                        .get(identifier)
+                        .map(|value| (identifier, value.clone()))
+                })
+                .collect(),
+        })
+        .collect();
+
+    Trace { operator, steps }
+}
+
+/// Flattens a chain of `operator`, like `a or b or c`, into its operands.
+fn collect_operands<'a>(
+    expression: &'a Expression,
+    operator: &Operator,
+    operands: &mut Vec<&'a Expression>,
+) {
+    match expression {
+        Expression::BinaryOp {
+            left,
+            operator: current,
+            right,
+        } if std::mem::discriminant(current) == std::mem::discriminant(operator) => {
+            collect_operands(left, operator, operands);
+            collect_operands(right, operator, operands);
+        }
+        _ => operands.push(expression),
+    }
+}
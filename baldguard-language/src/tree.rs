@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::fmt::Display;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Operator {
@@ -10,11 +11,41 @@ pub enum Operator {
     Xor,
     Equal,
     NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
     Plus,
     Minus,
     Multiply,
     Divide,
     Matches,
+    MatchesAny,
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operator::Not => write!(f, "not"),
+            Operator::And => write!(f, "and"),
+            Operator::Nand => write!(f, "nand"),
+            Operator::Or => write!(f, "or"),
+            Operator::Nor => write!(f, "nor"),
+            Operator::Xor => write!(f, "xor"),
+            Operator::Equal => write!(f, "="),
+            Operator::NotEqual => write!(f, "!="),
+            Operator::Less => write!(f, "<"),
+            Operator::LessEqual => write!(f, "<="),
+            Operator::Greater => write!(f, ">"),
+            Operator::GreaterEqual => write!(f, ">="),
+            Operator::Plus => write!(f, "+"),
+            Operator::Minus => write!(f, "-"),
+            Operator::Multiply => write!(f, "*"),
+            Operator::Divide => write!(f, "/"),
+            Operator::Matches => write!(f, "matches"),
+            Operator::MatchesAny => write!(f, "matches any"),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,6 +56,30 @@ pub enum Literal {
     Empty,
 }
 
+impl Display for Literal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Literal::Int(value) => write!(f, "{value}"),
+            Literal::Str(value) => {
+                write!(f, "\"")?;
+                for ch in value.chars() {
+                    match ch {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\r' => write!(f, "\\r")?,
+                        '\t' => write!(f, "\\t")?,
+                        _ => write!(f, "{ch}")?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Literal::Bool(value) => write!(f, "{value}"),
+            Literal::Empty => write!(f, "empty"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Expression {
     Identifier(String),
@@ -38,6 +93,242 @@ pub enum Expression {
         expression: Box<Expression>,
         operator: Operator,
     },
+    Call {
+        function: String,
+        arguments: Vec<Expression>,
+    },
+    List(Vec<Expression>),
+    Match {
+        expression: Box<Expression>,
+        cases: Vec<(Literal, Expression)>,
+        default: Option<Box<Expression>>,
+    },
+    /// Chain of two or more comparisons like `a < b <= c`, true if every
+    /// comparison is. Each operand is evaluated once, from left to right, and
+    /// evaluation stops at the first false comparison.
+    Comparison {
+        first: Box<Expression>,
+        comparisons: Vec<(Operator, Expression)>,
+    },
+}
+
+impl Expression {
+    pub fn chain_comparisons(
+        first: Box<Expression>,
+        mut comparisons: Vec<(Operator, Box<Expression>)>,
+    ) -> Box<Expression> {
+        if comparisons.len() == 1 {
+            let (operator, right) = comparisons.remove(0);
+            return Box::new(Expression::BinaryOp {
+                left: first,
+                operator,
+                right,
+            });
+        }
+
+        Box::new(Expression::Comparison {
+            first,
+            comparisons: comparisons
+                .into_iter()
+                .map(|(operator, operand)| (operator, *operand))
+                .collect(),
+        })
+    }
+
+    /// Builds a match expression from its cases, the case without a pattern
+    /// (`_`) is the default and, if present, always comes last.
+    pub fn new_match(
+        expression: Box<Expression>,
+        mut cases: Vec<(Option<Literal>, Expression)>,
+    ) -> Box<Expression> {
+        let default = match cases.last() {
+            Some((None, _)) => cases.pop().map(|(_, default)| Box::new(default)),
+            _ => None,
+        };
+
+        Box::new(Expression::Match {
+            expression,
+            cases: cases
+                .into_iter()
+                .filter_map(|(pattern, case)| pattern.map(|pattern| (pattern, case)))
+                .collect(),
+            default,
+        })
+    }
+
+    /// Prints the expression fully parenthesized, so that parsing the result
+    /// with the grammar of `LANGUAGE_VERSION` gives back the same tree.
+    pub fn to_canonical_string(&self) -> String {
+        let mut result = String::new();
+        self.write_canonical(&mut result);
+        result
+    }
+
+    fn write_canonical(&self, result: &mut String) {
+        match self {
+            Expression::Identifier(identifier) => result.push_str(identifier),
+            Expression::Literal(literal) => result.push_str(&literal.to_string()),
+            Expression::BinaryOp {
+                left,
+                operator,
+                right,
+            } => {
+                result.push('(');
+                left.write_canonical(result);
+                result.push_str(&format!(" {operator} "));
+                right.write_canonical(result);
+                result.push(')');
+            }
+            Expression::UnaryOp {
+                expression,
+                operator,
+            } => {
+                // the space keeps "- 1" from being read as the literal -1
+                result.push_str(&format!("({operator} "));
+                expression.write_canonical(result);
+                result.push(')');
+            }
+            Expression::Call {
+                function,
+                arguments,
+            } => {
+                result.push_str(function);
+                result.push('(');
+                write_canonical_list(arguments, result);
+                result.push(')');
+            }
+            Expression::List(elements) => {
+                result.push('[');
+                write_canonical_list(elements, result);
+                result.push(']');
+            }
+            Expression::Match {
+                expression,
+                cases,
+                default,
+            } => {
+                result.push_str("match ");
+                expression.write_canonical(result);
+                result.push_str(" {");
+                for (i, (pattern, case)) in cases.iter().enumerate() {
+                    if i > 0 {
+                        result.push(',');
+                    }
+                    result.push_str(&format!(" {pattern} => "));
+                    case.write_canonical(result);
+                }
+                if let Some(default) = default {
+                    if !cases.is_empty() {
+                        result.push(',');
+                    }
+                    result.push_str(" _ => ");
+                    default.write_canonical(result);
+                }
+                result.push_str(" }");
+            }
+            Expression::Comparison { first, comparisons } => {
+                result.push('(');
+                first.write_canonical(result);
+                for (operator, operand) in comparisons {
+                    result.push_str(&format!(" {operator} "));
+                    operand.write_canonical(result);
+                }
+                result.push(')');
+            }
+        }
+    }
+
+    pub fn identifiers(&self) -> Vec<&str> {
+        let mut collector = IdentifierCollector::default();
+        collector.visit_expression(self);
+        collector.identifiers
+    }
+}
+
+/// Read-only traversal of an expression tree. Every method defaults to
+/// visiting the children of its node, so an implementation only overrides the
+/// nodes it is interested in and calls the matching `walk_*` function to keep
+/// descending.
+pub trait Visitor<'a> {
+    fn visit_expression(&mut self, expression: &'a Expression) {
+        walk_expression(self, expression);
+    }
+
+    fn visit_identifier(&mut self, _identifier: &'a str) {}
+
+    fn visit_literal(&mut self, _literal: &'a Literal) {}
+
+    fn visit_call(&mut self, _function: &'a str, arguments: &'a [Expression]) {
+        walk_expressions(self, arguments);
+    }
+}
+
+pub fn walk_expression<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, expression: &'a Expression) {
+    match expression {
+        Expression::Identifier(identifier) => visitor.visit_identifier(identifier),
+        Expression::Literal(literal) => visitor.visit_literal(literal),
+        Expression::BinaryOp { left, right, .. } => {
+            visitor.visit_expression(left);
+            visitor.visit_expression(right);
+        }
+        Expression::UnaryOp { expression, .. } => visitor.visit_expression(expression),
+        Expression::Call {
+            function,
+            arguments,
+        } => visitor.visit_call(function, arguments),
+        Expression::List(elements) => walk_expressions(visitor, elements),
+        Expression::Match {
+            expression,
+            cases,
+            default,
+        } => {
+            visitor.visit_expression(expression);
+            for (pattern, case) in cases {
+                visitor.visit_literal(pattern);
+                visitor.visit_expression(case);
+            }
+            if let Some(default) = default {
+                visitor.visit_expression(default);
+            }
+        }
+        Expression::Comparison { first, comparisons } => {
+            visitor.visit_expression(first);
+            for (_, operand) in comparisons {
+                visitor.visit_expression(operand);
+            }
+        }
+    }
+}
+
+pub fn walk_expressions<'a, V: Visitor<'a> + ?Sized>(
+    visitor: &mut V,
+    expressions: &'a [Expression],
+) {
+    for expression in expressions {
+        visitor.visit_expression(expression);
+    }
+}
+
+fn write_canonical_list(expressions: &[Expression], result: &mut String) {
+    for (i, expression) in expressions.iter().enumerate() {
+        if i > 0 {
+            result.push_str(", ");
+        }
+        expression.write_canonical(result);
+    }
+}
+
+#[derive(Default)]
+struct IdentifierCollector<'a> {
+    identifiers: Vec<&'a str>,
+}
+
+impl<'a> Visitor<'a> for IdentifierCollector<'a> {
+    fn visit_identifier(&mut self, identifier: &'a str) {
+        if !self.identifiers.contains(&identifier) {
+            self.identifiers.push(identifier);
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -0,0 +1,241 @@
+use super::{
+    compile::CompiledExpression,
+    environment::{EmptyEnvironment, Sandbox},
+    evaluation::{evaluate_with_options, EvaluationOptions, EvaluationResult, Value, Variables},
+    grammar::ExpressionParser,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Expected outcome of evaluating a test vector.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Expected {
+    Value(Value),
+    /// Message of the error, without the hints that may follow it.
+    Error(String),
+}
+
+/// An expression with the variables it is evaluated with and its result, for
+/// other implementations of the language to check their semantics against.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TestVector {
+    pub expression: String,
+    pub env: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub lenient_identifiers: bool,
+    #[serde(default)]
+    pub null_safe: bool,
+    pub expected: Expected,
+}
+
+impl TestVector {
+    fn options(&self) -> EvaluationOptions {
+        EvaluationOptions {
+            lenient_identifiers: self.lenient_identifiers,
+            null_safe: self.null_safe,
+            max_steps: None,
+        }
+    }
+
+    fn variables(&self) -> Variables {
+        let mut variables = Variables::new();
+        for (name, value) in &self.env {
+            variables.put(name.clone(), value.clone());
+        }
+
+        variables
+    }
+
+    /// Evaluates the expression both interpreted and compiled, failing if
+    /// either result differs from the expected one.
+    pub fn check(&self) -> Result<(), String> {
+        let expression = ExpressionParser::new()
+            .parse(&self.expression)
+            .map_err(|e| format!("{}: {e}", self.expression))?;
+        let variables = self.variables();
+        let options = self.options();
+        let sandbox = Sandbox::new(&EmptyEnvironment);
+        let outcomes = [
+            (
+                "evaluated",
+                evaluate_with_options(&expression, &variables, &options),
+            ),
+            (
+                "compiled",
+                CompiledExpression::compile(&expression).evaluate(&variables, &options, &sandbox),
+            ),
+        ];
+
+        let expected = serde_json::to_value(&self.expected).map_err(|e| e.to_string())?;
+        for (how, outcome) in outcomes {
+            let actual = serde_json::to_value(expected_from(outcome)).map_err(|e| e.to_string())?;
+            if actual != expected {
+                return Err(format!(
+                    "{}: {how} to {actual}, expected {expected}",
+                    self.expression
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn expected_from(outcome: EvaluationResult) -> Expected {
+    match outcome {
+        Ok(value) => Expected::Value(value),
+        Err(e) => Expected::Error(e.to_string().lines().next().unwrap_or("").to_string()),
+    }
+}
+
+struct Case {
+    expression: &'static str,
+    env: Vec<(&'static str, Value)>,
+    lenient_identifiers: bool,
+    null_safe: bool,
+}
+
+impl Case {
+    fn new(expression: &'static str) -> Self {
+        Case {
+            expression,
+            env: Vec::new(),
+            lenient_identifiers: false,
+            null_safe: false,
+        }
+    }
+
+    fn with(mut self, name: &'static str, value: Value) -> Self {
+        self.env.push((name, value));
+        self
+    }
+
+    fn lenient(mut self) -> Self {
+        self.lenient_identifiers = true;
+        self
+    }
+
+    fn null_safe(mut self) -> Self {
+        self.null_safe = true;
+        self
+    }
+}
+
+fn str(s: &str) -> Value {
+    Value::Str(s.to_string())
+}
+
+/// Expressions covering operator precedence, every value type, regexes,
+/// `match`, the evaluation options and the error cases.
+fn cases() -> Vec<Case> {
+    vec![
+        Case::new("1 + 2 * 3"),
+        Case::new("(1 + 2) * 3"),
+        Case::new("7 / 2"),
+        Case::new("-7 / 2"),
+        Case::new("1 / 0"),
+        Case::new("- 3 + 1"),
+        Case::new("\"bald\" + \"guard\""),
+        Case::new("1 + \"a\""),
+        Case::new("[1, 2] + [3]"),
+        Case::new("true and false or true"),
+        Case::new("true xor true"),
+        Case::new("false nand true"),
+        Case::new("false nor false"),
+        Case::new("not true"),
+        Case::new("false and 1 / 0 = 1"),
+        Case::new("true or x").lenient(),
+        Case::new("1 < 2 < 3"),
+        Case::new("3 > 2 >= 2 < 1"),
+        Case::new("\"a\" < \"b\""),
+        Case::new("1 = 1"),
+        Case::new("1 != \"1\""),
+        Case::new("empty = empty"),
+        Case::new("[1, \"a\"] = [1, \"a\"]"),
+        Case::new("text matches \"^sp[a4]m$\"").with("text", str("sp4m")),
+        Case::new("text matches any [\"foo\", \"ba+r\"]").with("text", str("baaar")),
+        Case::new("text matches any [\"foo\", \"qux\"]").with("text", str("bar")),
+        Case::new("text matches \"(\"").with("text", str("x")),
+        Case::new("text matches pattern")
+            .with("text", str("hello"))
+            .with("pattern", str("^h")),
+        Case::new("match n { 1 => \"one\", 2 => \"two\", _ => \"many\" }").with("n", Value::Int(2)),
+        Case::new("match n { 1 => \"one\", _ => \"many\" }").with("n", Value::Int(5)),
+        Case::new("match n { 1 => \"one\" }").with("n", Value::Int(5)),
+        Case::new("missing"),
+        Case::new("missing").lenient(),
+        Case::new("missing > 1").lenient(),
+        Case::new("missing > 1").lenient().null_safe(),
+        Case::new("not missing").lenient().null_safe(),
+        Case::new("missing and true").lenient().null_safe(),
+        Case::new("unknown_function(1)"),
+        Case::new("word_count(\"one two  three\")"),
+        Case::new("caps_ratio(\"AAbb\")"),
+        Case::new("normalize(\"Ｓｐａｍ\")"),
+        Case::new("word_count(1)"),
+    ]
+}
+
+/// Test vectors of the built-in cases with the results of the interpreter,
+/// to be reviewed before they replace the checked-in ones.
+pub fn generate() -> Result<Vec<TestVector>, String> {
+    let parser = ExpressionParser::new();
+    let mut vectors = Vec::new();
+    for case in cases() {
+        let mut vector = TestVector {
+            expression: case.expression.to_string(),
+            env: case
+                .env
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value))
+                .collect(),
+            lenient_identifiers: case.lenient_identifiers,
+            null_safe: case.null_safe,
+            expected: Expected::Error(String::new()),
+        };
+        let expression = parser
+            .parse(case.expression)
+            .map_err(|e| format!("{}: {e}", case.expression))?;
+        vector.expected = expected_from(evaluate_with_options(
+            &expression,
+            &vector.variables(),
+            &vector.options(),
+        ));
+        vectors.push(vector);
+    }
+
+    Ok(vectors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checked_in() -> Vec<TestVector> {
+        serde_json::from_str(include_str!("../test_vectors.json")).unwrap()
+    }
+
+    #[test]
+    fn checked_in_vectors_hold_for_both_evaluators() {
+        for vector in checked_in() {
+            vector.check().unwrap();
+        }
+    }
+
+    #[test]
+    fn every_case_has_a_checked_in_vector() {
+        let checked_in = checked_in();
+        for case in cases() {
+            assert!(
+                checked_in
+                    .iter()
+                    .any(|vector| vector.expression == case.expression
+                        && vector.lenient_identifiers == case.lenient_identifiers
+                        && vector.null_safe == case.null_safe),
+                "{} has no checked-in vector",
+                case.expression
+            );
+        }
+    }
+}
@@ -1,51 +1,258 @@
 use proc_macro::TokenStream;
 use quote::{quote, ToTokens};
-use syn::{parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Fields, Ident};
+use syn::{
+    ext::IdentExt, parse_macro_input, spanned::Spanned, Data, DeriveInput, Error, Expr, Fields,
+    Ident, LitStr, Member,
+};
 
 enum FieldType {
     Int,
     Str,
     Bool,
+    DateTime,
+    /// `Vec<String>`, a list of strings.
+    StrList,
+    /// Type implementing the derived trait itself, flattened with the field
+    /// name as prefix. Enums give a single variable named after the field.
+    Nested,
+}
+
+impl Field {
+    /// Type name listed in `VARIABLE_NAMES`.
+    fn type_name(&self) -> String {
+        if self.optional {
+            format!("{} or empty", self.ty.type_str())
+        } else {
+            self.ty.type_str().to_string()
+        }
+    }
+}
+
+impl FieldType {
+    /// Type name as reported by the language.
+    fn type_str(&self) -> &'static str {
+        match self {
+            FieldType::Int => "int",
+            FieldType::Str => "str",
+            FieldType::Bool => "bool",
+            FieldType::DateTime => "datetime",
+            FieldType::StrList => "list",
+            FieldType::Nested => unreachable!("nested fields have no single type"),
+        }
+    }
 }
 
 struct Field {
-    name: Ident,
+    name: Member,
+    /// Name of the variable, the field name without `r#` unless renamed. The
+    /// field of a newtype gives a single unnamed variable, which the struct
+    /// containing it names after its own field.
+    variable: String,
     ty: FieldType,
+    /// Type of the field, without `Option`.
+    rust_type: syn::Type,
     optional: bool,
+    /// Constraints from `#[assignment(...)]`, checked by SetFromAssignment.
+    constraints: Constraints,
+}
+
+#[derive(Default)]
+struct Constraints {
+    min: Option<Expr>,
+    max: Option<Expr>,
+    matches: Option<LitStr>,
 }
 
 struct Derived {
     name: Ident,
+    namespace: Option<String>,
     fields: Vec<Field>,
+    /// Fields excluded with `#[to_variables(skip)]`.
+    skipped: Vec<Member>,
+}
+
+struct FieldAttributes {
+    rename: Option<String>,
+    skip: bool,
+    constraints: Constraints,
+}
+
+fn parse_field_attributes(field: &syn::Field) -> Result<FieldAttributes, Error> {
+    let mut result = FieldAttributes {
+        rename: None,
+        skip: false,
+        constraints: Constraints::default(),
+    };
+    for attr in &field.attrs {
+        if attr.path().is_ident("assignment") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("min") {
+                    result.constraints.min = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("max") {
+                    result.constraints.max = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else if meta.path.is_ident("matches") {
+                    result.constraints.matches = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("Unsupported assignment attribute"))
+                }
+            })?;
+            continue;
+        }
+
+        if !attr.path().is_ident("to_variables") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                let value: LitStr = meta.value()?.parse()?;
+                result.rename = Some(value.value());
+                Ok(())
+            } else if meta.path.is_ident("skip") {
+                result.skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("Unsupported to_variables attribute"))
+            }
+        })?;
+    }
+
+    Ok(result)
 }
 
-fn parse(input: DeriveInput, allow_optional: bool) -> Result<Derived, Error> {
+fn parse_namespace(input: &DeriveInput) -> Result<Option<String>, Error> {
+    let mut namespace = None;
+    for attr in &input.attrs {
+        if !attr.path().is_ident("variables") {
+            continue;
+        }
+
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("namespace") {
+                let value: LitStr = meta.value()?.parse()?;
+                namespace = Some(value.value());
+                Ok(())
+            } else {
+                Err(meta.error("Unsupported variables attribute"))
+            }
+        })?;
+    }
+
+    Ok(namespace)
+}
+
+/// `T` of an `Option<T>` type.
+fn option_inner_type(ty: &syn::Type) -> Option<&syn::Type> {
+    let syn::Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    let syn::PathArguments::AngleBracketed(arguments) = &segment.arguments else {
+        return None;
+    };
+    match arguments.args.first()? {
+        syn::GenericArgument::Type(inner) => Some(inner),
+        _ => None,
+    }
+}
+
+/// Fieldless enum, converted to the snake case name of its variant.
+struct DerivedEnum {
+    name: Ident,
+    variants: Vec<(Ident, String)>,
+}
+
+fn parse_enum(input: &DeriveInput) -> Option<Result<DerivedEnum, Error>> {
+    let Data::Enum(data) = &input.data else {
+        return None;
+    };
+
+    let mut variants = Vec::with_capacity(data.variants.len());
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Some(Err(Error::new(
+                variant.fields.span(),
+                "Only fieldless enums are supported",
+            )));
+        }
+        variants.push((
+            variant.ident.clone(),
+            to_snake_case(&variant.ident.to_string()),
+        ));
+    }
+
+    Some(Ok(DerivedEnum {
+        name: input.ident.clone(),
+        variants,
+    }))
+}
+
+fn to_snake_case(name: &str) -> String {
+    let mut result = String::with_capacity(name.len() + 4);
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                result.push('_');
+            }
+            result.extend(c.to_lowercase());
+        } else {
+            result.push(c);
+        }
+    }
+
+    result
+}
+
+/// `allow_compound` permits nested and list fields, which only the derives
+/// producing variables support.
+fn parse(input: DeriveInput, allow_optional: bool, allow_compound: bool) -> Result<Derived, Error> {
     let mut result = Derived {
         name: input.ident.clone(),
+        namespace: parse_namespace(&input)?,
         fields: Vec::new(),
+        skipped: Vec::new(),
     };
 
     let fields = if let Data::Struct(s) = input.data {
-        if let Fields::Named(fields) = s.fields {
-            fields
-        } else {
-            return Err(Error::new(
-                s.fields.span(),
-                "Only structs with named fields are supported",
-            ));
+        match s.fields {
+            Fields::Named(fields) => fields.named,
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => fields.unnamed,
+            fields => {
+                return Err(Error::new(
+                    fields.span(),
+                    "Only structs with named fields and newtypes are supported",
+                ));
+            }
         }
     } else {
         return Err(Error::new(input.ident.span(), "Only structs are supported"));
     };
 
-    result.fields.reserve(fields.named.len());
-    for field in fields.named {
-        let name = field.ident.expect("Unnamed field in fields.named");
+    result.fields.reserve(fields.len());
+    for (index, field) in fields.into_iter().enumerate() {
+        let attributes = parse_field_attributes(&field)?;
+        let (name, variable) = match &field.ident {
+            Some(ident) => (Member::Named(ident.clone()), ident.unraw().to_string()),
+            None => (Member::Unnamed(index.into()), String::new()),
+        };
+        if attributes.skip {
+            result.skipped.push(name);
+            continue;
+        }
+
         let mut optional = false;
+        let mut rust_type = field.ty.clone();
         let ty = match field.ty.to_token_stream().to_string().as_str() {
             "i64" => FieldType::Int,
             "String" => FieldType::Str,
             "bool" => FieldType::Bool,
+            "DateTime < Utc >" => FieldType::DateTime,
             "Option < i64 >" => {
                 optional = true;
                 FieldType::Int
@@ -58,6 +265,18 @@ fn parse(input: DeriveInput, allow_optional: bool) -> Result<Derived, Error> {
                 optional = true;
                 FieldType::Bool
             }
+            "Option < DateTime < Utc > >" => {
+                optional = true;
+                FieldType::DateTime
+            }
+            "Vec < String >" if allow_compound => FieldType::StrList,
+            _ if allow_compound => {
+                if let Some(inner) = option_inner_type(&field.ty) {
+                    optional = true;
+                    rust_type = inner.clone();
+                }
+                FieldType::Nested
+            }
             other => {
                 return Err(Error::new(
                     field.ty.span(),
@@ -73,7 +292,29 @@ fn parse(input: DeriveInput, allow_optional: bool) -> Result<Derived, Error> {
             ));
         }
 
-        let field = Field { name, ty, optional };
+        let constraints = attributes.constraints;
+        if (constraints.min.is_some() || constraints.max.is_some()) && !matches!(ty, FieldType::Int)
+        {
+            return Err(Error::new(
+                field.ty.span(),
+                "min and max are only supported on integer fields",
+            ));
+        }
+        if constraints.matches.is_some() && !matches!(ty, FieldType::Str) {
+            return Err(Error::new(
+                field.ty.span(),
+                "matches is only supported on string fields",
+            ));
+        }
+
+        let field = Field {
+            variable: attributes.rename.unwrap_or(variable),
+            name,
+            ty,
+            rust_type,
+            optional,
+            constraints,
+        };
 
         result.fields.push(field);
     }
@@ -81,10 +322,17 @@ fn parse(input: DeriveInput, allow_optional: bool) -> Result<Derived, Error> {
     Ok(result)
 }
 
-#[proc_macro_derive(ToVariables)]
+#[proc_macro_derive(ToVariables, attributes(variables, to_variables))]
 pub fn to_variables(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    let input = match parse(input, true) {
+    if let Some(derived) = parse_enum(&input) {
+        return match derived {
+            Ok(derived) => enum_to_variables(derived),
+            Err(e) => e.to_compile_error().into(),
+        };
+    }
+
+    let input = match parse(input, true, true) {
         Ok(input) => input,
         Err(e) => {
             return e.to_compile_error().into();
@@ -92,29 +340,112 @@ pub fn to_variables(input: TokenStream) -> TokenStream {
     };
 
     let name = input.name;
+    let (namespace, names_namespace) = match input.namespace {
+        Some(namespace) => (
+            quote! { result.into_namespace(#namespace) },
+            quote! {
+                result
+                    .into_iter()
+                    .map(|(name, ty)| {
+                        (
+                            ::std::format!(
+                                "{}{}{}",
+                                #namespace,
+                                ::baldguard_language::evaluation::NAMESPACE_SEPARATOR,
+                                name
+                            ),
+                            ty,
+                        )
+                    })
+                    .collect()
+            },
+        ),
+        None => (quote! { result }, quote! { result }),
+    };
     let mut assignments = Vec::new();
+    let mut names = Vec::new();
 
     for field in input.fields {
+        let variable = &field.variable;
+        let name_entry = if let FieldType::Nested = field.ty {
+            let rust_type = &field.rust_type;
+            let ty = if field.optional {
+                quote! { ::std::format!("{} or empty", ty) }
+            } else {
+                quote! { ty }
+            };
+            quote! {
+                for (name, ty) in
+                    <#rust_type as ::baldguard_language::evaluation::ToVariables>::variable_names()
+                {
+                    let name = if name.is_empty() {
+                        #variable.to_string()
+                    } else {
+                        ::std::format!("{}_{}", #variable, name)
+                    };
+                    result.push((name, #ty));
+                }
+            }
+        } else {
+            let type_name = field.type_name();
+            quote! {
+                result.push((#variable.to_string(), #type_name.to_string()));
+            }
+        };
+        names.push(name_entry);
+
         let field_name = field.name;
+        let variable = field.variable;
         let put = match field.ty {
             FieldType::Int => {
                 quote! {
-                    result.put(::std::stringify!(#field_name).to_string(),
+                    result.put(#variable.to_string(),
                         ::baldguard_language::evaluation::Value::Int(value));
                 }
             }
             FieldType::Str => {
                 quote! {
-                    result.put(::std::stringify!(#field_name).to_string(),
+                    result.put(#variable.to_string(),
                         ::baldguard_language::evaluation::Value::Str(value));
                 }
             }
             FieldType::Bool => {
                 quote! {
-                    result.put(::std::stringify!(#field_name).to_string(),
+                    result.put(#variable.to_string(),
                         ::baldguard_language::evaluation::Value::Bool(value));
                 }
             }
+            FieldType::DateTime => {
+                quote! {
+                    result.put(#variable.to_string(),
+                        ::baldguard_language::evaluation::Value::DateTime(value));
+                }
+            }
+            FieldType::StrList => {
+                quote! {
+                    result.put(#variable.to_string(),
+                        ::baldguard_language::evaluation::Value::List(
+                            value
+                                .into_iter()
+                                .map(::baldguard_language::evaluation::Value::Str)
+                                .collect(),
+                        ));
+                }
+            }
+            FieldType::Nested => {
+                quote! {
+                    for (name, value) in
+                        ::baldguard_language::evaluation::ToVariables::to_variables(value)
+                    {
+                        let name = if name.is_empty() {
+                            #variable.to_string()
+                        } else {
+                            ::std::format!("{}_{}", #variable, name)
+                        };
+                        result.put(name, value);
+                    }
+                }
+            }
         };
 
         let assignment = if field.optional {
@@ -122,7 +453,7 @@ pub fn to_variables(input: TokenStream) -> TokenStream {
                 if let Some(value) = self.#field_name {
                     #put
                 } else {
-                    result.put(::std::stringify!(#field_name).to_string(),
+                    result.put(#variable.to_string(),
                         ::baldguard_language::evaluation::Value::Empty);
                 }
             }
@@ -141,17 +472,123 @@ pub fn to_variables(input: TokenStream) -> TokenStream {
             fn to_variables(self) -> ::baldguard_language::evaluation::Variables {
                 let mut result = ::baldguard_language::evaluation::Variables::new();
                 #(#assignments)*
+                #namespace
+            }
+
+            fn variable_names() -> ::std::vec::Vec<(::std::string::String, ::std::string::String)> {
+                let mut result = ::std::vec::Vec::new();
+                #(#names)*
+                #names_namespace
+            }
+        }
+    }
+    .into()
+}
+
+/// Enums give a single unnamed variable, which the struct containing them
+/// names after the field.
+fn enum_to_variables(derived: DerivedEnum) -> TokenStream {
+    let name = derived.name;
+    let cases = derived.variants.iter().map(|(variant, value)| {
+        quote! { #name::#variant => #value }
+    });
+
+    quote! {
+        impl ::baldguard_language::evaluation::ToVariables for #name {
+            fn to_variables(self) -> ::baldguard_language::evaluation::Variables {
+                let value = match self {
+                    #(#cases),*
+                };
+                let mut result = ::baldguard_language::evaluation::Variables::new();
+                result.put(::std::string::String::new(),
+                    ::baldguard_language::evaluation::Value::Str(value.to_string()));
                 result
             }
+
+            fn variable_names() -> ::std::vec::Vec<(::std::string::String, ::std::string::String)> {
+                ::std::vec![(::std::string::String::new(), "str".to_string())]
+            }
+        }
+    }
+    .into()
+}
+
+#[proc_macro_derive(FromVariables, attributes(variables, to_variables))]
+pub fn from_variables(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let input = match parse(input, true, false) {
+        Ok(input) => input,
+        Err(e) => {
+            return e.to_compile_error().into();
+        }
+    };
+
+    let name = input.name;
+    let mut initializers = Vec::new();
+
+    for field in input.fields {
+        let field_name = field.name;
+        let field_variable = &field.variable;
+        let variable = match &input.namespace {
+            Some(namespace) => quote! {
+                ::std::format!(
+                    "{}{}{}",
+                    #namespace,
+                    ::baldguard_language::evaluation::NAMESPACE_SEPARATOR,
+                    #field_variable
+                )
+                .as_str()
+            },
+            None => quote! { #field_variable },
+        };
+        let get = match field.ty {
+            FieldType::Int => quote! { variables.get_int(#variable)? },
+            FieldType::Str => quote! { variables.get_str(#variable)?.to_string() },
+            FieldType::Bool => quote! { variables.get_bool(#variable)? },
+            FieldType::DateTime => quote! { variables.get_datetime(#variable)? },
+            FieldType::StrList | FieldType::Nested => {
+                unreachable!("compound fields are rejected by parse")
+            }
+        };
+
+        let initializer = if field.optional {
+            quote! {
+                #field_name: match variables.get(#variable) {
+                    ::std::option::Option::None
+                    | ::std::option::Option::Some(
+                        ::baldguard_language::evaluation::Value::Empty,
+                    ) => ::std::option::Option::None,
+                    ::std::option::Option::Some(_) => ::std::option::Option::Some(#get),
+                }
+            }
+        } else {
+            quote! {
+                #field_name: #get
+            }
+        };
+
+        initializers.push(initializer);
+    }
+    let skipped = input.skipped;
+
+    quote! {
+        impl ::baldguard_language::evaluation::FromVariables for #name {
+            fn from_variables(variables: &::baldguard_language::evaluation::Variables)
+            -> Result<Self, ::baldguard_language::evaluation::EvaluationError> {
+                Ok(#name {
+                    #(#initializers,)*
+                    #(#skipped: ::std::default::Default::default(),)*
+                })
+            }
         }
     }
     .into()
 }
 
-#[proc_macro_derive(SetFromAssignment)]
+#[proc_macro_derive(SetFromAssignment, attributes(to_variables, assignment))]
 pub fn set_from_assignment(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    let input = match parse(input, true) {
+    let input = match parse(input, true, false) {
         Ok(input) => input,
         Err(e) => {
             return e.to_compile_error().into();
@@ -160,8 +597,13 @@ pub fn set_from_assignment(input: TokenStream) -> TokenStream {
 
     let name = input.name;
     let mut cases = Vec::new();
+    let mut names = Vec::new();
+    let mut constraint_checks = Vec::new();
     for field in input.fields {
+        let type_name = field.type_name();
         let field_name = field.name;
+        let variable = field.variable;
+        names.push(quote! { (#variable, #type_name) });
 
         let (needed_type, correct_case) = match field.ty {
             FieldType::Int => (
@@ -182,14 +624,57 @@ pub fn set_from_assignment(input: TokenStream) -> TokenStream {
                     ::baldguard_language::evaluation::Value::Bool(value)
                 },
             ),
+            FieldType::DateTime => (
+                "datetime",
+                quote! {
+                    ::baldguard_language::evaluation::Value::DateTime(value)
+                },
+            ),
+            FieldType::StrList | FieldType::Nested => {
+                unreachable!("compound fields are rejected by parse")
+            }
         };
 
+        let constraints = field.constraints;
+        // Checks the value `value` evaluates to, an i64 or a String.
+        let check_value = |value: &dyn ToTokens| {
+            if constraints.min.is_some() || constraints.max.is_some() {
+                let min = match &constraints.min {
+                    Some(min) => quote! { ::std::option::Option::Some(#min) },
+                    None => quote! { ::std::option::Option::None },
+                };
+                let max = match &constraints.max {
+                    Some(max) => quote! { ::std::option::Option::Some(#max) },
+                    None => quote! { ::std::option::Option::None },
+                };
+                quote! {
+                    ::baldguard_language::evaluation::check_range(#variable, #value, #min, #max)?;
+                }
+            } else if let Some(pattern) = &constraints.matches {
+                quote! {
+                    ::baldguard_language::evaluation::check_pattern(#variable, &#value, #pattern)?;
+                }
+            } else {
+                quote! {}
+            }
+        };
+        let check = check_value(&quote! { value });
+        let check_field = if field.optional {
+            let check = check_value(&quote! { *value });
+            quote! {
+                if let ::std::option::Option::Some(value) = &self.#field_name {
+                    #check
+                }
+            }
+        } else {
+            check_value(&quote! { self.#field_name })
+        };
+        constraint_checks.push(check_field);
+
         let wrong_case = quote! {
-            _ => {
-                let field_name = ::std::stringify!(#field_name);
-                let needed_type = #needed_type;
-                return Err(::baldguard_language::evaluation::ValueError::new_other(
-                    ::std::format!("variable {} shoud be of type {}", field_name, needed_type)
+            value => {
+                return Err(::baldguard_language::evaluation::ValueError::new_type_mismatch(
+                    #variable, #needed_type, value
                 ).into());
             },
         };
@@ -198,6 +683,7 @@ pub fn set_from_assignment(input: TokenStream) -> TokenStream {
             quote! {
                 match value {
                     #correct_case => {
+                        #check
                         self.#field_name = ::std::option::Option::Some(value);
                     },
                     ::baldguard_language::evaluation::Value::Empty => {
@@ -210,10 +696,11 @@ pub fn set_from_assignment(input: TokenStream) -> TokenStream {
             quote! {
                 match value {
                     #correct_case => {
+                        #check
                         self.#field_name = value;
                     },
                     ::baldguard_language::evaluation::Value::Empty => {
-                        let field_name = ::std::stringify!(#field_name);
+                        let field_name = #variable;
                         return Err(::baldguard_language::evaluation::ValueError::new_other(
                             ::std::format!("variable {} cannot be empty", field_name)
                         ).into());
@@ -224,7 +711,7 @@ pub fn set_from_assignment(input: TokenStream) -> TokenStream {
         };
 
         let case = quote! {
-            stringify!(#field_name) => {
+            #variable => {
                 #assign
             }
         };
@@ -234,6 +721,8 @@ pub fn set_from_assignment(input: TokenStream) -> TokenStream {
 
     quote! {
         impl ::baldguard_language::evaluation::SetFromAssignment for #name {
+            const VARIABLE_NAMES: &'static [(&'static str, &'static str)] = &[#(#names),*];
+
             fn set_from_assignment(&mut self,
                 assignment: &::baldguard_language::tree::Assignment,
                 variables: &::baldguard_language::evaluation::Variables,
@@ -258,15 +747,38 @@ pub fn set_from_assignment(input: TokenStream) -> TokenStream {
 
                 Ok(())
             }
+
+            fn check_constraints(&self)
+            -> Result<(), ::baldguard_language::evaluation::ValueError> {
+                #(#constraint_checks)*
+                Ok(())
+            }
         }
     }
     .into()
 }
 
-#[proc_macro_derive(ContainsVariable)]
+#[proc_macro_derive(ContainsVariable, attributes(variables, to_variables))]
 pub fn contains_variable(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
-    let input = match parse(input, true) {
+    if let Some(derived) = parse_enum(&input) {
+        return match derived {
+            Ok(derived) => {
+                let name = derived.name;
+                quote! {
+                    impl ::baldguard_language::evaluation::ContainsVariable for #name {
+                        fn contains_variable(&self, identifier: &::std::primitive::str) -> bool {
+                            identifier.is_empty()
+                        }
+                    }
+                }
+                .into()
+            }
+            Err(e) => e.to_compile_error().into(),
+        };
+    }
+
+    let input = match parse(input, true, true) {
         Ok(input) => input,
         Err(e) => {
             return e.to_compile_error().into();
@@ -274,20 +786,68 @@ pub fn contains_variable(input: TokenStream) -> TokenStream {
     };
 
     let name = input.name;
-    let mut idents = Vec::new();
+    let strip_namespace = match &input.namespace {
+        Some(namespace) => quote! {
+            let identifier = identifier
+                .strip_prefix(#namespace)
+                .and_then(|name| {
+                    name.strip_prefix(::baldguard_language::evaluation::NAMESPACE_SEPARATOR)
+                })
+                .unwrap_or(identifier);
+        },
+        None => quote! {},
+    };
+
+    let mut identifiers = Vec::new();
+    let mut nested = Vec::new();
     for field in input.fields {
-        idents.push(field.name);
+        match field.ty {
+            FieldType::Nested => {
+                let field_name = field.name;
+                let variable = field.variable;
+                let prefix = format!("{variable}_");
+                let contains = quote! {
+                    (identifier == #variable
+                        && ::baldguard_language::evaluation::ContainsVariable::contains_variable(
+                            value, ""))
+                    || identifier.strip_prefix(#prefix).is_some_and(|identifier| {
+                        !identifier.is_empty()
+                            && ::baldguard_language::evaluation::ContainsVariable::contains_variable(
+                                value, identifier)
+                    })
+                };
+                if field.optional {
+                    // An absent value is put as a single empty variable.
+                    nested.push(quote! {
+                        || match &self.#field_name {
+                            ::std::option::Option::Some(value) => #contains,
+                            ::std::option::Option::None => identifier == #variable,
+                        }
+                    });
+                } else {
+                    nested.push(quote! {
+                        || {
+                            let value = &self.#field_name;
+                            #contains
+                        }
+                    });
+                }
+            }
+            _ => identifiers.push(field.variable),
+        }
     }
 
+    let flat = if identifiers.is_empty() {
+        quote! { false }
+    } else {
+        quote! { matches!(identifier, #(#identifiers)|*) }
+    };
+
     quote! {
         impl ::baldguard_language::evaluation::ContainsVariable for #name {
             fn contains_variable(&self, identifier: &::std::primitive::str) -> bool {
-                match identifier {
-                    #(
-                        stringify!(#idents) => true,
-                    )*
-                    _ => false,
-                }
+                #strip_namespace
+                #flat #(#nested)*
             }
         }
     }
@@ -0,0 +1,60 @@
+use std::{fmt::Display, str::FromStr};
+
+/// What an inline button does, sent back by Telegram as the data of a
+/// callback query. Telegram limits the data to 64 bytes, so buttons only carry
+/// ids and anything else stays in the session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Callback {
+    /// Check the forwarded message against the filters of a chat.
+    Check { chat_id: i64 },
+    /// Keep or delete a quarantined message of a chat.
+    Quarantine {
+        approve: bool,
+        chat_id: i64,
+        message_id: i32,
+    },
+    /// Ask the admins to review the deletion of a message.
+    Appeal { message_id: i32 },
+}
+
+impl FromStr for Callback {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<&str>>().as_slice() {
+            ["check", chat_id] => Ok(Callback::Check {
+                chat_id: chat_id.parse().map_err(|_| ())?,
+            }),
+            ["quarantine", decision, chat_id, message_id] => Ok(Callback::Quarantine {
+                approve: match *decision {
+                    "approve" => true,
+                    "delete" => false,
+                    _ => return Err(()),
+                },
+                chat_id: chat_id.parse().map_err(|_| ())?,
+                message_id: message_id.parse().map_err(|_| ())?,
+            }),
+            ["appeal", message_id] => Ok(Callback::Appeal {
+                message_id: message_id.parse().map_err(|_| ())?,
+            }),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Callback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Callback::Check { chat_id } => write!(f, "check:{chat_id}"),
+            Callback::Quarantine {
+                approve,
+                chat_id,
+                message_id,
+            } => {
+                let decision = if *approve { "approve" } else { "delete" };
+                write!(f, "quarantine:{decision}:{chat_id}:{message_id}")
+            }
+            Callback::Appeal { message_id } => write!(f, "appeal:{message_id}"),
+        }
+    }
+}
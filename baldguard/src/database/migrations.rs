@@ -114,6 +114,303 @@ async fn nullify_all_filters_after_filter_schema_change(db: Database) -> Migrati
     Ok(())
 }
 
+async fn add_field(db: Database, path: &str, value: Bson) -> MigrationActionResult {
+    let chats: Collection<Document> = db.collection("chats");
+    chats
+        .update_many(
+            doc! {
+                path: { "$exists": false }
+            },
+            doc! {
+                "$set": {
+                    path: value
+                }
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn add_setting(db: Database, name: &str, value: Bson) -> MigrationActionResult {
+    add_field(db, &format!("settings.{name}"), value).await
+}
+
+async fn add_digest_interval_days_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "digest_interval_days", Bson::Int64(0)).await
+}
+
+async fn add_title_and_admin_ids(db: Database) -> MigrationActionResult {
+    add_field(db.clone(), "title", Bson::Null).await?;
+    add_field(db, "admin_ids", Bson::Array(Vec::new())).await
+}
+
+async fn add_identifier_mode_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "identifier_mode", Bson::String("normal".to_string())).await
+}
+
+async fn add_null_safe_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "null_safe", Bson::Boolean(false)).await
+}
+
+async fn add_definitions(db: Database) -> MigrationActionResult {
+    add_field(
+        db,
+        "definitions",
+        Bson::Document(doc! { "definitions": {} }),
+    )
+    .await
+}
+
+async fn add_cooldowns(db: Database) -> MigrationActionResult {
+    add_field(db, "cooldowns", Bson::Document(doc! {})).await
+}
+
+async fn add_counters(db: Database) -> MigrationActionResult {
+    add_field(db, "counters", Bson::Document(doc! {})).await
+}
+
+/// Filters stored before versioning are marked with version 0, so sessions
+/// re-parse their text with the current grammar.
+async fn add_language_version_to_filters(db: Database) -> MigrationActionResult {
+    let chats: Collection<Document> = db.collection("chats");
+    chats
+        .update_many(
+            doc! {
+                "filter": { "$ne": null },
+                "filter.language_version": { "$exists": false }
+            },
+            doc! {
+                "$set": {
+                    "filter.language_version": 0i64
+                }
+            },
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn add_default_verdict_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "default_verdict", Bson::String("allow".to_string())).await
+}
+
+async fn add_max_delete_delay_seconds_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "max_delete_delay_seconds", Bson::Int64(0)).await
+}
+
+async fn add_max_evaluation_steps_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "max_evaluation_steps", Bson::Int64(10000)).await
+}
+
+async fn add_rewrite_links_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "rewrite_links", Bson::Boolean(false)).await
+}
+
+async fn add_public_stats_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "public_stats", Bson::Boolean(false)).await
+}
+
+async fn split_debug_print_in_settings(db: Database) -> MigrationActionResult {
+    let chats: Collection<Document> = db.collection("chats");
+    chats
+        .update_many(
+            doc! { "settings.debug_print": { "$exists": true } },
+            vec![
+                doc! {
+                    "$set": {
+                        "settings.debug_parse": "$settings.debug_print",
+                        "settings.debug_evaluation": "$settings.debug_print",
+                        "settings.debug_actions": "$settings.debug_print",
+                        "settings.debug_persistence": "$settings.debug_print"
+                    }
+                },
+                doc! { "$unset": "settings.debug_print" },
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn add_verbosity_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "verbosity", Bson::String("normal".to_string())).await
+}
+
+async fn add_fetch_profiles_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "fetch_profiles", Bson::Boolean(false)).await
+}
+
+async fn add_trusted_ids(db: Database) -> MigrationActionResult {
+    add_field(db, "trusted_ids", Bson::Array(Vec::new())).await
+}
+
+/// The single filter becomes the first of the named filters, called "default".
+async fn move_filter_to_filters(db: Database) -> MigrationActionResult {
+    let chats: Collection<Document> = db.collection("chats");
+    chats
+        .update_many(
+            doc! { "filters": { "$exists": false } },
+            vec![
+                doc! {
+                    "$set": {
+                        "filters": {
+                            "$cond": [
+                                { "$eq": [{ "$ifNull": ["$filter", null] }, null] },
+                                [],
+                                [{ "$mergeObjects": ["$filter", { "name": "default", "enabled": true }] }]
+                            ]
+                        }
+                    }
+                },
+                doc! { "$unset": "filter" },
+            ],
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn add_action_to_filters(db: Database) -> MigrationActionResult {
+    let chats: Collection<Document> = db.collection("chats");
+    chats
+        .update_many(
+            doc! {},
+            vec![doc! {
+                "$set": {
+                    "filters": {
+                        "$map": {
+                            "input": "$filters",
+                            "in": { "$mergeObjects": [{ "action": "delete" }, "$$this"] }
+                        }
+                    }
+                }
+            }],
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn add_shadow_to_filters(db: Database) -> MigrationActionResult {
+    let chats: Collection<Document> = db.collection("chats");
+    chats
+        .update_many(
+            doc! {},
+            vec![doc! {
+                "$set": {
+                    "filters": {
+                        "$map": {
+                            "input": "$filters",
+                            "in": { "$mergeObjects": [{ "shadow": false }, "$$this"] }
+                        }
+                    }
+                }
+            }],
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn add_exemptions(db: Database) -> MigrationActionResult {
+    add_field(db.clone(), "exempt_ids", Bson::Array(Vec::new())).await?;
+    add_field(db, "exempt_usernames", Bson::Array(Vec::new())).await
+}
+
+async fn add_apply_to_admins_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "apply_to_admins", Bson::Boolean(false)).await
+}
+
+async fn add_filter_history(db: Database) -> MigrationActionResult {
+    add_field(db, "filter_history", Bson::Array(Vec::new())).await
+}
+
+async fn add_log_chat_id_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "log_chat_id", Bson::Int64(0)).await
+}
+
+async fn add_appeal_button_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "appeal_button", Bson::Boolean(false)).await
+}
+
+async fn add_utc_offset_minutes_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "utc_offset_minutes", Bson::Int64(0)).await
+}
+
+async fn add_test_cases(db: Database) -> MigrationActionResult {
+    add_field(db, "test_cases", Bson::Array(Vec::new())).await
+}
+
+async fn add_flood_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db.clone(), "flood_messages", Bson::Int64(0)).await?;
+    add_setting(db.clone(), "flood_window", Bson::Int64(10)).await?;
+    add_setting(db, "flood_action", Bson::String("mute 10".to_string())).await
+}
+
+async fn add_blocked_domains(db: Database) -> MigrationActionResult {
+    add_field(db, "blocked_domains", Bson::Array(Vec::new())).await
+}
+
+async fn add_blocked_origins(db: Database) -> MigrationActionResult {
+    add_field(db.clone(), "blocked_origin_ids", Bson::Array(Vec::new())).await?;
+    add_field(db, "blocked_origin_usernames", Bson::Array(Vec::new())).await
+}
+
+async fn add_check_reputation_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "check_reputation", Bson::Boolean(false)).await
+}
+
+async fn add_delete_command_messages_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "delete_command_messages", Bson::Boolean(false)).await
+}
+
+async fn add_filtered_message(db: Database) -> MigrationActionResult {
+    add_field(db, "filtered_message", Bson::String(String::new())).await
+}
+
+async fn add_command_prefix_to_settings(db: Database) -> MigrationActionResult {
+    add_setting(db, "command_prefix", Bson::String("/".to_string())).await
+}
+
+async fn add_aliases(db: Database) -> MigrationActionResult {
+    add_field(db, "aliases", Bson::Document(Document::new())).await
+}
+
+/// Counters were bare values, they become records with the time of their
+/// last increment, set to the time of the migration.
+async fn add_updated_to_counters(db: Database) -> MigrationActionResult {
+    let chats: Collection<Document> = db.collection("chats");
+    let mut cursor = chats.find(doc! {}).await?;
+    let now = chrono::Utc::now().timestamp();
+
+    while let Some(doc) = cursor.next().await {
+        let doc = doc?;
+        let Ok(counters) = doc.get_document("counters") else {
+            continue;
+        };
+        let mut records = Document::new();
+        for (name, value) in counters {
+            records.insert(name, doc! { "value": value.clone(), "updated": now });
+        }
+
+        chats
+            .update_one(
+                doc! {
+                    "_id": doc.get("_id").unwrap()
+                },
+                doc! {
+                    "$set": {
+                        "counters": records
+                    }
+                },
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
 pub fn get_vec() -> Vec<MigrationAction> {
     macro_rules! migration_action {
         ($name:ident) => {
@@ -135,16 +432,54 @@ pub fn get_vec() -> Vec<MigrationAction> {
         move_filter_enabled_to_settings,
         add_report_command_success_to_settings,
         add_variables,
-        nullify_all_filters_after_filter_schema_change
+        nullify_all_filters_after_filter_schema_change,
+        add_digest_interval_days_to_settings,
+        add_title_and_admin_ids,
+        add_identifier_mode_to_settings,
+        add_null_safe_to_settings,
+        add_definitions,
+        add_cooldowns,
+        add_counters,
+        add_language_version_to_filters,
+        add_default_verdict_to_settings,
+        add_max_delete_delay_seconds_to_settings,
+        add_max_evaluation_steps_to_settings,
+        add_rewrite_links_to_settings,
+        add_public_stats_to_settings,
+        split_debug_print_in_settings,
+        add_verbosity_to_settings,
+        add_fetch_profiles_to_settings,
+        add_trusted_ids,
+        move_filter_to_filters,
+        add_action_to_filters,
+        add_exemptions,
+        add_apply_to_admins_to_settings,
+        add_filter_history,
+        add_log_chat_id_to_settings,
+        add_appeal_button_to_settings,
+        add_utc_offset_minutes_to_settings,
+        add_shadow_to_filters,
+        add_test_cases,
+        add_flood_to_settings,
+        add_blocked_domains,
+        add_blocked_origins,
+        add_check_reputation_to_settings,
+        add_delete_command_messages_to_settings,
+        add_filtered_message,
+        add_command_prefix_to_settings,
+        add_aliases,
+        add_updated_to_counters
     ]
 }
 
 pub type MigrationActionResult = Result<(), Box<dyn Error + Send + Sync>>;
 
+type MigrationActionFn =
+    Box<dyn FnOnce(Database) -> Pin<Box<dyn Future<Output = MigrationActionResult>>>>;
+
 pub struct MigrationAction {
     pub name: String,
-    pub action:
-        Option<Box<dyn FnOnce(Database) -> Pin<Box<dyn Future<Output = MigrationActionResult>>>>>,
+    pub action: Option<MigrationActionFn>,
 }
 
 impl MigrationAction {
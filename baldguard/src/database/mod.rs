@@ -1,66 +1,1060 @@
 mod migrations;
+pub mod query;
 
 use super::error::GenericError;
-use baldguard_language::{evaluation::Variables, tree::Expression};
-use baldguard_macros::{SetFromAssignment, ToVariables};
-use mongodb::{bson::doc, options::IndexOptions, Client, Collection, Database, IndexModel};
+use baldguard_language::{
+    compile::CompiledExpression,
+    definitions::DefinedExpressions,
+    environment::{Effect, Sandbox},
+    evaluation::{
+        evaluate_in_sandbox, EvaluationError, EvaluationOptions, EvaluationResult, ValueError,
+        Variables,
+    },
+    tree::Expression,
+    LANGUAGE_VERSION,
+};
+use baldguard_macros::{FromVariables, SetFromAssignment, ToVariables};
+use chrono::{DateTime, Datelike, NaiveDateTime, NaiveTime, SecondsFormat, Timelike, Weekday};
+use futures::TryStreamExt;
+use mongodb::{
+    bson::doc,
+    bson::oid::ObjectId,
+    error::{ErrorKind, WriteFailure, RETRYABLE_WRITE_ERROR},
+    options::IndexOptions,
+    Client, Collection, Database, IndexModel,
+};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt::Display,
+    str::FromStr,
+};
 
-#[derive(Serialize, Deserialize, Clone, Debug, SetFromAssignment, ToVariables)]
+pub const SECONDS_PER_DAY: i64 = 86400;
+pub const MAX_DELETE_DELAY_SECONDS: i64 = 300;
+pub const MAX_DIGEST_INTERVAL_DAYS: i64 = 365;
+/// Sessions only keep the events of the last ten minutes.
+pub const MAX_FLOOD_WINDOW_SECONDS: i64 = 600;
+const MAX_PENDING_WRITES: usize = 10000;
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+const MAX_FILTER_HISTORY: usize = 20;
+/// Counters not incremented for 30 days are reset, per-user counters would
+/// otherwise keep growing the chat document.
+pub const COUNTER_TTL_SECONDS: i64 = 30 * SECONDS_PER_DAY;
+/// Beyond this the counters incremented longest ago are reset, documents
+/// can't exceed 16 MB.
+const MAX_COUNTERS: usize = 10000;
+pub const MAX_BLOCKED_DOMAINS: usize = 1000;
+pub const MAX_BLOCKED_ORIGINS: usize = 1000;
+pub const MAX_BLOCKED_MEDIA: u64 = 1000;
+pub const MAX_TEST_CASES: usize = 100;
+pub const MAX_ALIASES: usize = 100;
+
+#[derive(Serialize, Deserialize, Clone, Debug, SetFromAssignment, ToVariables, FromVariables)]
 pub struct Settings {
-    pub debug_print: bool,
+    pub debug_parse: bool,
+    pub debug_evaluation: bool,
+    pub debug_actions: bool,
+    pub debug_persistence: bool,
     pub report_filtered: bool,
     pub report_invalid_commands: bool,
     pub filter_enabled: bool,
     pub report_command_success: bool,
+    #[assignment(min = 0, max = MAX_DIGEST_INTERVAL_DAYS)]
+    pub digest_interval_days: i64,
+    #[assignment(matches = "^(normal|strict|lenient)$")]
+    pub identifier_mode: String,
+    pub null_safe: bool,
+    #[assignment(matches = "^(allow|deny)$")]
+    pub default_verdict: String,
+    #[assignment(min = 0, max = MAX_DELETE_DELAY_SECONDS)]
+    pub max_delete_delay_seconds: i64,
+    #[assignment(min = 0)]
+    pub max_evaluation_steps: i64,
+    pub rewrite_links: bool,
+    pub public_stats: bool,
+    #[assignment(matches = "^(quiet|normal|verbose)$")]
+    pub verbosity: String,
+    pub fetch_profiles: bool,
+    pub check_reputation: bool,
+    pub delete_command_messages: bool,
+    /// 1 to 3 symbols, neither letters, digits nor spaces.
+    #[assignment(matches = r"^[^\p{Alphabetic}\p{N}\s]{1,3}$")]
+    pub command_prefix: String,
+    pub apply_to_admins: bool,
+    pub log_chat_id: i64,
+    pub appeal_button: bool,
+    pub utc_offset_minutes: i64,
+    #[assignment(min = 0)]
+    pub flood_messages: i64,
+    #[assignment(min = 1, max = MAX_FLOOD_WINDOW_SECONDS)]
+    pub flood_window: i64,
+    /// Any action but quarantine, which needs a message to review.
+    #[assignment(matches = "^(delete|warn|mute [1-9][0-9]{0,8}|ban|report)$")]
+    pub flood_action: String,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Settings {
-            debug_print: false,
+            debug_parse: false,
+            debug_evaluation: false,
+            debug_actions: false,
+            debug_persistence: false,
             report_filtered: true,
             report_invalid_commands: true,
             filter_enabled: true,
             report_command_success: true,
+            digest_interval_days: 0,
+            identifier_mode: IdentifierMode::Normal.to_string(),
+            null_safe: false,
+            default_verdict: Verdict::Allow.to_string(),
+            max_delete_delay_seconds: 0,
+            max_evaluation_steps: 10000,
+            rewrite_links: false,
+            public_stats: false,
+            verbosity: Verbosity::Normal.to_string(),
+            fetch_profiles: false,
+            check_reputation: false,
+            delete_command_messages: false,
+            command_prefix: "/".to_string(),
+            apply_to_admins: false,
+            log_chat_id: 0,
+            appeal_button: false,
+            utc_offset_minutes: 0,
+            flood_messages: 0,
+            flood_window: 10,
+            flood_action: Action::Mute(10).to_string(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn flood_action(&self) -> Action {
+        Action::from_str(&self.flood_action).unwrap_or(Action::Delete)
+    }
+
+    pub fn identifier_mode(&self) -> IdentifierMode {
+        IdentifierMode::from_str(&self.identifier_mode).unwrap_or(IdentifierMode::Normal)
+    }
+
+    pub fn default_verdict(&self) -> Verdict {
+        Verdict::from_str(&self.default_verdict).unwrap_or(Verdict::Allow)
+    }
+
+    pub fn verbosity(&self) -> Verbosity {
+        Verbosity::from_str(&self.verbosity).unwrap_or(Verbosity::Normal)
+    }
+
+    pub fn should_report_filtered(&self) -> bool {
+        match self.verbosity() {
+            Verbosity::Quiet => false,
+            Verbosity::Normal => self.report_filtered,
+            Verbosity::Verbose => true,
+        }
+    }
+
+    pub fn should_report_invalid_commands(&self) -> bool {
+        match self.verbosity() {
+            Verbosity::Quiet => false,
+            Verbosity::Normal => self.report_invalid_commands,
+            Verbosity::Verbose => true,
+        }
+    }
+
+    pub fn should_report_command_success(&self) -> bool {
+        match self.verbosity() {
+            Verbosity::Quiet => false,
+            Verbosity::Normal => self.report_command_success,
+            Verbosity::Verbose => true,
+        }
+    }
+
+    pub fn should_report_actions(&self) -> bool {
+        self.debug_actions || self.verbosity() == Verbosity::Verbose
+    }
+
+    pub fn should_show_hints(&self) -> bool {
+        self.verbosity() != Verbosity::Quiet
+    }
+
+    pub fn evaluation_options(&self) -> EvaluationOptions {
+        EvaluationOptions {
+            lenient_identifiers: self.identifier_mode() == IdentifierMode::Lenient,
+            null_safe: self.null_safe,
+            max_steps: match self.max_evaluation_steps {
+                0 => None,
+                steps => Some(steps as u64),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentifierMode {
+    Normal,
+    Strict,
+    Lenient,
+}
+
+impl FromStr for IdentifierMode {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(IdentifierMode::Normal),
+            "strict" => Ok(IdentifierMode::Strict),
+            "lenient" => Ok(IdentifierMode::Lenient),
+            other => Err(ValueError::new_other(format!(
+                "invalid identifier mode \"{other}\", expected normal, strict or lenient"
+            ))),
+        }
+    }
+}
+
+impl Display for IdentifierMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentifierMode::Normal => write!(f, "normal"),
+            IdentifierMode::Strict => write!(f, "strict"),
+            IdentifierMode::Lenient => write!(f, "lenient"),
+        }
+    }
+}
+
+/// Outcome for messages in a chat without a filter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny,
+}
+
+impl FromStr for Verdict {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Verdict::Allow),
+            "deny" => Ok(Verdict::Deny),
+            other => Err(ValueError::new_other(format!(
+                "invalid verdict \"{other}\", expected allow or deny"
+            ))),
+        }
+    }
+}
+
+impl Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verdict::Allow => write!(f, "allow"),
+            Verdict::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// What happens when a filter matches a message.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    #[default]
+    Delete,
+    /// Reply to the message with a warning and keep it.
+    Warn,
+    /// Delete the message and mute the sender for this many minutes.
+    Mute(i64),
+    /// Delete the message and ban the sender.
+    Ban,
+    /// Keep the message and only report the match to the chat.
+    Report,
+    /// Keep the message and let admins approve or delete it from the log
+    /// chat, or the chat itself if it has none.
+    Quarantine,
+}
+
+impl FromStr for Action {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            ["delete"] => Ok(Action::Delete),
+            ["warn"] => Ok(Action::Warn),
+            ["mute", minutes] => match minutes.parse() {
+                Ok(minutes) if minutes > 0 => Ok(Action::Mute(minutes)),
+                _ => Err(ValueError::new_other(format!(
+                    "invalid mute duration \"{minutes}\", expected a positive number of minutes"
+                ))),
+            },
+            ["ban"] => Ok(Action::Ban),
+            ["report"] => Ok(Action::Report),
+            ["quarantine"] => Ok(Action::Quarantine),
+            _ => Err(ValueError::new_other(format!(
+                "invalid action \"{s}\", expected delete, warn, mute <minutes>, ban, report or quarantine"
+            ))),
+        }
+    }
+}
+
+impl Action {
+    pub fn deletes(&self) -> bool {
+        matches!(self, Action::Delete | Action::Mute(_) | Action::Ban)
+    }
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Delete => write!(f, "delete"),
+            Action::Warn => write!(f, "warn"),
+            Action::Mute(minutes) => write!(f, "mute {minutes}"),
+            Action::Ban => write!(f, "ban"),
+            Action::Report => write!(f, "report"),
+            Action::Quarantine => write!(f, "quarantine"),
+        }
+    }
+}
+
+/// How much detail replies include, on top of the individual report options.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+impl FromStr for Verbosity {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "quiet" => Ok(Verbosity::Quiet),
+            "normal" => Ok(Verbosity::Normal),
+            "verbose" => Ok(Verbosity::Verbose),
+            other => Err(ValueError::new_other(format!(
+                "invalid verbosity \"{other}\", expected quiet, normal or verbose"
+            ))),
+        }
+    }
+}
+
+impl Display for Verbosity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verbosity::Quiet => write!(f, "quiet"),
+            Verbosity::Normal => write!(f, "normal"),
+            Verbosity::Verbose => write!(f, "verbose"),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Filter {
+    pub name: String,
+    /// Disabled filters are kept but not evaluated.
+    pub enabled: bool,
+    pub action: Action,
     pub text: String,
     pub expression: Expression,
+    /// `LANGUAGE_VERSION` of the grammar `text` was parsed with.
+    pub language_version: i64,
+    /// When the filter is evaluated, always if `None`.
+    pub schedule: Option<Schedule>,
+    /// Unix time in seconds the filter disables itself at.
+    pub expires_at: Option<i64>,
+    /// Shadow filters only log and count their matches, without acting on
+    /// the message.
+    pub shadow: bool,
 }
 
 impl Filter {
-    pub fn new(text: String, expression: Expression) -> Self {
-        Self { text, expression }
+    pub fn new(name: String, text: String, expression: Expression) -> Self {
+        Self {
+            name,
+            enabled: true,
+            action: Action::Delete,
+            text,
+            expression,
+            language_version: LANGUAGE_VERSION,
+            schedule: None,
+            expires_at: None,
+            shadow: false,
+        }
+    }
+
+    /// Whether the filter is evaluated at `time`, local time of the chat.
+    pub fn is_active(&self, time: NaiveDateTime) -> bool {
+        self.enabled
+            && self
+                .schedule
+                .as_ref()
+                .is_none_or(|schedule| schedule.is_active(time))
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.language_version < LANGUAGE_VERSION
+    }
+
+    /// The filter with `expression`, parsed from its text with the current
+    /// grammar.
+    pub fn reparsed(&self, expression: Expression) -> Self {
+        Self {
+            enabled: self.enabled,
+            action: self.action,
+            schedule: self.schedule.clone(),
+            expires_at: self.expires_at,
+            shadow: self.shadow,
+            ..Self::new(self.name.clone(), self.text.clone(), expression)
+        }
     }
 }
 
+/// Variables of a message with whether a filter should match it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TestCase {
+    pub name: String,
+    pub expect_match: bool,
+    /// Message variables, without their namespace which isn't serialized.
+    pub variables: Variables,
+}
+
+/// When a filter is evaluated, in the local time of the chat.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+    /// Days of the week counted from monday as 0, every day if empty.
+    pub days: Vec<u32>,
+    /// Minutes of the day the filter starts and stops being evaluated at, the
+    /// whole day if `None`. A window ending before it starts goes past
+    /// midnight and belongs to the day it starts on.
+    pub window: Option<(u32, u32)>,
+}
+
+impl Schedule {
+    pub fn is_active(&self, time: NaiveDateTime) -> bool {
+        let minute = time.hour() * 60 + time.minute();
+        let day = time.weekday().num_days_from_monday();
+        let (in_window, day) = match self.window {
+            None => (true, day),
+            Some((start, end)) if start < end => (start <= minute && minute < end, day),
+            Some((start, _)) if minute >= start => (true, day),
+            Some((_, end)) => (minute < end, (day + 6) % 7),
+        };
+
+        in_window && (self.days.is_empty() || self.days.contains(&day))
+    }
+}
+
+fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY_ERROR_CODE
+    )
+}
+
+/// Whether `error` comes from the database being unreachable, as opposed to
+/// one it refused the write with, which replaying it would get again.
+fn is_connectivity_error(error: &(dyn Error + Send + Sync + 'static)) -> bool {
+    let Some(error) = error.downcast_ref::<mongodb::error::Error>() else {
+        return false;
+    };
+
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Io(_)
+            | ErrorKind::ConnectionPoolCleared { .. }
+            | ErrorKind::ServerSelection { .. }
+            | ErrorKind::DnsResolve { .. }
+    ) || error.contains_label(RETRYABLE_WRITE_ERROR)
+}
+
+fn parse_minute_of_day(s: &str) -> Result<u32, ValueError> {
+    NaiveTime::parse_from_str(s, "%H:%M")
+        .map(|time| time.hour() * 60 + time.minute())
+        .map_err(|_| ValueError::new_other(format!("invalid time \"{s}\", expected HH:MM")))
+}
+
+impl FromStr for Schedule {
+    type Err = ValueError;
+
+    /// Parses days, like `weekends` or `mon,tue`, and a window like
+    /// `22:00-06:00`, separated by whitespace. Either can be left out.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut days = Vec::new();
+        let mut window = None;
+        for part in s.split_whitespace() {
+            if let Some((start, end)) = part.split_once('-') {
+                if window.is_some() {
+                    return Err(ValueError::new_other(
+                        "a schedule has at most one time window".to_string(),
+                    ));
+                }
+                window = Some((parse_minute_of_day(start)?, parse_minute_of_day(end)?));
+                continue;
+            }
+
+            match part {
+                "weekdays" => days.extend(0..5),
+                "weekends" => days.extend(5..7),
+                _ => {
+                    for day in part.split(',') {
+                        let day = day.parse::<Weekday>().map_err(|_| {
+                            ValueError::new_other(format!(
+                                "invalid day \"{day}\", expected weekdays, weekends, a day like mon or a time window like 22:00-06:00"
+                            ))
+                        })?;
+                        days.push(day.num_days_from_monday());
+                    }
+                }
+            }
+        }
+        if days.is_empty() && window.is_none() {
+            return Err(ValueError::new_other("empty schedule".to_string()));
+        }
+
+        days.sort_unstable();
+        days.dedup();
+        Ok(Schedule { days, window })
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if !self.days.is_empty() {
+            let days: Vec<String> = self
+                .days
+                .iter()
+                .filter_map(|day| Weekday::try_from(*day as u8).ok())
+                .map(|day| day.to_string().to_lowercase())
+                .collect();
+            parts.push(days.join(","));
+        }
+        if let Some((start, end)) = self.window {
+            parts.push(format!(
+                "{:02}:{:02}-{:02}:{:02}",
+                start / 60,
+                start % 60,
+                end / 60,
+                end % 60
+            ));
+        }
+
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Text a filter had before a change, kept so the change can be reverted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct FilterVersion {
+    pub name: String,
+    /// `None` if the filter didn't exist before the change.
+    pub text: Option<String>,
+    /// User who made the change.
+    pub author_id: Option<i64>,
+    /// Time of the change in unix seconds.
+    pub time: i64,
+}
+
+impl Display for FilterVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = DateTime::from_timestamp(self.time, 0).unwrap_or_default();
+        write!(
+            f,
+            "{}: {} changed",
+            time.to_rfc3339_opts(SecondsFormat::Secs, true),
+            self.name
+        )?;
+        if let Some(author_id) = self.author_id {
+            write!(f, " by {author_id}")?;
+        }
+        match &self.text {
+            Some(text) => write!(f, ", it was {text}"),
+            None => write!(f, ", it didn't exist"),
+        }
+    }
+}
+
+/// Moderation setup of a chat, exported by /export_config and restored by
+/// /import_config.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChatConfig {
+    pub filters: Vec<Filter>,
+    pub settings: Settings,
+    pub variables: Variables,
+    pub definitions: DefinedExpressions,
+    pub trusted_ids: Vec<i64>,
+    pub exempt_ids: Vec<i64>,
+    pub exempt_usernames: Vec<String>,
+    /// Missing from exports made before the domain blocklist.
+    #[serde(default)]
+    pub blocked_domains: Vec<String>,
+    #[serde(default)]
+    pub blocked_origin_ids: Vec<i64>,
+    #[serde(default)]
+    pub blocked_origin_usernames: Vec<String>,
+    #[serde(default)]
+    pub filtered_message: String,
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Chat {
     pub chat_id: i64,
-    pub filter: Option<Filter>,
+    pub title: Option<String>,
+    pub admin_ids: Vec<i64>,
+    /// Users exempted from rules through `from_is_trusted`.
+    pub trusted_ids: Vec<i64>,
+    /// Users whose messages are never evaluated.
+    pub exempt_ids: Vec<i64>,
+    /// Lowercase usernames, without the @, of users and bots whose messages
+    /// are never evaluated.
+    pub exempt_usernames: Vec<String>,
+    /// Lowercase domains whose links, subdomains included, get a message
+    /// deleted before the filters are evaluated. Sorted.
+    pub blocked_domains: Vec<String>,
+    /// Users, chats and channels whose messages are deleted when forwarded.
+    pub blocked_origin_ids: Vec<i64>,
+    /// Lowercase usernames, without the @, of blocked forward origins.
+    pub blocked_origin_usernames: Vec<String>,
+    /// Filters in the order they are evaluated, the first match wins.
+    pub filters: Vec<Filter>,
+    /// Latest changes of filters, oldest first.
+    pub filter_history: Vec<FilterVersion>,
+    /// Messages with the verdict the filters should give them, checked by
+    /// /run_tests.
+    pub test_cases: Vec<TestCase>,
+    /// Template of the report of filtered messages, the default one if empty.
+    pub filtered_message: String,
+    /// Commands, with their leading /, by the name of their shortcut.
+    pub aliases: HashMap<String, String>,
     pub settings: Settings,
     pub variables: Variables,
+    pub definitions: DefinedExpressions,
+    /// Expiry time (unix seconds) of every active `cooldown()` key.
+    pub cooldowns: HashMap<String, i64>,
+    /// Counters changed with `inc()`.
+    pub counters: HashMap<String, Counter>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Counter {
+    pub value: i64,
+    /// Unix seconds of the last increment.
+    pub updated: i64,
+}
+
+impl Counter {
+    /// Value of the counter at `now` (unix seconds), 0 once it expired.
+    pub fn value_at(&self, now: i64) -> i64 {
+        if now - self.updated < COUNTER_TTL_SECONDS {
+            self.value
+        } else {
+            0
+        }
+    }
 }
 
 impl Default for Chat {
     fn default() -> Self {
         Chat {
             chat_id: 0,
-            filter: None,
+            title: None,
+            admin_ids: Vec::new(),
+            trusted_ids: Vec::new(),
+            exempt_ids: Vec::new(),
+            exempt_usernames: Vec::new(),
+            blocked_domains: Vec::new(),
+            blocked_origin_ids: Vec::new(),
+            blocked_origin_usernames: Vec::new(),
+            filters: Vec::new(),
+            filter_history: Vec::new(),
+            test_cases: Vec::new(),
+            filtered_message: String::new(),
+            aliases: HashMap::new(),
             settings: Settings::default(),
             variables: Variables::new(),
+            definitions: DefinedExpressions::new(),
+            cooldowns: HashMap::new(),
+            counters: HashMap::new(),
+        }
+    }
+}
+
+impl Chat {
+    pub fn evaluate(
+        &self,
+        expression: &Expression,
+        variables: &Variables,
+        sandbox: &Sandbox,
+    ) -> EvaluationResult {
+        let expression = self.definitions.expand(expression)?;
+        evaluate_in_sandbox(
+            &expression,
+            variables,
+            &self.settings.evaluation_options(),
+            sandbox,
+        )
+    }
+
+    pub fn config(&self) -> ChatConfig {
+        ChatConfig {
+            filters: self.filters.clone(),
+            settings: self.settings.clone(),
+            variables: self.variables.clone(),
+            definitions: self.definitions.clone(),
+            trusted_ids: self.trusted_ids.clone(),
+            exempt_ids: self.exempt_ids.clone(),
+            exempt_usernames: self.exempt_usernames.clone(),
+            blocked_domains: self.blocked_domains.clone(),
+            blocked_origin_ids: self.blocked_origin_ids.clone(),
+            blocked_origin_usernames: self.blocked_origin_usernames.clone(),
+            filtered_message: self.filtered_message.clone(),
+            aliases: self.aliases.clone(),
         }
     }
+
+    pub fn apply_config(&mut self, config: ChatConfig) {
+        self.filters = config.filters;
+        self.settings = config.settings;
+        self.variables = config.variables;
+        self.definitions = config.definitions;
+        self.trusted_ids = config.trusted_ids;
+        self.exempt_ids = config.exempt_ids;
+        self.exempt_usernames = config.exempt_usernames;
+        self.blocked_domains = config.blocked_domains;
+        self.blocked_origin_ids = config.blocked_origin_ids;
+        self.blocked_origin_usernames = config.blocked_origin_usernames;
+        self.filtered_message = config.filtered_message;
+        self.aliases = config.aliases;
+    }
+
+    pub fn is_blocked_origin(&self, id: Option<i64>, username: Option<&str>) -> bool {
+        id.is_some_and(|id| self.blocked_origin_ids.contains(&id))
+            || username.is_some_and(|username| {
+                self.blocked_origin_usernames
+                    .contains(&username.to_lowercase())
+            })
+    }
+
+    /// Whether `domain` is blocked or a subdomain of a blocked domain.
+    pub fn is_blocked_domain(&self, domain: &str) -> bool {
+        self.blocked_domains.iter().any(|blocked| {
+            domain
+                .strip_suffix(blocked.as_str())
+                .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+        })
+    }
+
+    pub fn is_exempt(&self, user_id: i64, username: Option<&str>) -> bool {
+        self.exempt_ids.contains(&user_id)
+            || username
+                .is_some_and(|username| self.exempt_usernames.contains(&username.to_lowercase()))
+    }
+
+    /// Remembers the current text of the filter `name` before it is changed.
+    pub fn record_filter_version(&mut self, name: &str, author_id: Option<i64>, time: i64) {
+        let text = self.filter(name).map(|filter| filter.text.clone());
+        self.filter_history.push(FilterVersion {
+            name: name.to_string(),
+            text,
+            author_id,
+            time,
+        });
+        if self.filter_history.len() > MAX_FILTER_HISTORY {
+            self.filter_history.remove(0);
+        }
+    }
+
+    pub fn filter(&self, name: &str) -> Option<&Filter> {
+        self.filters.iter().find(|filter| filter.name == name)
+    }
+
+    pub fn filter_mut(&mut self, name: &str) -> Option<&mut Filter> {
+        self.filters.iter_mut().find(|filter| filter.name == name)
+    }
+
+    /// Replaces the filter with the same name in place, keeping its action,
+    /// or appends `filter` so it is evaluated last.
+    pub fn set_filter(&mut self, mut filter: Filter) {
+        match self.filter_mut(&filter.name) {
+            Some(existing) => {
+                filter.action = existing.action;
+                *existing = filter;
+            }
+            None => self.filters.push(filter),
+        }
+    }
+
+    /// Returns whether a filter named `name` existed.
+    pub fn remove_filter(&mut self, name: &str) -> bool {
+        let len = self.filters.len();
+        self.filters.retain(|filter| filter.name != name);
+        self.filters.len() != len
+    }
+
+    pub fn enabled_filters(&self) -> impl Iterator<Item = &Filter> {
+        self.filters.iter().filter(|filter| filter.enabled)
+    }
+
+    /// Disables the filters that expired by `now`, in unix seconds, and
+    /// returns the names of those that were enabled.
+    pub fn expire_filters(&mut self, now: i64) -> Vec<String> {
+        let mut expired = Vec::new();
+        for filter in &mut self.filters {
+            if filter
+                .expires_at
+                .is_some_and(|expires_at| expires_at <= now)
+            {
+                filter.expires_at = None;
+                if filter.enabled {
+                    filter.enabled = false;
+                    expired.push(filter.name.clone());
+                }
+            }
+        }
+
+        expired
+    }
+
+    /// Compiles the enabled filters in order with all definitions expanded,
+    /// for repeated evaluation with `evaluate_compiled`.
+    pub fn compile_filters(&self) -> Vec<(String, Result<CompiledExpression, EvaluationError>)> {
+        self.enabled_filters()
+            .map(|filter| {
+                (
+                    filter.name.clone(),
+                    self.definitions
+                        .expand(&filter.expression)
+                        .map(|expression| CompiledExpression::compile(&expression)),
+                )
+            })
+            .collect()
+    }
+
+    pub fn evaluate_compiled(
+        &self,
+        compiled: &CompiledExpression,
+        variables: &Variables,
+        sandbox: &Sandbox,
+    ) -> EvaluationResult {
+        compiled.evaluate(variables, &self.settings.evaluation_options(), sandbox)
+    }
+
+    /// Commits effects collected while evaluating an expression for a message
+    /// sent at `now` (unix seconds).
+    pub fn apply_effects(&mut self, effects: Vec<Effect>, now: i64) {
+        self.cooldowns.retain(|_, expires| *expires > now);
+        self.counters
+            .retain(|_, counter| now - counter.updated < COUNTER_TTL_SECONDS);
+
+        for effect in effects {
+            match effect {
+                Effect::StartCooldown { key, duration } => {
+                    self.cooldowns.insert(key, now + duration.num_seconds());
+                }
+                Effect::IncrementCounter { name } => {
+                    let counter = self.counters.entry(name).or_insert(Counter {
+                        value: 0,
+                        updated: now,
+                    });
+                    counter.value = counter.value.saturating_add(1);
+                    counter.updated = now;
+                }
+            }
+        }
+
+        while self.counters.len() > MAX_COUNTERS {
+            let oldest = self
+                .counters
+                .iter()
+                .min_by_key(|(_, counter)| counter.updated)
+                .map(|(name, _)| name.clone());
+            match oldest {
+                Some(name) => self.counters.remove(&name),
+                None => break,
+            };
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DailyStats {
+    pub chat_id: i64,
+    pub day: i64,
+    /// Messages seen in the chat, commands excepted.
+    pub messages: i64,
+    /// Messages a filter matched, whatever its action.
+    pub filtered: i64,
+    /// Messages a shadow filter matched, also counted per filter.
+    pub shadowed: i64,
+    pub deleted: i64,
+    pub warned: i64,
+    pub restored: i64,
+    pub filters: HashMap<String, i64>,
+    pub offenders: HashMap<String, i64>,
+}
+
+impl DailyStats {
+    fn apply(&mut self, event: &ModerationEvent) {
+        match &event.kind {
+            ModerationEventKind::Filtered {
+                filter_name,
+                shadow: true,
+                ..
+            } => {
+                self.shadowed += 1;
+                *self.filters.entry(filter_name.clone()).or_insert(0) += 1;
+            }
+            ModerationEventKind::Filtered {
+                filter_name,
+                user_id,
+                action,
+                ..
+            } => {
+                self.filtered += 1;
+                if action.deletes() {
+                    self.deleted += 1;
+                } else if *action == Action::Warn {
+                    self.warned += 1;
+                }
+                *self.filters.entry(filter_name.clone()).or_insert(0) += 1;
+                if let Some(user_id) = user_id {
+                    *self.offenders.entry(user_id.to_string()).or_insert(0) += 1;
+                }
+            }
+            ModerationEventKind::Restored => self.restored += 1,
+        }
+    }
+}
+
+/// First message of a user in a chat.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SeenUser {
+    pub chat_id: i64,
+    pub user_id: i64,
+    /// Unix seconds.
+    pub first_seen: i64,
+}
+
+/// Photo, sticker or file deleted whenever it is sent to a chat.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockedMedia {
+    pub chat_id: i64,
+    /// Same for every copy of the file, unlike its file id.
+    pub file_unique_id: String,
+    pub author_id: Option<i64>,
+    /// Unix seconds.
+    pub time: i64,
+}
+
+/// Moderation decision, kept in an append-only log per chat that
+/// `DailyStats` can be recomputed from.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ModerationEvent {
+    /// Chosen when the event is first written, so a write replayed after it
+    /// was logged isn't logged twice.
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub chat_id: i64,
+    /// Unix seconds.
+    pub time: i64,
+    pub kind: ModerationEventKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ModerationEventKind {
+    Filtered {
+        filter_name: String,
+        user_id: Option<i64>,
+        /// Events logged before filters had actions were all deletes.
+        #[serde(default)]
+        action: Action,
+        /// Matched by a shadow filter, which didn't act on the message.
+        #[serde(default)]
+        shadow: bool,
+    },
+    Restored,
+}
+
+impl Display for ModerationEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = DateTime::from_timestamp(self.time, 0).unwrap_or_default();
+        write!(f, "{}: ", time.to_rfc3339_opts(SecondsFormat::Secs, true))?;
+        match &self.kind {
+            ModerationEventKind::Filtered {
+                filter_name,
+                user_id,
+                action,
+                shadow,
+            } => {
+                write!(f, "filtered message")?;
+                if let Some(user_id) = user_id {
+                    write!(f, " of {user_id}")?;
+                }
+                if *shadow {
+                    write!(f, " by shadow filter {filter_name} (would {action})")
+                } else {
+                    write!(f, " by {filter_name} ({action})")
+                }
+            }
+            ModerationEventKind::Restored => write!(f, "restored message"),
+        }
+    }
+}
+
+/// Chats a user manages together, so their stats can be rolled up.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Project {
+    pub owner_id: i64,
+    pub name: String,
+    pub chat_ids: Vec<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct DigestState {
+    chat_id: i64,
+    last_sent: i64,
+}
+
+/// Write that failed while the database was unavailable, kept in memory to be
+/// replayed once it is back.
+#[derive(Clone, Debug)]
+pub enum PendingWrite {
+    Chat(Box<Chat>),
+    Filtered {
+        chat_id: i64,
+        time: i64,
+        filter_name: String,
+        user_id: Option<i64>,
+        action: Action,
+        shadow: bool,
+        event_id: ObjectId,
+    },
+    /// `count` messages were seen in the chat on the day of `time`, queued
+    /// messages of a day are counted in a single write.
+    Messages {
+        chat_id: i64,
+        time: i64,
+        count: i64,
+    },
+    Restored {
+        chat_id: i64,
+        time: i64,
+        event_id: ObjectId,
+    },
 }
 
 pub struct Db {
     chats: Collection<Chat>,
+    stats: Collection<DailyStats>,
+    digests: Collection<DigestState>,
+    projects: Collection<Project>,
+    events: Collection<ModerationEvent>,
+    seen_users: Collection<SeenUser>,
+    blocked_media: Collection<BlockedMedia>,
+    pending_writes: VecDeque<PendingWrite>,
 }
 
 impl Db {
@@ -80,27 +1074,261 @@ impl Db {
             .build();
         chats.create_index(index_model).await?;
 
+        let stats: Collection<DailyStats> = database.collection("stats");
+        let index_keys = doc! { "chat_id": 1, "day": 1 };
+        let index_options = IndexOptions::builder()
+            .unique(true)
+            .name(Some("chat_id_day_unique_ascending".to_string()))
+            .build();
+        let index_model = IndexModel::builder()
+            .keys(index_keys)
+            .options(index_options)
+            .build();
+        stats.create_index(index_model).await?;
+
+        let digests: Collection<DigestState> = database.collection("digests");
+        let index_keys = doc! { "chat_id": 1 };
+        let index_options = IndexOptions::builder()
+            .unique(true)
+            .name(Some("chat_id_unique_ascending".to_string()))
+            .build();
+        let index_model = IndexModel::builder()
+            .keys(index_keys)
+            .options(index_options)
+            .build();
+        digests.create_index(index_model).await?;
+
+        let projects: Collection<Project> = database.collection("projects");
+        let index_keys = doc! { "owner_id": 1, "name": 1 };
+        let index_options = IndexOptions::builder()
+            .unique(true)
+            .name(Some("owner_id_name_unique_ascending".to_string()))
+            .build();
+        let index_model = IndexModel::builder()
+            .keys(index_keys)
+            .options(index_options)
+            .build();
+        projects.create_index(index_model).await?;
+
+        let events: Collection<ModerationEvent> = database.collection("moderation_events");
+        let index_keys = doc! { "chat_id": 1, "time": 1 };
+        let index_options = IndexOptions::builder()
+            .name(Some("chat_id_time_ascending".to_string()))
+            .build();
+        let index_model = IndexModel::builder()
+            .keys(index_keys)
+            .options(index_options)
+            .build();
+        events.create_index(index_model).await?;
+
+        let seen_users: Collection<SeenUser> = database.collection("seen_users");
+        let index_keys = doc! { "chat_id": 1, "user_id": 1 };
+        let index_options = IndexOptions::builder()
+            .unique(true)
+            .name(Some("chat_id_user_id_unique_ascending".to_string()))
+            .build();
+        let index_model = IndexModel::builder()
+            .keys(index_keys)
+            .options(index_options)
+            .build();
+        seen_users.create_index(index_model).await?;
+
+        let blocked_media: Collection<BlockedMedia> = database.collection("blocked_media");
+        let index_keys = doc! { "chat_id": 1, "file_unique_id": 1 };
+        let index_options = IndexOptions::builder()
+            .unique(true)
+            .name(Some("chat_id_file_unique_id_unique_ascending".to_string()))
+            .build();
+        let index_model = IndexModel::builder()
+            .keys(index_keys)
+            .options(index_options)
+            .build();
+        blocked_media.create_index(index_model).await?;
+
         if let Err(e) = migrate(&database).await {
             return Err(Box::new(GenericError::from(format!(
                 "database migration error: {e}"
             ))));
         }
 
-        Ok(Db { chats })
+        Ok(Db {
+            chats,
+            stats,
+            digests,
+            projects,
+            events,
+            seen_users,
+            blocked_media,
+            pending_writes: VecDeque::new(),
+        })
+    }
+
+    /// Performs `write`, or queues it for `replay_pending_writes` if the
+    /// database can't be reached. While writes are pending the database is
+    /// considered unavailable and `write` is queued right away, so writes are
+    /// replayed in order and moderation does not wait for timeouts. Writes
+    /// the database refuses are not queued, they would fail again.
+    pub async fn write_or_queue(
+        &mut self,
+        write: PendingWrite,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if !self.pending_writes.is_empty() {
+            self.queue_write(write);
+            return Err(Box::new(GenericError::from(
+                "database unavailable".to_string(),
+            )));
+        }
+
+        let outcome = self.write(&write).await;
+        if outcome
+            .as_ref()
+            .is_err_and(|e| is_connectivity_error(e.as_ref()))
+        {
+            self.queue_write(write);
+        }
+
+        outcome
+    }
+
+    /// Retries queued writes in order, stopping at the first failure to reach
+    /// the database. Writes the database refuses are logged and dropped.
+    /// Returns the number of writes that were applied.
+    pub async fn replay_pending_writes(&mut self) -> Result<usize, Box<dyn Error + Send + Sync>> {
+        let mut replayed = 0;
+        while let Some(write) = self.pending_writes.front() {
+            match self.write(write).await {
+                Ok(()) => replayed += 1,
+                Err(e) if is_connectivity_error(e.as_ref()) => return Err(e),
+                Err(e) => log::error!("Dropping pending write {write:?}: {e}"),
+            }
+            self.pending_writes.pop_front();
+        }
+
+        Ok(replayed)
+    }
+
+    pub fn pending_write_count(&self) -> usize {
+        self.pending_writes.len()
+    }
+
+    fn queue_write(&mut self, write: PendingWrite) {
+        match &write {
+            PendingWrite::Chat(chat) => {
+                // Only the latest state of a chat matters.
+                self.pending_writes.retain(
+                    |pending| !matches!(pending, PendingWrite::Chat(c) if c.chat_id == chat.chat_id),
+                );
+            }
+            PendingWrite::Messages {
+                chat_id,
+                time,
+                count,
+            } => {
+                let day = time.div_euclid(SECONDS_PER_DAY);
+                let queued = self
+                    .pending_writes
+                    .iter_mut()
+                    .find_map(|pending| match pending {
+                        PendingWrite::Messages {
+                            chat_id: queued_chat_id,
+                            time: queued_time,
+                            count,
+                        } if queued_chat_id == chat_id
+                            && queued_time.div_euclid(SECONDS_PER_DAY) == day =>
+                        {
+                            Some(count)
+                        }
+                        _ => None,
+                    });
+                if let Some(queued) = queued {
+                    *queued += count;
+                    return;
+                }
+            }
+            _ => {}
+        }
+        if self.pending_writes.len() >= MAX_PENDING_WRITES {
+            // Chats are queued once each and losing one loses its setup, the
+            // oldest stats write goes instead.
+            let oldest = self
+                .pending_writes
+                .iter()
+                .position(|pending| !matches!(pending, PendingWrite::Chat(_)));
+            if let Some(index) = oldest {
+                log::warn!("Too many pending writes, dropping the oldest stats write");
+                self.pending_writes.remove(index);
+            }
+        }
+        self.pending_writes.push_back(write);
+    }
+
+    async fn write(&self, write: &PendingWrite) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match write {
+            PendingWrite::Chat(chat) => self.insert_chat(chat).await,
+            PendingWrite::Filtered {
+                chat_id,
+                time,
+                filter_name,
+                user_id,
+                action,
+                shadow,
+                event_id,
+            } => {
+                let event = ModerationEvent {
+                    id: *event_id,
+                    chat_id: *chat_id,
+                    time: *time,
+                    kind: ModerationEventKind::Filtered {
+                        filter_name: filter_name.clone(),
+                        user_id: *user_id,
+                        action: *action,
+                        shadow: *shadow,
+                    },
+                };
+                self.record_event(&event).await
+            }
+            PendingWrite::Messages {
+                chat_id,
+                time,
+                count,
+            } => self.record_messages(*chat_id, *time, *count).await,
+            PendingWrite::Restored {
+                chat_id,
+                time,
+                event_id,
+            } => {
+                let event = ModerationEvent {
+                    id: *event_id,
+                    chat_id: *chat_id,
+                    time: *time,
+                    kind: ModerationEventKind::Restored,
+                };
+                self.record_event(&event).await
+            }
+        }
     }
 
     pub async fn find_chat_by_id(&self, chat_id: i64) -> Result<Chat, Box<dyn Error>> {
         match self.chats.find_one(doc! { "chat_id": chat_id }).await? {
             Some(chat) => Ok(chat),
             None => {
-                let mut chat = Chat::default();
-                chat.chat_id = chat_id;
+                let chat = Chat {
+                    chat_id,
+                    ..Default::default()
+                };
                 self.chats.insert_one(&chat).await?;
                 Ok(chat)
             }
         }
     }
 
+    pub async fn find_chat(
+        &self,
+        chat_id: i64,
+    ) -> Result<Option<Chat>, Box<dyn Error + Send + Sync>> {
+        Ok(self.chats.find_one(doc! { "chat_id": chat_id }).await?)
+    }
+
     pub async fn insert_chat(&self, chat: &Chat) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.chats
             .replace_one(doc! { "chat_id": chat.chat_id }, chat)
@@ -109,6 +1337,328 @@ impl Db {
 
         Ok(())
     }
+
+    pub async fn find_chats_by_admin(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<Chat>, Box<dyn Error + Send + Sync>> {
+        let cursor = self.chats.find(doc! { "admin_ids": user_id }).await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    pub async fn find_chats_with_public_stats(
+        &self,
+    ) -> Result<Vec<Chat>, Box<dyn Error + Send + Sync>> {
+        let cursor = self
+            .chats
+            .find(doc! { "settings.public_stats": true })
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    pub async fn find_chats_with_digest(&self) -> Result<Vec<Chat>, Box<dyn Error + Send + Sync>> {
+        let cursor = self
+            .chats
+            .find(doc! { "settings.digest_interval_days": { "$gt": 0 } })
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Appends `event` to the moderation log and counts it in the stats of
+    /// its day. Replaying an event that was logged before the stats update
+    /// failed only updates the stats.
+    pub async fn record_event(
+        &self,
+        event: &ModerationEvent,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Err(e) = self.events.insert_one(event).await {
+            if !is_duplicate_key_error(&e) {
+                return Err(e.into());
+            }
+        }
+
+        let day = event.time.div_euclid(SECONDS_PER_DAY);
+        let increments = match &event.kind {
+            ModerationEventKind::Filtered {
+                filter_name,
+                shadow: true,
+                ..
+            } => doc! {
+                "shadowed": 1,
+                format!("filters.{filter_name}"): 1,
+            },
+            ModerationEventKind::Filtered {
+                filter_name,
+                user_id,
+                action,
+                shadow: false,
+            } => {
+                let mut increments = doc! {
+                    "filtered": 1,
+                    "deleted": i64::from(action.deletes()),
+                    "warned": i64::from(*action == Action::Warn),
+                    "restored": 0,
+                    format!("filters.{filter_name}"): 1,
+                };
+                if let Some(user_id) = user_id {
+                    increments.insert(format!("offenders.{user_id}"), 1);
+                }
+                increments
+            }
+            ModerationEventKind::Restored => doc! { "filtered": 0, "restored": 1 },
+        };
+
+        self.stats
+            .update_one(
+                doc! { "chat_id": event.chat_id, "day": day },
+                doc! { "$inc": increments },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Counts `count` messages seen in the chat in the stats of the day of
+    /// `time`, in unix seconds. Messages aren't logged, so they survive `recompute_stats`
+    /// only through the stats themselves.
+    pub async fn record_messages(
+        &self,
+        chat_id: i64,
+        time: i64,
+        count: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let day = time.div_euclid(SECONDS_PER_DAY);
+        self.stats
+            .update_one(
+                doc! { "chat_id": chat_id, "day": day },
+                doc! { "$inc": { "messages": count } },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Remembers that the user wrote in the chat, `time` is in unix seconds.
+    /// Returns whether it was their first message there.
+    pub async fn mark_user_seen(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        time: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let result = self
+            .seen_users
+            .update_one(
+                doc! { "chat_id": chat_id, "user_id": user_id },
+                doc! { "$setOnInsert": { "first_seen": time } },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(result.upserted_id.is_some())
+    }
+
+    pub async fn count_blocked_media(
+        &self,
+        chat_id: i64,
+    ) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .blocked_media
+            .count_documents(doc! { "chat_id": chat_id })
+            .await?)
+    }
+
+    pub async fn insert_blocked_media(
+        &self,
+        media: &BlockedMedia,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.blocked_media
+            .replace_one(
+                doc! { "chat_id": media.chat_id, "file_unique_id": &media.file_unique_id },
+                media,
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns whether the media was blocked.
+    pub async fn remove_blocked_media(
+        &self,
+        chat_id: i64,
+        file_unique_id: &str,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let result = self
+            .blocked_media
+            .delete_one(doc! { "chat_id": chat_id, "file_unique_id": file_unique_id })
+            .await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    /// Blocked media of the chat among `file_unique_ids`, if any.
+    pub async fn find_blocked_media(
+        &self,
+        chat_id: i64,
+        file_unique_ids: &[String],
+    ) -> Result<Option<BlockedMedia>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .blocked_media
+            .find_one(doc! { "chat_id": chat_id, "file_unique_id": { "$in": file_unique_ids } })
+            .await?)
+    }
+
+    pub async fn find_stats_since(
+        &self,
+        chat_id: i64,
+        first_day: i64,
+    ) -> Result<Vec<DailyStats>, Box<dyn Error + Send + Sync>> {
+        let cursor = self
+            .stats
+            .find(doc! { "chat_id": chat_id, "day": { "$gte": first_day } })
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    /// Latest `limit` entries of the moderation log of a chat, oldest first.
+    pub async fn find_latest_events(
+        &self,
+        chat_id: i64,
+        limit: i64,
+    ) -> Result<Vec<ModerationEvent>, Box<dyn Error + Send + Sync>> {
+        let cursor = self
+            .events
+            .find(doc! { "chat_id": chat_id })
+            .sort(doc! { "time": -1 })
+            .limit(limit)
+            .await?;
+
+        let mut events: Vec<ModerationEvent> = cursor.try_collect().await?;
+        events.reverse();
+        Ok(events)
+    }
+
+    /// Replaces the stats of a chat with ones recomputed from its moderation
+    /// log, returns the number of events replayed. Days before the first
+    /// logged event are kept as they are.
+    pub async fn recompute_stats(&self, chat_id: i64) -> Result<u64, Box<dyn Error + Send + Sync>> {
+        let mut cursor = self.events.find(doc! { "chat_id": chat_id }).await?;
+
+        let mut days: HashMap<i64, DailyStats> = HashMap::new();
+        let mut replayed = 0;
+        while let Some(event) = cursor.try_next().await? {
+            let day = event.time.div_euclid(SECONDS_PER_DAY);
+            days.entry(day)
+                .or_insert_with(|| DailyStats {
+                    chat_id,
+                    day,
+                    ..Default::default()
+                })
+                .apply(&event);
+            replayed += 1;
+        }
+
+        let Some(first_day) = days.keys().min().copied() else {
+            return Ok(0);
+        };
+
+        for stats in self.find_stats_since(chat_id, first_day).await? {
+            days.entry(stats.day)
+                .or_insert_with(|| DailyStats {
+                    chat_id,
+                    day: stats.day,
+                    ..Default::default()
+                })
+                .messages = stats.messages;
+        }
+
+        // Every stored day since the first event is in `days`, so they are
+        // replaced one by one rather than deleted and inserted again. The
+        // stats of a day are never missing and a failure leaves each day
+        // either recomputed or as it was, transactions would need a replica
+        // set.
+        for stats in days.into_values() {
+            self.stats
+                .replace_one(doc! { "chat_id": chat_id, "day": stats.day }, &stats)
+                .upsert(true)
+                .await?;
+        }
+
+        Ok(replayed)
+    }
+
+    pub async fn find_stats_of_chats_since(
+        &self,
+        chat_ids: &[i64],
+        first_day: i64,
+    ) -> Result<Vec<DailyStats>, Box<dyn Error + Send + Sync>> {
+        let cursor = self
+            .stats
+            .find(doc! { "chat_id": { "$in": chat_ids }, "day": { "$gte": first_day } })
+            .await?;
+
+        Ok(cursor.try_collect().await?)
+    }
+
+    pub async fn find_project(
+        &self,
+        owner_id: i64,
+        name: &str,
+    ) -> Result<Option<Project>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .projects
+            .find_one(doc! { "owner_id": owner_id, "name": name })
+            .await?)
+    }
+
+    pub async fn insert_project(
+        &self,
+        project: &Project,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.projects
+            .replace_one(
+                doc! { "owner_id": project.owner_id, "name": &project.name },
+                project,
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn find_last_digest(
+        &self,
+        chat_id: i64,
+    ) -> Result<Option<i64>, Box<dyn Error + Send + Sync>> {
+        Ok(self
+            .digests
+            .find_one(doc! { "chat_id": chat_id })
+            .await?
+            .map(|state| state.last_sent))
+    }
+
+    pub async fn insert_last_digest(
+        &self,
+        chat_id: i64,
+        last_sent: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.digests
+            .replace_one(
+                doc! { "chat_id": chat_id },
+                DigestState { chat_id, last_sent },
+            )
+            .upsert(true)
+            .await?;
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -133,9 +1683,10 @@ async fn migrate(db: &Database) -> Result<(), Box<dyn Error + Send + Sync>> {
     migrations.create_index(index_model).await?;
 
     for mut migration_action in migrations::get_vec() {
-        if let None = migrations
+        if migrations
             .find_one(doc! { "name": migration_action.name.to_string() })
             .await?
+            .is_none()
         {
             log::info!("Applying migration {}...", migration_action.name);
             migration_action.run(db.clone()).await?;
@@ -0,0 +1,261 @@
+use crate::error::GenericError;
+use baldguard_language::{
+    definitions::DefinedExpressions,
+    tree::{Expression, Literal, Operator},
+};
+use mongodb::bson::{doc, Bson, Document};
+
+/// Compiles the part of the language that has a MongoDB counterpart into a
+/// query document. Definitions are expanded first, the identifiers left are
+/// used as field names.
+///
+/// Supported are the logical operators except xor, comparisons between an
+/// identifier and a literal, chains of them like `0 < x < 10`, `matches` and
+/// `matches any` with literal patterns, bool identifiers and bool literals.
+///
+/// The query doesn't always agree with evaluating the expression:
+/// - patterns are matched by `$regex`, which has PCRE syntax instead of the
+///   one of the `regex` crate, so a pattern with lookarounds or backreferences
+///   works in the query and is invalid in the language;
+/// - a document without the field matches `$ne`, where the evaluation fails
+///   on the undeclared identifier, or compares empty in lenient mode;
+/// - comparisons only match fields of the type of the literal, where the
+///   evaluation fails on a type mismatch.
+pub fn compile_query(
+    expression: &Expression,
+    definitions: &DefinedExpressions,
+) -> Result<Document, GenericError> {
+    let expression = definitions
+        .expand(expression)
+        .map_err(|e| GenericError::from(e.to_string()))?;
+
+    compile_expanded(&expression)
+}
+
+fn compile_expanded(expression: &Expression) -> Result<Document, GenericError> {
+    match expression {
+        Expression::Identifier(field) => Ok(doc! { field: true }),
+        Expression::Literal(Literal::Bool(true)) => Ok(doc! {}),
+        Expression::Literal(Literal::Bool(false)) => Ok(doc! { "$expr": false }),
+        Expression::UnaryOp {
+            expression,
+            operator: Operator::Not,
+        } => Ok(doc! { "$nor": [compile_expanded(expression)?] }),
+        Expression::BinaryOp {
+            left,
+            operator,
+            right,
+        } => match operator {
+            Operator::And => {
+                Ok(doc! { "$and": [compile_expanded(left)?, compile_expanded(right)?] })
+            }
+            Operator::Or => Ok(doc! { "$or": [compile_expanded(left)?, compile_expanded(right)?] }),
+            Operator::Nor => {
+                Ok(doc! { "$nor": [compile_expanded(left)?, compile_expanded(right)?] })
+            }
+            Operator::Nand => Ok(doc! {
+                "$nor": [{ "$and": [compile_expanded(left)?, compile_expanded(right)?] }]
+            }),
+            Operator::Matches => match (left.as_ref(), right.as_ref()) {
+                (Expression::Identifier(field), Expression::Literal(Literal::Str(pattern))) => {
+                    Ok(doc! { field: { "$regex": pattern } })
+                }
+                _ => Err(unsupported(expression)),
+            },
+            Operator::MatchesAny => match (left.as_ref(), right.as_ref()) {
+                (Expression::Identifier(field), Expression::List(patterns)) => {
+                    let patterns = patterns
+                        .iter()
+                        .map(|pattern| match pattern {
+                            Expression::Literal(Literal::Str(pattern)) => {
+                                Ok(Bson::Document(doc! { field: { "$regex": pattern } }))
+                            }
+                            _ => Err(unsupported(expression)),
+                        })
+                        .collect::<Result<Vec<Bson>, GenericError>>()?;
+                    Ok(doc! { "$or": patterns })
+                }
+                _ => Err(unsupported(expression)),
+            },
+            _ => compile_binary_comparison(left, operator, right)
+                .ok_or_else(|| unsupported(expression)),
+        },
+        Expression::Comparison { first, comparisons } => {
+            let mut left = first.as_ref();
+            let mut compiled = Vec::new();
+            for (operator, right) in comparisons {
+                let comparison = compile_binary_comparison(left, operator, right)
+                    .ok_or_else(|| unsupported(expression))?;
+                compiled.push(Bson::Document(comparison));
+                left = right;
+            }
+            Ok(doc! { "$and": compiled })
+        }
+        _ => Err(unsupported(expression)),
+    }
+}
+
+/// Comparison between an identifier and a literal, in either order.
+fn compile_binary_comparison(
+    left: &Expression,
+    operator: &Operator,
+    right: &Expression,
+) -> Option<Document> {
+    match (left, right) {
+        (Expression::Identifier(field), Expression::Literal(literal)) => {
+            compile_comparison(field, operator, literal, false)
+        }
+        (Expression::Literal(literal), Expression::Identifier(field)) => {
+            compile_comparison(field, operator, literal, true)
+        }
+        _ => None,
+    }
+}
+
+fn compile_comparison(
+    field: &str,
+    operator: &Operator,
+    literal: &Literal,
+    flipped: bool,
+) -> Option<Document> {
+    let operator = match (operator, flipped) {
+        (Operator::Equal, _) => "$eq",
+        (Operator::NotEqual, _) => "$ne",
+        (Operator::Less, false) | (Operator::Greater, true) => "$lt",
+        (Operator::LessEqual, false) | (Operator::GreaterEqual, true) => "$lte",
+        (Operator::Greater, false) | (Operator::Less, true) => "$gt",
+        (Operator::GreaterEqual, false) | (Operator::LessEqual, true) => "$gte",
+        _ => return None,
+    };
+
+    let value = match literal {
+        Literal::Int(value) => Bson::Int64(*value),
+        Literal::Str(value) => Bson::String(value.clone()),
+        Literal::Bool(value) => Bson::Boolean(*value),
+        Literal::Empty => Bson::Null,
+    };
+
+    Some(doc! { field: { operator: value } })
+}
+
+fn unsupported(expression: &Expression) -> GenericError {
+    GenericError::from(format!(
+        "{} has no query counterpart",
+        expression.to_canonical_string()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use baldguard_language::{definitions::Definition, grammar::ExpressionParser};
+
+    fn parse(text: &str) -> Expression {
+        *ExpressionParser::new().parse(text).unwrap()
+    }
+
+    fn query(text: &str) -> Document {
+        compile_query(&parse(text), &DefinedExpressions::new()).unwrap()
+    }
+
+    #[test]
+    fn compiles_logical_operators() {
+        assert_eq!(query("a"), doc! { "a": true });
+        assert_eq!(query("true"), doc! {});
+        assert_eq!(query("false"), doc! { "$expr": false });
+        assert_eq!(query("not a"), doc! { "$nor": [{ "a": true }] });
+        assert_eq!(
+            query("a and b"),
+            doc! { "$and": [{ "a": true }, { "b": true }] }
+        );
+        assert_eq!(
+            query("a or b"),
+            doc! { "$or": [{ "a": true }, { "b": true }] }
+        );
+        assert_eq!(
+            query("a nor b"),
+            doc! { "$nor": [{ "a": true }, { "b": true }] }
+        );
+        assert_eq!(
+            query("a nand b"),
+            doc! { "$nor": [{ "$and": [{ "a": true }, { "b": true }] }] }
+        );
+    }
+
+    #[test]
+    fn compiles_comparisons() {
+        assert_eq!(query("n = 1"), doc! { "n": { "$eq": 1_i64 } });
+        assert_eq!(query("s != \"x\""), doc! { "s": { "$ne": "x" } });
+        assert_eq!(query("n < 1"), doc! { "n": { "$lt": 1_i64 } });
+        assert_eq!(query("n <= 1"), doc! { "n": { "$lte": 1_i64 } });
+        assert_eq!(query("n > 1"), doc! { "n": { "$gt": 1_i64 } });
+        assert_eq!(query("n >= 1"), doc! { "n": { "$gte": 1_i64 } });
+        assert_eq!(query("b = true"), doc! { "b": { "$eq": true } });
+        assert_eq!(query("e = empty"), doc! { "e": { "$eq": Bson::Null } });
+    }
+
+    #[test]
+    fn flips_comparisons_with_the_literal_first() {
+        assert_eq!(query("1 < n"), doc! { "n": { "$gt": 1_i64 } });
+        assert_eq!(query("1 <= n"), doc! { "n": { "$gte": 1_i64 } });
+        assert_eq!(query("1 > n"), doc! { "n": { "$lt": 1_i64 } });
+        assert_eq!(query("1 >= n"), doc! { "n": { "$lte": 1_i64 } });
+        assert_eq!(query("1 = n"), doc! { "n": { "$eq": 1_i64 } });
+    }
+
+    #[test]
+    fn compiles_comparison_chains() {
+        assert_eq!(
+            query("0 < n <= 10"),
+            doc! { "$and": [{ "n": { "$gt": 0_i64 } }, { "n": { "$lte": 10_i64 } }] }
+        );
+    }
+
+    #[test]
+    fn compiles_matches() {
+        assert_eq!(
+            query("text matches \"^a\""),
+            doc! { "text": { "$regex": "^a" } }
+        );
+        assert_eq!(
+            query("text matches any [\"a\", \"b\"]"),
+            doc! { "$or": [{ "text": { "$regex": "a" } }, { "text": { "$regex": "b" } }] }
+        );
+    }
+
+    #[test]
+    fn expands_definitions() {
+        let mut definitions = DefinedExpressions::new();
+        definitions
+            .put(
+                "big".to_string(),
+                Definition {
+                    text: "n > 100".to_string(),
+                    expression: parse("n > 100"),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(
+            compile_query(&parse("big and a"), &definitions).unwrap(),
+            doc! { "$and": [{ "n": { "$gt": 100_i64 } }, { "a": true }] }
+        );
+    }
+
+    #[test]
+    fn rejects_expressions_without_counterpart() {
+        for text in [
+            "a xor b",
+            "a < b",
+            "n + 1 > 2",
+            "text matches pattern",
+            "text matches any [pattern]",
+            "word_count(text) > 1",
+        ] {
+            assert!(
+                compile_query(&parse(text), &DefinedExpressions::new()).is_err(),
+                "{text}"
+            );
+        }
+    }
+}
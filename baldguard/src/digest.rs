@@ -0,0 +1,103 @@
+use super::database::{Chat, DailyStats, Db, MAX_DIGEST_INTERVAL_DAYS, SECONDS_PER_DAY};
+use std::{collections::HashMap, error::Error, fmt::Display};
+
+const TOP_ENTRIES: usize = 5;
+
+pub struct Digest {
+    pub days: i64,
+    pub filtered: i64,
+    pub restored: i64,
+    pub top_filters: Vec<(String, i64)>,
+    pub top_offenders: Vec<(String, i64)>,
+}
+
+fn top_entries(counts: HashMap<String, i64>) -> Vec<(String, i64)> {
+    let mut entries: Vec<(String, i64)> = counts.into_iter().collect();
+    entries.sort_by(|(l_name, l_count), (r_name, r_count)| {
+        r_count.cmp(l_count).then_with(|| l_name.cmp(r_name))
+    });
+    entries.truncate(TOP_ENTRIES);
+    entries
+}
+
+impl Digest {
+    pub fn from_stats(days: i64, stats: &[DailyStats]) -> Self {
+        let mut filtered = 0;
+        let mut restored = 0;
+        let mut filters = HashMap::new();
+        let mut offenders = HashMap::new();
+
+        for day in stats {
+            filtered += day.filtered;
+            restored += day.restored;
+            for (name, count) in &day.filters {
+                *filters.entry(name.clone()).or_insert(0) += count;
+            }
+            for (user_id, count) in &day.offenders {
+                *offenders.entry(user_id.clone()).or_insert(0) += count;
+            }
+        }
+
+        Digest {
+            days,
+            filtered,
+            restored,
+            top_filters: top_entries(filters),
+            top_offenders: top_entries(offenders),
+        }
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "moderation digest for the last {} day(s)", self.days)?;
+        writeln!(f, "messages filtered: {}", self.filtered)?;
+        write!(f, "false positives restored: {}", self.restored)?;
+
+        if !self.top_filters.is_empty() {
+            write!(f, "\ntop filters:")?;
+            for (name, count) in &self.top_filters {
+                write!(f, "\n- {name}: {count}")?;
+            }
+        }
+
+        if !self.top_offenders.is_empty() {
+            write!(f, "\ntop offenders:")?;
+            for (user_id, count) in &self.top_offenders {
+                write!(f, "\n- {user_id}: {count}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects digests for every chat whose digest interval has elapsed at `now`
+/// (unix seconds), or that never got one, and marks them as sent. Each digest
+/// comes with the chat it is about.
+pub async fn collect_due_digests(
+    db: &Db,
+    now: i64,
+) -> Result<Vec<(Chat, Digest)>, Box<dyn Error + Send + Sync>> {
+    let mut result = Vec::new();
+
+    for chat in db.find_chats_with_digest().await? {
+        // chats may have stored a longer interval before it was bounded
+        let days = chat
+            .settings
+            .digest_interval_days
+            .min(MAX_DIGEST_INTERVAL_DAYS);
+        if let Some(last_sent) = db.find_last_digest(chat.chat_id).await? {
+            if now - last_sent < days * SECONDS_PER_DAY {
+                continue;
+            }
+        }
+
+        let first_day = now.div_euclid(SECONDS_PER_DAY) - days + 1;
+        let stats = db.find_stats_since(chat.chat_id, first_day).await?;
+        db.insert_last_digest(chat.chat_id, now).await?;
+        result.push((chat, Digest::from_stats(days, &stats)));
+    }
+
+    Ok(result)
+}
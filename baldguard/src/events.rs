@@ -0,0 +1,178 @@
+use super::database::Chat;
+use baldguard_language::{environment::Environment, evaluation::ValueError};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// Sessions are dropped after ten minutes of inactivity, so longer windows
+/// could not be counted reliably.
+const MAX_WINDOW_SECONDS: i64 = 600;
+
+pub const MESSAGES: &str = "messages";
+pub const FILTERED: &str = "filtered";
+pub const JOINS: &str = "joins";
+
+const FROM_USER_SUFFIX: &str = "_from_user";
+
+/// Number of recent message texts compared against.
+const RECENT_TEXTS: usize = 50;
+
+pub fn user_event(event: &str, user_id: i64) -> String {
+    format!("{event}{FROM_USER_SUFFIX}:{user_id}")
+}
+
+#[derive(Debug, Default)]
+pub struct EventWindows {
+    events: HashMap<String, VecDeque<DateTime<Utc>>>,
+}
+
+impl EventWindows {
+    pub fn new() -> Self {
+        EventWindows {
+            events: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, event: String, at: DateTime<Utc>) {
+        self.events.entry(event).or_default().push_back(at);
+        self.prune(at);
+    }
+
+    pub fn count(&self, event: &str, since: DateTime<Utc>) -> i64 {
+        match self.events.get(event) {
+            Some(times) => times.iter().rev().take_while(|at| **at > since).count() as i64,
+            None => 0,
+        }
+    }
+
+    fn prune(&mut self, now: DateTime<Utc>) {
+        let oldest = now - TimeDelta::seconds(MAX_WINDOW_SECONDS);
+        for times in self.events.values_mut() {
+            while times.front().is_some_and(|at| *at <= oldest) {
+                times.pop_front();
+            }
+        }
+        self.events.retain(|_, times| !times.is_empty());
+    }
+}
+
+/// Latest message texts of a chat, kept as sets of character trigrams.
+#[derive(Debug, Default)]
+pub struct RecentTexts {
+    texts: VecDeque<HashSet<String>>,
+}
+
+impl RecentTexts {
+    pub fn new() -> Self {
+        RecentTexts {
+            texts: VecDeque::with_capacity(RECENT_TEXTS),
+        }
+    }
+
+    /// Highest similarity of `text` to any recent text in percent, by the
+    /// Jaccard index of their trigrams. `text` is remembered afterwards.
+    pub fn similarity_and_record(&mut self, text: &str) -> i64 {
+        let trigrams = trigrams(text);
+        let similarity = self
+            .texts
+            .iter()
+            .map(|recent| {
+                let shared = trigrams.intersection(recent).count();
+                let total = trigrams.len() + recent.len() - shared;
+                (shared * 100).checked_div(total).unwrap_or(100) as i64
+            })
+            .max()
+            .unwrap_or(0);
+
+        if self.texts.len() == RECENT_TEXTS {
+            self.texts.pop_front();
+        }
+        self.texts.push_back(trigrams);
+
+        similarity
+    }
+}
+
+/// Trigrams of the lowercase text with whitespace collapsed, texts shorter than
+/// three characters are a single "trigram".
+fn trigrams(text: &str) -> HashSet<String> {
+    let chars: Vec<char> = text
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .to_lowercase()
+        .chars()
+        .collect();
+    if chars.len() < 3 {
+        return HashSet::from([chars.into_iter().collect()]);
+    }
+
+    chars
+        .windows(3)
+        .map(|window| window.iter().collect())
+        .collect()
+}
+
+/// Resolves event names used in `count()` against the windows of a chat, as
+/// seen by the message being filtered, and cooldowns and counters against the
+/// chat itself.
+pub struct MessageEnvironment<'a> {
+    windows: &'a EventWindows,
+    chat: &'a Chat,
+    user_id: Option<i64>,
+    now: DateTime<Utc>,
+}
+
+impl<'a> MessageEnvironment<'a> {
+    pub fn new(
+        windows: &'a EventWindows,
+        chat: &'a Chat,
+        user_id: Option<i64>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        MessageEnvironment {
+            windows,
+            chat,
+            user_id,
+            now,
+        }
+    }
+}
+
+impl Environment for MessageEnvironment<'_> {
+    fn count(&self, event: &str, window: TimeDelta) -> Result<i64, ValueError> {
+        if window <= TimeDelta::zero() || window.num_seconds() > MAX_WINDOW_SECONDS {
+            return Err(ValueError::new_other(format!(
+                "count window must be between 1 and {MAX_WINDOW_SECONDS} seconds"
+            )));
+        }
+
+        let key = match event {
+            MESSAGES | FILTERED | JOINS => event.to_string(),
+            _ => match event.strip_suffix(FROM_USER_SUFFIX) {
+                Some(base @ (MESSAGES | FILTERED)) => match self.user_id {
+                    Some(user_id) => user_event(base, user_id),
+                    None => return Ok(0),
+                },
+                _ => return Err(ValueError::new_other(format!("unknown event \"{event}\""))),
+            },
+        };
+
+        Ok(self.windows.count(&key, self.now - window))
+    }
+
+    fn is_cooling_down(&self, key: &str) -> Result<bool, ValueError> {
+        Ok(self
+            .chat
+            .cooldowns
+            .get(key)
+            .is_some_and(|expires| *expires > self.now.timestamp()))
+    }
+
+    fn counter(&self, name: &str) -> Result<i64, ValueError> {
+        Ok(self
+            .chat
+            .counters
+            .get(name)
+            .map_or(0, |counter| counter.value_at(self.now.timestamp())))
+    }
+}
@@ -1,3 +1,11 @@
+pub mod callbacks;
 pub mod database;
+pub mod digest;
 pub mod error;
+pub mod events;
+pub mod outbox;
+pub mod presets;
+pub mod profiles;
+pub mod reputation;
 pub mod session;
+pub mod status_page;
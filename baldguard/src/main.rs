@@ -1,23 +1,72 @@
 use baldguard::{
     database::Db,
-    session::{SendUpdate, Session},
+    digest::collect_due_digests,
+    outbox::Outbox,
+    profiles::{Profile, ProfileCache},
+    reputation::ReputationService,
+    session::{self, SendUpdate, Session},
+    status_page::write_status_pages,
+};
+use chrono::{TimeDelta, Utc};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::PathBuf,
+    process::exit,
+    sync::Arc,
+    time::Duration,
 };
-use std::{collections::HashMap, process::exit, sync::Arc, time::Duration};
 use teloxide::{
-    prelude::Requester,
-    types::{ChatId, ChatMemberStatus, Message},
+    dispatching::{Dispatcher, UpdateFilterExt},
+    dptree,
+    net::Download,
+    payloads::{
+        AnswerCallbackQuerySetters, AnswerInlineQuerySetters, BanChatMemberSetters,
+        EditMessageTextSetters, RestrictChatMemberSetters, SendMessageSetters,
+        UnbanChatMemberSetters,
+    },
+    prelude::{Requester, ResponseResult},
+    types::{
+        CallbackQuery, ChatId, ChatMemberStatus, Document, InlineKeyboardButton,
+        InlineKeyboardMarkup, InlineQuery, InlineQueryResult, InlineQueryResultArticle, InputFile,
+        InputMessageContent, InputMessageContentText, Message, ReplyParameters, Update, UserId,
+    },
     Bot,
 };
 use tokio::sync::Mutex;
 
 type Sessions = Arc<Mutex<HashMap<ChatId, Session>>>;
+type Profiles = Arc<Mutex<ProfileCache>>;
+/// `None` when REPUTATION_API_URL isn't set.
+type Reputation = Arc<Option<ReputationService>>;
+
+/// Largest document downloaded for /import_config, in bytes.
+const MAX_DOCUMENT_SIZE: u32 = 1024 * 1024;
+
+#[derive(Clone, Copy)]
+struct OwnerId(Option<UserId>);
 
-async fn session_cleanup_routine(sessions: Sessions) {
+async fn session_cleanup_routine(
+    sessions: Sessions,
+    database: Arc<Mutex<Db>>,
+    profiles: Profiles,
+    reputation: Reputation,
+) {
     let timeout_duration = Duration::from_secs(600);
     let cleanup_interval = Duration::from_secs(60);
     loop {
         tokio::time::sleep(cleanup_interval).await;
 
+        profiles.lock().await.remove_expired();
+        if let Some(reputation) = reputation.as_ref() {
+            reputation.remove_expired().await;
+        }
+
+        // Sessions could not be reopened while the database is unavailable,
+        // keep filtering with the chats they hold.
+        if database.lock().await.pending_write_count() > 0 {
+            continue;
+        }
+
         let mut sessions_lock = sessions.lock().await;
 
         sessions_lock.retain(|&_, session| {
@@ -31,6 +80,542 @@ async fn session_cleanup_routine(sessions: Sessions) {
     }
 }
 
+/// Posts due digests in their chat, or in its log chat if it has one.
+async fn digest_routine(database: Arc<Mutex<Db>>, outbox: Arc<Outbox>) {
+    let check_interval = Duration::from_secs(3600);
+    loop {
+        tokio::time::sleep(check_interval).await;
+
+        let db_lock = database.lock().await;
+        let digests = match collect_due_digests(&db_lock, Utc::now().timestamp()).await {
+            Ok(digests) => digests,
+            Err(e) => {
+                log::error!("Failed to collect digests: {e}");
+                continue;
+            }
+        };
+        drop(db_lock);
+
+        for (chat, digest) in digests {
+            let chat_id = ChatId(chat.chat_id);
+            let update = match chat.settings.log_chat_id {
+                0 => SendUpdate::message(digest.to_string()),
+                log_chat_id => SendUpdate::Log {
+                    chat_id: ChatId(log_chat_id),
+                    text: format!(
+                        "{}: {digest}",
+                        chat.title.unwrap_or_else(|| chat_id.to_string())
+                    ),
+                    buttons: Vec::new(),
+                },
+            };
+            send_updates(&outbox, chat_id, vec![update]).await;
+        }
+    }
+}
+
+/// Replays writes queued while the database was unavailable and tells the
+/// owner when it goes down and comes back.
+async fn database_recovery_routine(
+    database: Arc<Mutex<Db>>,
+    owner_id: OwnerId,
+    outbox: Arc<Outbox>,
+) {
+    let retry_interval = Duration::from_secs(30);
+    let mut is_unavailable = false;
+    loop {
+        tokio::time::sleep(retry_interval).await;
+
+        let mut db_lock = database.lock().await;
+        let pending = db_lock.pending_write_count();
+        if pending == 0 {
+            continue;
+        }
+
+        let message = match db_lock.replay_pending_writes().await {
+            Ok(replayed) => {
+                log::info!("Database available again, replayed {replayed} writes");
+                is_unavailable = false;
+                Some(format!(
+                    "database available again, replayed {replayed} queued writes"
+                ))
+            }
+            Err(e) => {
+                log::error!("Database unavailable, {pending} writes queued: {e}");
+                let message = (!is_unavailable).then(|| {
+                    format!(
+                        "database unavailable, filtering continues with cached chats \
+                        and writes are queued: {e}"
+                    )
+                });
+                is_unavailable = true;
+                message
+            }
+        };
+        drop(db_lock);
+
+        if let (Some(message), Some(owner_id)) = (message, owner_id.0) {
+            outbox
+                .push(ChatId::from(owner_id), SendUpdate::message(message))
+                .await;
+        }
+    }
+}
+
+async fn status_page_routine(database: Arc<Mutex<Db>>, directory: PathBuf) {
+    let update_interval = Duration::from_secs(3600);
+    loop {
+        let db_lock = database.lock().await;
+        if let Err(e) = write_status_pages(&db_lock, &directory, Utc::now().timestamp()).await {
+            log::error!("Failed to write status pages: {e}");
+        }
+        drop(db_lock);
+
+        tokio::time::sleep(update_interval).await;
+    }
+}
+
+async fn get_session<'a>(
+    sessions_lock: &'a mut HashMap<ChatId, Session>,
+    chat_id: ChatId,
+    database: Arc<Mutex<Db>>,
+    bot_username: &str,
+) -> Option<&'a mut Session> {
+    match sessions_lock.entry(chat_id) {
+        Entry::Occupied(entry) => Some(entry.into_mut()),
+        Entry::Vacant(entry) => {
+            match Session::new(database, chat_id, bot_username.to_string()).await {
+                Ok(session) => {
+                    log::info!("Opening session for {chat_id}");
+                    Some(entry.insert(session))
+                }
+                Err(e) => {
+                    log::error!("Failed to open session for {chat_id}: {e}");
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// Profile of the user from the cache, fetched with getChat if it is missing or
+/// expired.
+async fn get_profile(bot: &Bot, profiles: &Profiles, user_id: UserId) -> Option<Profile> {
+    if let Some(profile) = profiles.lock().await.get(user_id) {
+        return Some(profile.clone());
+    }
+
+    match bot.get_chat(user_id).await {
+        Ok(chat) => {
+            let profile = Profile::from(&chat);
+            profiles.lock().await.insert(user_id, profile.clone());
+            Some(profile)
+        }
+        Err(e) => {
+            log::warn!("Failed to get profile of {user_id}: {e}");
+            None
+        }
+    }
+}
+
+/// Administrators and owner of the chat, `None` if they can't be fetched.
+async fn get_admins(bot: &Bot, chat_id: ChatId) -> Option<Vec<UserId>> {
+    match bot.get_chat_administrators(chat_id).await {
+        Ok(members) => Some(
+            members
+                .iter()
+                .filter(|member| {
+                    matches!(
+                        member.status(),
+                        ChatMemberStatus::Administrator | ChatMemberStatus::Owner
+                    )
+                })
+                .map(|member| member.user.id)
+                .collect(),
+        ),
+        Err(e) => {
+            log::error!("Failed to get chat administrators for {chat_id}: {e}");
+            None
+        }
+    }
+}
+
+/// Content of a small text document, `None` if it is too large, not text or
+/// can't be downloaded.
+async fn download_document(bot: &Bot, document: &Document) -> Option<String> {
+    if document.file.size > MAX_DOCUMENT_SIZE {
+        log::warn!(
+            "Refusing to download document of {} bytes",
+            document.file.size
+        );
+        return None;
+    }
+
+    let file = match bot.get_file(&document.file.id).await {
+        Ok(file) => file,
+        Err(e) => {
+            log::error!("Failed to get document: {e}");
+            return None;
+        }
+    };
+    let mut content = Vec::new();
+    if let Err(e) = bot.download_file(&file.path, &mut content).await {
+        log::error!("Failed to download document: {e}");
+        return None;
+    }
+
+    String::from_utf8(content).ok()
+}
+
+async fn send_updates(outbox: &Arc<Outbox>, chat_id: ChatId, updates: Vec<SendUpdate>) {
+    for update in updates {
+        let (chat_id, update) = match update {
+            SendUpdate::ForChat { chat_id, update } => (chat_id, *update),
+            update => (chat_id, update),
+        };
+        match update {
+            SendUpdate::ReloadChat(_) => {}
+            SendUpdate::DelayedDeleteMessage { message_id, delay } => {
+                let outbox = outbox.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    outbox
+                        .push(chat_id, SendUpdate::DeleteMessage(message_id))
+                        .await;
+                });
+            }
+            // Queued under the log chat, so a busy chat doesn't hold up the
+            // log chats of others.
+            update @ SendUpdate::Log {
+                chat_id: log_chat_id,
+                ..
+            } => outbox.push(log_chat_id, update).await,
+            update => outbox.push(chat_id, update).await,
+        }
+    }
+}
+
+/// Inline keyboard with one button per row, from labels and callback data.
+fn keyboard(buttons: Vec<(String, String)>) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(
+        buttons
+            .into_iter()
+            .map(|(label, data)| vec![InlineKeyboardButton::callback(label, data)]),
+    )
+}
+
+/// Sends queued operations one at a time, staying below the global rate limit
+/// of Telegram.
+async fn outbox_routine(bot: Bot, outbox: Arc<Outbox>) {
+    let send_interval = Duration::from_millis(35);
+    loop {
+        let (chat_id, update) = outbox.pop().await;
+        match update {
+            SendUpdate::Message {
+                text,
+                reply_to,
+                parse_mode,
+            } => {
+                let mut request = bot.send_message(chat_id, text);
+                if let Some(parse_mode) = parse_mode {
+                    request = request.parse_mode(parse_mode);
+                }
+                if let Some(message_id) = reply_to {
+                    // Delayed deletions may remove the message first.
+                    request = request.reply_parameters(
+                        ReplyParameters::new(message_id).allow_sending_without_reply(),
+                    );
+                }
+                if let Err(e) = request.await {
+                    log::error!("Failed to send message: {e}");
+                }
+            }
+            SendUpdate::MessageWithButtons {
+                text,
+                buttons,
+                reply_to,
+            } => {
+                let mut request = bot
+                    .send_message(chat_id, text)
+                    .reply_markup(keyboard(buttons));
+                if let Some(message_id) = reply_to {
+                    request = request.reply_parameters(
+                        ReplyParameters::new(message_id).allow_sending_without_reply(),
+                    );
+                }
+                if let Err(e) = request.await {
+                    log::error!("Failed to send message: {e}");
+                }
+            }
+            SendUpdate::DeleteMessage(message_id) => {
+                if let Err(e) = bot.delete_message(chat_id, message_id).await {
+                    log::error!("Failed to delete message: {e}");
+                }
+            }
+            SendUpdate::ForwardMessage {
+                from_chat_id,
+                message_id,
+            } => {
+                if let Err(e) = bot.forward_message(chat_id, from_chat_id, message_id).await {
+                    log::error!("Failed to forward message: {e}");
+                }
+            }
+            SendUpdate::Log {
+                chat_id: log_chat_id,
+                text,
+                buttons,
+            } => {
+                let mut request = bot.send_message(log_chat_id, text);
+                if !buttons.is_empty() {
+                    request = request.reply_markup(keyboard(buttons));
+                }
+                if let Err(e) = request.await {
+                    log::error!("Failed to send log message to {log_chat_id}: {e}");
+                }
+            }
+            SendUpdate::Document { file_name, content } => {
+                let file = InputFile::memory(content.into_bytes()).file_name(file_name);
+                if let Err(e) = bot.send_document(chat_id, file).await {
+                    log::error!("Failed to send document: {e}");
+                }
+            }
+            SendUpdate::RestrictUser {
+                user_id,
+                duration,
+                permissions,
+            } => {
+                // Telegram restricts forever when until is in the past, which
+                // is also what an out of range duration asks for.
+                let until = Utc::now() + TimeDelta::from_std(duration).unwrap_or_default();
+                if let Err(e) = bot
+                    .restrict_chat_member(chat_id, user_id, permissions)
+                    .until_date(until)
+                    .await
+                {
+                    log::error!("Failed to restrict user: {e}");
+                }
+            }
+            SendUpdate::BanUser { user_id, duration } => {
+                let mut request = bot.ban_chat_member(chat_id, user_id);
+                if let Some(duration) = duration {
+                    request = request
+                        .until_date(Utc::now() + TimeDelta::from_std(duration).unwrap_or_default());
+                }
+                if let Err(e) = request.await {
+                    log::error!("Failed to ban user: {e}");
+                }
+            }
+            SendUpdate::UnbanUser(user_id) => {
+                // Without only_if_banned Telegram removes members who aren't
+                // banned from the chat.
+                if let Err(e) = bot
+                    .unban_chat_member(chat_id, user_id)
+                    .only_if_banned(true)
+                    .await
+                {
+                    log::error!("Failed to unban user: {e}");
+                }
+            }
+            SendUpdate::EditMessage {
+                message_id,
+                text,
+                buttons,
+            } => {
+                let mut request = bot.edit_message_text(chat_id, message_id, text);
+                if !buttons.is_empty() {
+                    request = request.reply_markup(keyboard(buttons));
+                }
+                if let Err(e) = request.await {
+                    log::error!("Failed to edit message: {e}");
+                }
+            }
+            // Handled by send_updates before queueing.
+            SendUpdate::DelayedDeleteMessage { .. }
+            | SendUpdate::ReloadChat(_)
+            | SendUpdate::ForChat { .. } => {}
+            // Handled by handle_callback_query, queries are answered once.
+            SendUpdate::AnswerCallback { .. } => {}
+        }
+
+        tokio::time::sleep(send_interval).await;
+    }
+}
+
+// Handlers receive their dependencies from dptree as separate arguments.
+#[allow(clippy::too_many_arguments)]
+async fn handle_message(
+    bot: Bot,
+    message: Message,
+    sessions: Sessions,
+    database: Arc<Mutex<Db>>,
+    bot_username: Arc<String>,
+    owner_id: OwnerId,
+    outbox: Arc<Outbox>,
+    profiles: Profiles,
+    reputation: Reputation,
+) -> ResponseResult<()> {
+    let chat_id = message.chat.id;
+
+    // Lookups over the network are made without holding the sessions, which
+    // would hold up the messages of every chat.
+    let mut sessions_lock = sessions.lock().await;
+    let Some(session) =
+        get_session(&mut sessions_lock, chat_id, database.clone(), &bot_username).await
+    else {
+        return Ok(());
+    };
+    let fetches_profiles = session.fetches_profiles();
+    let checks_reputation = session.checks_reputation();
+    let fetches_admins = !message.chat.is_private() && session.fetches_admins();
+    let is_known_admin = message
+        .from
+        .as_ref()
+        .is_some_and(|from| session.is_known_admin(from.id));
+    drop(sessions_lock);
+
+    let admin_ids = match fetches_admins {
+        true => get_admins(&bot, chat_id).await,
+        false => None,
+    };
+    let is_admin = message.chat.is_private()
+        || match (&admin_ids, &message.from) {
+            (Some(admin_ids), Some(from)) => admin_ids.contains(&from.id),
+            _ => is_known_admin,
+        };
+
+    let is_owner = match (&message.from, owner_id.0) {
+        (Some(from), Some(owner_id)) => from.id == owner_id,
+        _ => false,
+    };
+
+    let profile = match &message.from {
+        Some(from) if fetches_profiles => get_profile(&bot, &profiles, from.id).await,
+        _ => None,
+    };
+
+    let is_known_spammer = match (&message.from, reputation.as_ref()) {
+        (Some(from), Some(reputation)) if checks_reputation => {
+            reputation.is_known_spammer(from.id).await
+        }
+        _ => None,
+    };
+
+    let document = match session::requested_document(&message) {
+        Some(document) if is_admin => download_document(&bot, document).await,
+        _ => None,
+    };
+
+    let mut sessions_lock = sessions.lock().await;
+    let Some(session) = get_session(&mut sessions_lock, chat_id, database, &bot_username).await
+    else {
+        return Ok(());
+    };
+
+    match session
+        .handle_message(
+            message,
+            admin_ids.as_deref(),
+            is_owner,
+            profile.as_ref(),
+            is_known_spammer,
+            document.as_deref(),
+        )
+        .await
+    {
+        Ok(updates) => {
+            for update in &updates {
+                if let SendUpdate::ReloadChat(reload_chat_id) = update {
+                    sessions_lock.remove(reload_chat_id);
+                }
+            }
+            send_updates(&outbox, chat_id, updates).await
+        }
+        Err(e) => {
+            log::error!("Failed to handle message from {chat_id}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_callback_query(
+    bot: Bot,
+    query: CallbackQuery,
+    sessions: Sessions,
+    database: Arc<Mutex<Db>>,
+    bot_username: Arc<String>,
+    outbox: Arc<Outbox>,
+) -> ResponseResult<()> {
+    let mut answer = None;
+    if let (Some(data), Some(message)) = (&query.data, &query.message) {
+        let chat_id = message.chat().id;
+        let message_text = message
+            .regular_message()
+            .and_then(|message| message.text())
+            .unwrap_or("");
+        let mut sessions_lock = sessions.lock().await;
+
+        if let Some(session) =
+            get_session(&mut sessions_lock, chat_id, database, &bot_username).await
+        {
+            match session
+                .handle_callback_query(data, query.from.id.0 as i64, message.id(), message_text)
+                .await
+            {
+                Ok(updates) => {
+                    let (answers, updates): (Vec<SendUpdate>, Vec<SendUpdate>) = updates
+                        .into_iter()
+                        .partition(|update| matches!(update, SendUpdate::AnswerCallback { .. }));
+                    answer = answers.into_iter().last();
+                    send_updates(&outbox, chat_id, updates).await
+                }
+                Err(e) => {
+                    log::error!("Failed to handle callback query from {chat_id}: {e}");
+                }
+            }
+        }
+    }
+
+    let mut request = bot.answer_callback_query(query.id);
+    if let Some(SendUpdate::AnswerCallback { text, alert }) = answer {
+        request = request.text(text).show_alert(alert);
+    }
+    if let Err(e) = request.await {
+        log::error!("Failed to answer callback query: {e}");
+    }
+
+    Ok(())
+}
+
+/// Answers `@bot <expr>` with the value of the expression, which can be sent
+/// to the chat along with the expression.
+async fn handle_inline_query(bot: Bot, query: InlineQuery) -> ResponseResult<()> {
+    let expression = query.query.trim();
+    let mut results = Vec::new();
+    if !expression.is_empty() {
+        let value = session::evaluate_inline_query(expression);
+        let content = InputMessageContentText::new(format!("{expression}\n= {value}"));
+        let article = InlineQueryResultArticle::new(
+            "result",
+            value.clone(),
+            InputMessageContent::Text(content),
+        )
+        .description(expression);
+        results.push(InlineQueryResult::Article(article));
+    }
+
+    // Not cached, expressions can depend on the time with now().
+    if let Err(e) = bot
+        .answer_inline_query(query.id, results)
+        .cache_time(0)
+        .await
+    {
+        log::error!("Failed to answer inline query: {e}");
+    }
+
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     pretty_env_logger::init();
@@ -52,6 +637,17 @@ async fn main() {
         }
     };
 
+    let owner_id = match std::env::var("BOT_OWNER_ID") {
+        Ok(value) => match value.parse() {
+            Ok(value) => OwnerId(Some(UserId(value))),
+            Err(e) => {
+                log::error!("Invalid BOT_OWNER_ID: {e}");
+                exit(1)
+            }
+        },
+        Err(_) => OwnerId(None),
+    };
+
     let sessions: Sessions = Arc::new(Mutex::new(HashMap::new()));
     let sessions_clone = sessions.clone();
     let database: Arc<Mutex<Db>> = Arc::new(Mutex::new(match Db::new(&connection_str).await {
@@ -62,9 +658,41 @@ async fn main() {
         }
     }));
 
-    tokio::spawn(async move { session_cleanup_routine(sessions_clone) });
+    let reputation: Reputation = Arc::new(match std::env::var("REPUTATION_API_URL") {
+        Ok(url) => match ReputationService::new(url) {
+            Ok(service) => Some(service),
+            Err(e) => {
+                log::error!("Invalid REPUTATION_API_URL: {e}");
+                exit(1)
+            }
+        },
+        Err(_) => None,
+    });
+
+    let profiles: Profiles = Arc::new(Mutex::new(ProfileCache::new()));
+    tokio::spawn(session_cleanup_routine(
+        sessions_clone,
+        database.clone(),
+        profiles.clone(),
+        reputation.clone(),
+    ));
+
+    if let Ok(directory) = std::env::var("STATUS_PAGE_DIR") {
+        tokio::spawn(status_page_routine(
+            database.clone(),
+            PathBuf::from(directory),
+        ));
+    }
 
     let bot = Bot::new(token);
+    let outbox = Arc::new(Outbox::new());
+    tokio::spawn(outbox_routine(bot.clone(), outbox.clone()));
+    tokio::spawn(database_recovery_routine(
+        database.clone(),
+        owner_id,
+        outbox.clone(),
+    ));
+    tokio::spawn(digest_routine(database.clone(), outbox.clone()));
     let bot_username = match bot.get_me().await {
         Ok(me) => match &me.username {
             Some(username) => username.clone(),
@@ -79,75 +707,25 @@ async fn main() {
         }
     };
     let bot_username = Arc::new(bot_username);
-    teloxide::repl(bot, move |bot: Bot, message: Message| {
-        let sessions = Arc::clone(&sessions);
-        let database = Arc::clone(&database);
-        let bot_username = Arc::clone(&bot_username);
-        async move {
-            let chat_id = message.chat.id;
-            let mut sessions_lock = sessions.lock().await;
-
-            let session = if sessions_lock.contains_key(&chat_id) {
-                sessions_lock.get_mut(&chat_id).unwrap()
-            } else {
-                match Session::new(database, chat_id, bot_username.as_ref().clone()).await {
-                    Ok(session) => {
-                        log::info!("Opening session for {chat_id}");
-                        sessions_lock.insert(chat_id, session);
-                        sessions_lock.get_mut(&chat_id).unwrap()
-                    }
-                    Err(e) => {
-                        log::error!("Failed to open session for {chat_id}: {e}");
-                        return Ok(());
-                    }
-                }
-            };
 
-            let mut is_admin = false;
-            if message.chat.is_private() {
-                is_admin = true;
-            } else {
-                if let Some(user_id) = message.from.clone().map(|u| u.id) {
-                    match bot.get_chat_administrators(chat_id).await {
-                        Ok(admins) => {
-                            is_admin = admins.iter().any(|member| {
-                                member.user.id == user_id
-                                    && matches!(
-                                        member.status(),
-                                        ChatMemberStatus::Administrator | ChatMemberStatus::Owner
-                                    )
-                            })
-                        }
-                        Err(e) => {
-                            log::error!("Failed to get chat administrators for {chat_id}: {e}");
-                        }
-                    }
-                }
-            }
-
-            match session.handle_message(message, is_admin).await {
-                Ok(updates) => {
-                    for update in updates {
-                        match update {
-                            SendUpdate::Message(text) => {
-                                if let Err(e) = bot.send_message(chat_id, text).await {
-                                    log::error!("Failed to send message: {e}");
-                                }
-                            }
-                            SendUpdate::DeleteMessage(message_id) => {
-                                if let Err(e) = bot.delete_message(chat_id, message_id).await {
-                                    log::error!("Failed to delete message: {e}");
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to handle message from {chat_id}: {e}");
-                }
-            }
-            Ok(())
-        }
-    })
-    .await;
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(handle_message))
+        .branch(Update::filter_callback_query().endpoint(handle_callback_query))
+        .branch(Update::filter_inline_query().endpoint(handle_inline_query));
+
+    Dispatcher::builder(bot, handler)
+        .dependencies(dptree::deps![
+            sessions,
+            database,
+            bot_username,
+            owner_id,
+            outbox,
+            profiles,
+            reputation
+        ])
+        .default_handler(|_| async {})
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch()
+        .await;
 }
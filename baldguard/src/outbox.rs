@@ -0,0 +1,105 @@
+use super::session::SendUpdate;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use teloxide::types::ChatId;
+use tokio::sync::{Mutex, Notify};
+
+/// Order outgoing operations are sent in, most important first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Delete,
+    Restriction,
+    Log,
+    Reply,
+}
+
+impl SendUpdate {
+    pub fn priority(&self) -> Priority {
+        match self {
+            SendUpdate::DeleteMessage(_) | SendUpdate::DelayedDeleteMessage { .. } => {
+                Priority::Delete
+            }
+            SendUpdate::RestrictUser { .. }
+            | SendUpdate::BanUser { .. }
+            | SendUpdate::UnbanUser(_) => Priority::Restriction,
+            SendUpdate::Log { .. } | SendUpdate::ForwardMessage { .. } => Priority::Log,
+            SendUpdate::ForChat { update, .. } => update.priority(),
+            SendUpdate::Message { .. }
+            | SendUpdate::MessageWithButtons { .. }
+            | SendUpdate::EditMessage { .. }
+            | SendUpdate::AnswerCallback { .. }
+            | SendUpdate::Document { .. }
+            | SendUpdate::ReloadChat(_) => Priority::Reply,
+        }
+    }
+}
+
+/// Operations of one priority, queued per chat and taken from the chats in
+/// turn, so a raid in one chat doesn't hold up the others.
+#[derive(Default)]
+struct FairQueue {
+    turns: VecDeque<ChatId>,
+    updates: HashMap<ChatId, VecDeque<SendUpdate>>,
+}
+
+impl FairQueue {
+    fn push(&mut self, chat_id: ChatId, update: SendUpdate) {
+        let updates = self.updates.entry(chat_id).or_default();
+        if updates.is_empty() {
+            self.turns.push_back(chat_id);
+        }
+        updates.push_back(update);
+    }
+
+    fn pop(&mut self) -> Option<(ChatId, SendUpdate)> {
+        let chat_id = self.turns.pop_front()?;
+        let updates = self.updates.get_mut(&chat_id)?;
+        let update = updates.pop_front()?;
+        if updates.is_empty() {
+            self.updates.remove(&chat_id);
+        } else {
+            self.turns.push_back(chat_id);
+        }
+
+        Some((chat_id, update))
+    }
+}
+
+/// Prioritized queue of outgoing operations, drained by a single sender so
+/// deletes go out first when Telegram rate limits kick in.
+#[derive(Default)]
+pub struct Outbox {
+    queues: Mutex<BTreeMap<Priority, FairQueue>>,
+    notify: Notify,
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn push(&self, chat_id: ChatId, update: SendUpdate) {
+        let mut queues = self.queues.lock().await;
+        queues
+            .entry(update.priority())
+            .or_default()
+            .push(chat_id, update);
+        drop(queues);
+
+        self.notify.notify_one();
+    }
+
+    /// Waits for the most important queued operation.
+    pub async fn pop(&self) -> (ChatId, SendUpdate) {
+        loop {
+            let mut queues = self.queues.lock().await;
+            for queue in queues.values_mut() {
+                if let Some(item) = queue.pop() {
+                    return item;
+                }
+            }
+            drop(queues);
+
+            self.notify.notified().await;
+        }
+    }
+}
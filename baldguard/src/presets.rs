@@ -0,0 +1,38 @@
+/// Filter shipped with the bot, added to a chat with /use_preset.
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub text: &'static str,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "link_spam",
+        description: "messages with many links, or a link in the first message of a user",
+        text: "msg.url_count >= 3 or (msg.has_url and msg.is_first_message = true)",
+    },
+    Preset {
+        name: "forward_spam",
+        description: "forwards from newcomers or from users who forwarded a lot lately",
+        text: "msg.has_origin and (msg.is_first_message = true \
+               or count(\"messages_from_user\", \"1m\") > 3)",
+    },
+    Preset {
+        name: "media_flood",
+        description: "media sent by a user who sent more than 4 messages in 10 seconds",
+        text: "(msg.has_photo or msg.has_video or msg.has_animation or msg.has_sticker \
+               or msg.has_document) and count(\"messages_from_user\", \"10s\") > 4",
+    },
+    Preset {
+        name: "crypto_scam",
+        description: "giveaways and investment offers in crypto currencies",
+        text: "msg.has_text and normalize(msg.text) matches any [\
+               \"(?i)(airdrop|giveaway)\", \
+               \"(?i)(bitcoin|btc|usdt|eth|crypto).{0,40}(invest|profit|earn|double)\", \
+               \"(?i)(invest|profit|earn).{0,40}(bitcoin|btc|usdt|eth|crypto)\"]",
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
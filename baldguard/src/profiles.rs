@@ -0,0 +1,54 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use teloxide::types::{Chat, UserId};
+
+/// Profiles are fetched again after a day, so changes to them are picked up
+/// eventually without a request per message.
+const PROFILE_TTL: Duration = Duration::from_secs(86400);
+
+/// Details of a user's profile that are not part of their messages.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    pub bio: Option<String>,
+    pub has_photo: bool,
+}
+
+impl From<&Chat> for Profile {
+    fn from(value: &Chat) -> Self {
+        Profile {
+            bio: value.bio().map(|bio| bio.to_string()),
+            has_photo: value.photo.is_some(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ProfileCache {
+    profiles: HashMap<UserId, (Instant, Profile)>,
+}
+
+impl ProfileCache {
+    pub fn new() -> Self {
+        ProfileCache {
+            profiles: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, user_id: UserId) -> Option<&Profile> {
+        match self.profiles.get(&user_id) {
+            Some((fetched, profile)) if fetched.elapsed() < PROFILE_TTL => Some(profile),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, user_id: UserId, profile: Profile) {
+        self.profiles.insert(user_id, (Instant::now(), profile));
+    }
+
+    pub fn remove_expired(&mut self) {
+        self.profiles
+            .retain(|_, (fetched, _)| fetched.elapsed() < PROFILE_TTL);
+    }
+}
@@ -0,0 +1,95 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+use teloxide::types::UserId;
+use tokio::sync::Mutex;
+
+/// Verdicts are looked up again after six hours, ban lists change slowly.
+const VERDICT_TTL: Duration = Duration::from_secs(21600);
+/// Failed lookups are not retried for a minute, so a service that is down
+/// doesn't cost every message of the user the timeout.
+const FAILURE_TTL: Duration = Duration::from_secs(60);
+/// Messages wait for the lookup, so a slow service must not hold them up.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(3);
+/// Placeholder replaced by the id of the user in the url of the service.
+pub const USER_ID_PLACEHOLDER: &str = "{user_id}";
+
+/// Answer of the service, CAS style: `ok` is true when the user is listed.
+#[derive(Deserialize)]
+struct ReputationResponse {
+    ok: bool,
+}
+
+/// HTTP service listing known spammers, queried with a GET on its url with
+/// `{user_id}` replaced, like `https://api.cas.chat/check?user_id={user_id}`.
+pub struct ReputationService {
+    client: reqwest::Client,
+    url: String,
+    /// Expiry and verdict of the users looked up, no verdict if the lookup
+    /// failed.
+    verdicts: Mutex<HashMap<UserId, (Instant, Option<bool>)>>,
+}
+
+impl ReputationService {
+    pub fn new(url: String) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if !url.contains(USER_ID_PLACEHOLDER) {
+            return Err(format!("the url has no {USER_ID_PLACEHOLDER} placeholder").into());
+        }
+
+        Ok(ReputationService {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            url,
+            verdicts: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether the user is listed, from the cache or the service. `None` if
+    /// the service can't be reached.
+    pub async fn is_known_spammer(&self, user_id: UserId) -> Option<bool> {
+        if let Some((expires, verdict)) = self.verdicts.lock().await.get(&user_id) {
+            if Instant::now() < *expires {
+                return *verdict;
+            }
+        }
+
+        let (ttl, verdict) = match self.fetch(user_id).await {
+            Ok(verdict) => (VERDICT_TTL, Some(verdict)),
+            Err(e) => {
+                log::warn!("Failed to look up the reputation of {user_id}: {e}");
+                (FAILURE_TTL, None)
+            }
+        };
+        self.verdicts
+            .lock()
+            .await
+            .insert(user_id, (Instant::now() + ttl, verdict));
+        verdict
+    }
+
+    pub async fn remove_expired(&self) {
+        let now = Instant::now();
+        self.verdicts
+            .lock()
+            .await
+            .retain(|_, (expires, _)| now < *expires);
+    }
+
+    async fn fetch(&self, user_id: UserId) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let url = self.url.replace(USER_ID_PLACEHOLDER, &user_id.to_string());
+        let response: ReputationResponse = self
+            .client
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(response.ok)
+    }
+}
@@ -1,39 +1,166 @@
-use super::database::{Chat, Db, Filter};
+use super::{
+    callbacks::Callback,
+    database::{
+        Action, BlockedMedia, Chat, ChatConfig, Db, Filter, IdentifierMode, PendingWrite, Project,
+        Schedule, Settings, TestCase, Verdict, MAX_ALIASES, MAX_BLOCKED_DOMAINS, MAX_BLOCKED_MEDIA,
+        MAX_BLOCKED_ORIGINS, MAX_TEST_CASES, SECONDS_PER_DAY,
+    },
+    digest::Digest,
+    error::GenericError,
+    events::{self, EventWindows, MessageEnvironment, RecentTexts},
+    presets::{self, PRESETS},
+    profiles::Profile,
+};
 use baldguard_language::{
-    evaluation::{evaluate, ContainsVariable, SetFromAssignment, Value, Variables},
-    grammar::{AssignmentParser, ExpressionParser, IdentifierParser},
+    builtins,
+    compile::CompiledExpression,
+    definitions::{DefinedExpressions, Definition},
+    environment::{Effect, EmptyEnvironment, Sandbox},
+    error_code::{
+        self, INVALID_ARGUMENTS, INVALID_COMMAND, INVALID_CONFIG, INVALID_INPUT, LIMIT_EXCEEDED,
+        NOT_FOUND, NO_REPLY_MESSAGE, PERMISSION_DENIED, REQUEST_FAILED,
+    },
+    evaluation::{
+        ContainsVariable, EvaluationError, EvaluationResult, MergePolicy, SetFromAssignment,
+        ToVariables, Value, ValueError, Variables, NAMESPACE_SEPARATOR,
+    },
+    grammar::{AssignmentParser, AssignmentsParser, ExpressionParser, IdentifierParser},
+    lint::{self, Warning},
+    parse_error, trace,
+    tree::Expression,
 };
 use baldguard_macros::{ContainsVariable, ToVariables};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeDelta, Utc};
+use mongodb::bson::oid::ObjectId;
+use rand::Rng;
 use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
     error::Error,
     fmt::Display,
+    ops::Range,
     sync::Arc,
     time::{Duration, Instant},
 };
-use teloxide::types::{ChatId, Message, MessageId, MessageOrigin};
+use teloxide::{
+    types::{
+        ChatId, ChatPermissions, Document, Message, MessageEntityKind, MessageId, MessageOrigin,
+        ParseMode, PhotoSize, UserId,
+    },
+    utils::html,
+};
 use tokio::sync::Mutex;
 
+const USER_NAMESPACE: &str = "user";
+/// Namespace of `MessageVariables`.
+const MESSAGE_NAMESPACE: &str = "msg";
+
 const HELP_STRING: &str = "/set_filter <expr>
-change current filter. expr should evaluate to bool value.
+change the default filter. expr should evaluate to bool value.
+requires admin rights.
+
+/add_filter <name> <expr>
+add a named filter, or change the filter with that name. filters are evaluated
+in the order they were added, the first one that matches deletes the message.
+/set_filter changes the filter named default.
+requires admin rights.
+
+/remove_filter <name>
+remove a filter.
+requires admin rights.
+
+/enable_filter <name>
+/disable_filter <name>
+turn a filter on or off without removing it.
+requires admin rights.
+
+/shadow_filter <name>
+/enforce_filter <name>
+put a filter in shadow mode, where its matches are only sent to the log chat
+and counted in /stats, or make it act on messages again.
+requires admin rights.
+
+/set_filter_action <name> <action>
+choose what happens when a filter matches. action is one of:
+- delete: delete the message, which new filters do.
+- warn: reply to the message with a warning and keep it.
+- mute <minutes>: delete the message and mute its sender.
+- ban: delete the message and ban its sender.
+- report: keep the message and report the match.
+- quarantine: keep the message and ask admins to approve or delete it, in the log chat if there is one.
+requires admin rights.
+
+/set_filter_expiry <name> <time>
+disable a filter at a time, given as a duration from now like 30m, 12h or 7d,
+or as YYYY-MM-DD HH:MM in the time of utc_offset_minutes. never removes the
+expiry.
+requires admin rights.
+
+/set_filter_schedule <name> <schedule>
+evaluate a filter only at some times, in the time of utc_offset_minutes.
+schedule is days, like weekends, weekdays or sat,sun, a time window like
+22:00-06:00, or both. a window past midnight belongs to the day it starts on.
+always removes the schedule.
+requires admin rights.
+
+/filter_history
+display the latest changes of filters, most recent first.
+
+/revert_filter [n]
+undo the nth latest filter change, the latest by default.
+requires admin rights.
+
+/list_presets
+display the filters shipped with the bot.
+
+/use_preset <name>
+add a filter shipped with the bot under its name, /get_filter displays it
+and /add_filter with the same name changes it.
+requires admin rights.
+
+/list_filters
+display all filters in the order they are evaluated, with their actions.
+
+/get_filter [name]
+display the filter with that name, or the default filter.
+
+/lint_filter
+warn about parts of the filters that are always true or false, comparisons
+of an expression with itself, invalid regexes and variables no message
+provides. /set_filter and /add_filter warn
+about them too.
+
+/set_filtered_message [template]
+set the report of filtered messages, or restore the default one.
+{rule_name} is replaced by the name of the filter and {variable}, like
+{from_username} or {text}, by the value of a message or user variable.
 requires admin rights.
 
-/get_filter
-display current filter.
+/alias <name> <command>
+make /name a shortcut for the command, like /alias nolinks /set_filter
+msg.has_link. what follows /name is appended to the command.
+requires admin rights.
+
+/unalias <name>
+remove a shortcut.
+requires admin rights.
+
+/list_aliases
+display the shortcuts.
 
-/set_option <option> := <expr>
-set an option.
+/set_option <option> := <expr>[, <option> := <expr>...]
+set options, none of them if any can't be set.
 available options:
-- debug_print: bool
-- report_filtered: bool
-- report_invalid_commands: bool
-- filter_enabled: bool
-- report_command_success: bool
+{options}
 expr should evaluate to value of option's type.
 requires admin rights.
 
 /get_options
 display current options.
 
+/get_option <option>
+display the value of an option with its type.
+
 /set_variable <variable> := <expr>
 set a user variable.
 requires admin rights.
@@ -45,18 +172,501 @@ requires admin rights.
 /get_variables
 display user variables.
 
+/export_variables
+display user variables as json, to back them up or import them elsewhere.
+
+/import_variables <json>
+set user variables from the output of /export_variables, variables that are
+already set are overwritten.
+requires admin rights.
+
 /get_message_variables
 display variables from message.
 
+expressions refer to message variables as msg.<name>, user variables as
+user.<name> and the chat as chat.id and chat.title. an unqualified name refers
+to the message variable of that name, or else the user variable.
+message variables:
+{message_variables}
+
+/define <name> := <expr>
+define a named expression that filters and /eval can refer to by name.
+requires admin rights.
+
+/undefine <name>
+remove a named expression.
+requires admin rights.
+
+/get_definitions
+display named expressions.
+
+forward a message to the bot in a private chat to display its variables and
+check it against the filter of any chat you administer.
+
 /eval <expr>
 evaluate the expression.
 
+@{bot_username} <expr>
+evaluate the expression from any chat, without variables.
+
+functions available in expressions:
+- normalize(str): str
+  NFKC-normalize, strip zero-width characters and fold lookalike letters to latin.
+- word_count(str): int
+- caps_ratio(str): int
+  percentage of uppercase letters among all letters.
+- emoji_count(str): int
+- now(): datetime
+- datetime(str): datetime
+  parse an RFC 3339 datetime such as \"2024-01-31T18:00:00Z\".
+- hour(datetime): int
+- format_datetime(datetime, str): str
+  format a datetime using strftime syntax.
+- count(str, str | int): int
+  number of events in the chat during the last window, given as seconds or
+  as \"30s\", \"5m\" and so on, up to 10 minutes. events are messages,
+  messages_from_user, filtered, filtered_from_user and joins; the _from_user
+  events count only those of the message's sender, for example
+  count(\"messages_from_user\", \"1m\") > 10.
+- cooldown(str | int, str | int): bool
+  true at most once per duration for the given key, for example
+  cooldown(from_id, \"1h\") to warn each user at most once an hour.
+- inc(str[, str | int]): int
+  increment a counter stored in the chat and return its new value, the
+  optional key keeps a separate counter per key, for example
+  inc(\"strikes\", from_id). counters not incremented for 30 days are
+  reset.
+- get_counter(str[, str | int]): int
+  value of a counter, 0 if it was never incremented or was reset.
+cooldown() and inc() only take effect when the filter matches the message,
+/eval and checking a forwarded message never change them.
+
+ints and datetimes can be compared with <, <=, > and >=, comparisons can be
+chained like 0 < x < 10. subtracting two datetimes gives the difference in
+seconds, adding or subtracting an int shifts a datetime by that many seconds.
+
+str matches any [\"pattern\", ...] checks a string against several regexes at
+once.
+
+match expr { \"user\" => ..., \"channel\" => ..., _ => ... } picks the value of the
+first case equal to expr, _ is used when no case matches.
+
+/test_filter [expr]
+reply to a message to check it against the filters, or against expr if given.
+nothing is deleted and cooldowns and counters are not changed.
+
+/explain [name]
+reply to a message to see why the filter with that name, or else the first
+filter that matches, gave its result: each operand of its top-level and/or with
+its value and the message variables it reads.
+
+/add_test <match|no_match> [name]
+reply to a message to keep its variables as a test case the filters should
+match or not. a test case with the same name is replaced.
+requires admin rights.
+
+/remove_test <name>
+remove a test case.
+requires admin rights.
+
+/run_tests
+check every test case against the enabled filters, outside of shadow mode.
+cooldowns and counters are not used.
+
+/export_config
+send the filters, options, variables, definitions and trusted and exempt
+users of this chat as a json document.
+requires admin rights.
+
+/import_config [config]
+reply to a document sent by /export_config, or give its content, to replace
+the setup of this chat with it.
+requires admin rights.
+
+/clone_chat_config <src_id> <dst_id>
+copy filters, options, variables and definitions from one chat to another.
+requires bot owner rights.
+
+/moderation_log [count]
+display the latest 10 (or count) moderation decisions in this chat.
+requires admin rights.
+
+/stats [days]
+display how many messages were seen, matched by each filter, deleted, warned
+about and restored in this chat during the last 7 (or the given number of)
+days, up to 365.
+requires admin rights.
+
+/recompute_stats
+rebuild the stats of this chat from its moderation log.
+requires admin rights.
+
+/trust [user_id]
+let filters exempt a user, given by id or by replying to one of their
+messages, through msg.from_is_trusted.
+requires admin rights.
+
+/untrust [user_id]
+take a user off the trusted list.
+requires admin rights.
+
+/exempt [user_id|@username]
+skip filtering for a user or bot, given by id, by username or by replying to
+one of their messages.
+requires admin rights.
+
+/unexempt [user_id|@username]
+filter a user or bot again.
+requires admin rights.
+
+/block_domain <domain>...
+delete messages linking to these domains or their subdomains, before the
+filters are evaluated.
+requires admin rights.
+
+/unblock_domain <domain>...
+stop deleting messages linking to these domains.
+requires admin rights.
+
+/list_domains
+display the blocked domains.
+
+/block_forward_source <id|@username>
+delete forwards from a user, chat or channel, whatever the filters say.
+requires admin rights.
+
+/unblock_forward_source <id|@username>
+stop deleting forwards from a user, chat or channel.
+requires admin rights.
+
+/list_forward_sources
+display the blocked forward sources.
+
+/block_media
+reply to a photo, sticker or file to delete it whenever it is sent again.
+requires admin rights.
+
+/unblock_media
+reply to a message to stop deleting its media.
+requires admin rights.
+
+/mute <minutes> [user_id]
+stop a user, given by id or by replying to one of their messages, from
+sending messages for some minutes.
+requires admin rights.
+
+/ban [minutes]
+reply to a message to ban its sender, forever or for some minutes.
+requires admin rights.
+
+/kick
+reply to a message to remove its sender, who can join again.
+requires admin rights.
+
+/unban [user_id]
+lift the ban of a user given by id or by replying to one of their messages.
+requires admin rights.
+
+/project_add <name> <chat_id>
+add a chat you administer to one of your projects, creating it if needed.
+
+/project_remove <name> <chat_id>
+remove a chat from one of your projects.
+
+/project_stats <name> [days]
+display moderation stats of the last 7 (or the given number of) days summed
+over all chats of a project.
+
 /help
 display this message.";
 
+/// Explanations of options listed by /help, options without one are listed
+/// with their type only.
+const OPTION_DESCRIPTIONS: &[(&str, &str)] = &[
+    (
+        "debug_parse",
+        "report filters that could not be re-parsed after a language update.",
+    ),
+    (
+        "debug_evaluation",
+        "report filter evaluation errors and non-bool results.",
+    ),
+    (
+        "debug_actions",
+        "report deleted messages and the cooldowns and counters a filter changed.",
+    ),
+    (
+        "debug_persistence",
+        "report failures to save the chat or its statistics.",
+    ),
+    (
+        "digest_interval_days",
+        "0 disables the periodic moderation digest, at most 365.",
+    ),
+    (
+        "identifier_mode",
+        "normal: undeclared identifiers fail filter evaluation.
+strict: undeclared identifiers are rejected by /set_filter.
+lenient: undeclared identifiers evaluate to empty.",
+    ),
+    (
+        "null_safe",
+        "operations on empty no longer fail: arithmetic and function calls give
+empty, comparisons and matches give false, logic treats empty as false.",
+    ),
+    (
+        "default_verdict",
+        "allow: messages are kept while no filter is set.
+deny: messages are deleted while no filter is set.",
+    ),
+    (
+        "max_delete_delay_seconds",
+        "delete filtered messages after a random delay of up to this many seconds
+(at most 300), so it is harder to tell which wording triggered the filter.
+0 deletes them immediately.",
+    ),
+    (
+        "max_evaluation_steps",
+        "abort evaluating an expression after this many steps, the error is reported
+when debug_evaluation is enabled. 0 removes the limit.",
+    ),
+    (
+        "rewrite_links",
+        "after deleting a filtered message, repost its text with links replaced by
+a placeholder to keep the discussion readable.",
+    ),
+    (
+        "public_stats",
+        "publish filtered and restored message counts of the last 30 days and the
+top filters on the status page, if the bot runs one.",
+    ),
+    (
+        "verbosity",
+        "quiet: no reports of filtered messages, invalid commands or successful
+commands, errors come without hints.
+normal: reports follow the report_* options.
+verbose: everything is reported, including what was done to filtered
+messages.",
+    ),
+    (
+        "fetch_profiles",
+        "fetch the profile of senders, cached for a day, to fill msg.from_has_bio,
+msg.from_bio and msg.from_has_photo. they are empty while this is disabled.",
+    ),
+    (
+        "check_reputation",
+        "look senders up, cached for 6 hours, in the list of known spammers the bot
+is configured with to fill msg.from_is_known_spammer. it is empty while this is
+disabled, if the bot has no list or if the list can't be reached.",
+    ),
+    (
+        "delete_command_messages",
+        "delete the commands administrators send in the chat once they are handled,
+so members don't see how the chat is moderated. replies are kept.",
+    ),
+    (
+        "command_prefix",
+        "1 to 3 symbols commands start with instead of /, like !, for chats where
+other bots have the same commands. /command@botname works whatever it is.",
+    ),
+    (
+        "apply_to_admins",
+        "evaluate filters for messages of administrators too. their messages are
+deleted, warned about or reported, but Telegram doesn't let bots mute or ban
+administrators.",
+    ),
+    (
+        "log_chat_id",
+        "also report filter matches, with the text of the message, and mutes and
+bans to this chat. it has to be a chat the bot knows you administer, or your
+private chat with the bot. 0 disables it.",
+    ),
+    (
+        "utc_offset_minutes",
+        "offset of the time zone of the chat from UTC in minutes, like 120 for UTC+2.
+filter schedules are in this time.",
+    ),
+    (
+        "flood_messages",
+        "apply flood_action to a user who sends more than this many messages in
+flood_window seconds, whatever the filters say. 0 disables it.",
+    ),
+    (
+        "flood_window",
+        "seconds flood_messages are counted over, at most 600.",
+    ),
+    (
+        "flood_action",
+        "delete, warn, mute <minutes>, ban or report. messages over the limit are
+deleted with delete, mute and ban; the warning, report, mute or ban only happen
+for the first one. with warn and report the messages are still filtered.",
+    ),
+    (
+        "appeal_button",
+        "attach an appeal button to the report of a deleted message while
+report_filtered is on. when the sender presses it, the text of the message and
+the filter that matched are sent to the log chat, or to this chat if there is
+none.",
+    ),
+];
+
+/// help_string with the commands in code, to be copied with a tap.
+fn help_html(bot_username: &str) -> String {
+    help_string(bot_username)
+        .lines()
+        .map(|line| {
+            if line.starts_with('/') || line.starts_with('@') {
+                html::code_inline(line)
+            } else {
+                html::escape(line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// HELP_STRING with the options and message variables listed from their
+/// derives.
+fn help_string(bot_username: &str) -> String {
+    let options: Vec<String> = <Settings as SetFromAssignment>::VARIABLE_NAMES
+        .iter()
+        .map(|(name, ty)| {
+            match OPTION_DESCRIPTIONS
+                .iter()
+                .find(|(option, _)| option == name)
+            {
+                Some((_, description)) => {
+                    format!("- {name}: {ty}\n  {}", description.replace('\n', "\n  "))
+                }
+                None => format!("- {name}: {ty}"),
+            }
+        })
+        .collect();
+    let message_variables: Vec<String> = MessageVariables::variable_names()
+        .into_iter()
+        .map(|(name, ty)| format!("- {name}: {ty}"))
+        .collect();
+
+    HELP_STRING
+        .replace("{options}", &options.join("\n"))
+        .replace("{message_variables}", &message_variables.join("\n"))
+        .replace("{bot_username}", bot_username)
+}
+
+const DEFAULT_FILTER_NAME: &str = "default";
+/// Recorded as the filter name when a message is deleted by default_verdict.
+const DEFAULT_VERDICT_FILTER_NAME: &str = "default_verdict";
+/// Recorded as the filter name when flood_action is applied to a message.
+const FLOOD_FILTER_NAME: &str = "flood";
+/// Recorded as the filter name when a message links to a blocked domain.
+const BLOCKED_DOMAIN_FILTER_NAME: &str = "blocked_domain";
+/// Recorded as the filter name when a forward from a blocked origin is deleted.
+const BLOCKED_ORIGIN_FILTER_NAME: &str = "blocked_forward_source";
+/// Recorded as the filter name when a message with blocked media is deleted.
+const BLOCKED_MEDIA_FILTER_NAME: &str = "blocked_media";
+const PROJECT_STATS_DAYS: i64 = 7;
+const STATS_DAYS: i64 = 7;
+const MAX_STATS_DAYS: i64 = 365;
+const MODERATION_LOG_COUNT: i64 = 10;
+const MAX_MODERATION_LOG_COUNT: i64 = 100;
+/// Deleted messages remembered per chat for their senders to appeal.
+const MAX_APPEALS: usize = 50;
+/// Administrators are fetched again after five minutes, so a promotion or a
+/// demotion is noticed without a request per message.
+const ADMINS_TTL: Duration = Duration::from_secs(300);
+/// Most commands run from a single message, the rest are skipped.
+const MAX_BATCH_COMMANDS: usize = 20;
+/// Longest filter text, a filter set with a command can't be longer than a
+/// Telegram message.
+const MAX_FILTER_LENGTH: usize = 4096;
+
 pub enum SendUpdate {
-    Message(String),
+    Message {
+        text: String,
+        /// Message it is a reply to, if it is still there.
+        reply_to: Option<MessageId>,
+        /// `None` for plain text.
+        parse_mode: Option<ParseMode>,
+    },
+    MessageWithButtons {
+        text: String,
+        buttons: Vec<(String, String)>,
+        /// Message it is a reply to, if it is still there.
+        reply_to: Option<MessageId>,
+    },
     DeleteMessage(MessageId),
+    /// Forward a message of the chat `from_chat_id`.
+    ForwardMessage {
+        from_chat_id: ChatId,
+        message_id: MessageId,
+    },
+    Document {
+        file_name: String,
+        content: String,
+    },
+    /// Limit a user in the chat to `permissions` for `duration`.
+    RestrictUser {
+        user_id: UserId,
+        duration: Duration,
+        permissions: ChatPermissions,
+    },
+    /// Ban a user from the chat for `duration`, or forever.
+    BanUser {
+        user_id: UserId,
+        duration: Option<Duration>,
+    },
+    /// Lift the ban of a user, who can join again.
+    UnbanUser(UserId),
+    DelayedDeleteMessage {
+        message_id: MessageId,
+        delay: Duration,
+    },
+    /// Report of a moderation action for the log chat of the chat.
+    Log {
+        chat_id: ChatId,
+        text: String,
+        buttons: Vec<(String, String)>,
+    },
+    /// Replace the text of a message of the bot, and its buttons with
+    /// `buttons`. Without buttons the message keeps none.
+    EditMessage {
+        message_id: MessageId,
+        text: String,
+        buttons: Vec<(String, String)>,
+    },
+    /// Answer to the button press being handled, shown as a notification or,
+    /// with `alert`, as a dialog to dismiss.
+    AnswerCallback {
+        text: String,
+        alert: bool,
+    },
+    /// An update for another chat than the one it was produced for.
+    ForChat {
+        chat_id: ChatId,
+        update: Box<SendUpdate>,
+    },
+    /// Drop the session of the chat so it is reloaded from the database.
+    ReloadChat(ChatId),
+}
+
+impl SendUpdate {
+    pub fn message(text: String) -> Self {
+        SendUpdate::Message {
+            text,
+            reply_to: None,
+            parse_mode: None,
+        }
+    }
+
+    /// Message in Telegram's HTML, anything from users in it has to be
+    /// escaped with `teloxide::utils::html`.
+    pub fn html(text: String) -> Self {
+        SendUpdate::Message {
+            text,
+            reply_to: None,
+            parse_mode: Some(ParseMode::Html),
+        }
+    }
 }
 
 pub struct Session {
@@ -65,174 +675,389 @@ pub struct Session {
     db: Arc<Mutex<Db>>,
     expression_parser: ExpressionParser,
     assignment_parser: AssignmentParser,
+    assignments_parser: AssignmentsParser,
     identifier_parser: IdentifierParser,
     chat: Chat,
+    /// Enabled filters in evaluation order.
+    compiled_filters: Vec<(String, Result<CompiledExpression, EvaluationError>)>,
+    pending_check: Option<Variables>,
+    parse_warnings: Vec<String>,
+    events: EventWindows,
+    /// Users known to have written in the chat before.
+    seen_users: HashSet<i64>,
+    recent_texts: RecentTexts,
+    /// Deleted messages whose senders can still appeal, oldest first.
+    appeals: VecDeque<Appeal>,
+    /// When `chat.admin_ids` was last set from getChatAdministrators.
+    admins_fetched: Option<Instant>,
     last_active: Instant,
 }
 
-#[derive(Debug, Clone, ToVariables, ContainsVariable)]
-struct MessageVariables {
-    has_from: bool,
+/// A deleted message its sender can appeal.
+struct Appeal {
+    message_id: MessageId,
+    user_id: i64,
+    /// Report for the admins once the sender appeals.
+    text: String,
+}
+
+#[derive(Debug, Clone, Default, ToVariables)]
+#[variables(namespace = "chat")]
+struct ChatVariables {
+    id: i64,
+    title: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+struct SenderVariables {
+    id: Option<i64>,
+    is_bot: Option<bool>,
+    username: Option<String>,
+    has_username: Option<bool>,
+    is_premium: Option<bool>,
+    is_trusted: Option<bool>,
+    /// Profile details, only known when fetch_profiles is enabled.
+    has_bio: Option<bool>,
+    bio: Option<String>,
+    has_photo: Option<bool>,
+    /// Only known when check_reputation is enabled.
+    is_known_spammer: Option<bool>,
+}
+
+impl SenderVariables {
+    fn set_profile(&mut self, profile: &Profile) {
+        self.has_bio = Some(profile.bio.is_some());
+        self.bio = profile.bio.clone();
+        self.has_photo = Some(profile.has_photo);
+    }
+}
+
+#[derive(Debug, Clone, Copy, ToVariables, ContainsVariable)]
+enum OriginType {
+    User,
+    HiddenUser,
+    Chat,
+    Channel,
+}
+
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+struct OriginVariables {
+    #[to_variables(rename = "type")]
+    kind: Option<OriginType>,
+    user_id: Option<i64>,
+    user_is_bot: Option<bool>,
+    user_username: Option<String>,
+    hidden_user_username: Option<String>,
+    chat_id: Option<i64>,
+    chat_author_signature: Option<String>,
+    channel_id: Option<i64>,
+    channel_message_id: Option<i64>,
+    channel_author_signature: Option<String>,
+}
+
+impl From<&MessageOrigin> for OriginVariables {
+    fn from(value: &MessageOrigin) -> Self {
+        let mut result = OriginVariables::default();
+
+        match value {
+            MessageOrigin::User {
+                date: _,
+                sender_user,
+            } => {
+                result.kind = Some(OriginType::User);
+                result.user_id = Some(sender_user.id.0 as i64);
+                result.user_is_bot = Some(sender_user.is_bot);
+                if let Some(username) = &sender_user.username {
+                    result.user_username = Some(username.to_string());
+                }
+            }
+            MessageOrigin::HiddenUser {
+                date: _,
+                sender_user_name,
+            } => {
+                result.kind = Some(OriginType::HiddenUser);
+                result.hidden_user_username = Some(sender_user_name.to_string());
+            }
+            MessageOrigin::Chat {
+                date: _,
+                sender_chat,
+                author_signature,
+            } => {
+                result.kind = Some(OriginType::Chat);
+                result.chat_id = Some(sender_chat.id.0);
+                if let Some(signature) = author_signature {
+                    result.chat_author_signature = Some(signature.to_string());
+                }
+            }
+            MessageOrigin::Channel {
+                date: _,
+                chat,
+                message_id,
+                author_signature,
+            } => {
+                result.kind = Some(OriginType::Channel);
+                result.channel_id = Some(chat.id.0);
+                result.channel_message_id = Some(message_id.0 as i64);
+                if let Some(signature) = author_signature {
+                    result.channel_author_signature = Some(signature.to_string());
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+struct MediaVariables {
+    audio: bool,
+    document: bool,
+    animation: bool,
+    game: bool,
+    photo: bool,
+    sticker: bool,
+    story: bool,
+    video: bool,
+    voice: bool,
+    video_note: bool,
+    contact: bool,
+    location: bool,
+    venue: bool,
+    poll: bool,
+    dice: bool,
+    media_spoiler: bool,
+}
+
+impl From<&Message> for MediaVariables {
+    fn from(value: &Message) -> Self {
+        MediaVariables {
+            audio: value.audio().is_some(),
+            document: value.document().is_some(),
+            animation: value.animation().is_some(),
+            game: value.game().is_some(),
+            photo: value.photo().is_some(),
+            sticker: value.sticker().is_some(),
+            story: value.story().is_some(),
+            video: value.video().is_some(),
+            voice: value.voice().is_some(),
+            video_note: value.video_note().is_some(),
+            contact: value.contact().is_some(),
+            location: value.location().is_some(),
+            venue: value.venue().is_some(),
+            poll: value.poll().is_some(),
+            dice: value.dice().is_some(),
+            media_spoiler: value.has_media_spoiler(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+struct DocumentVariables {
+    mime_type: Option<String>,
+    file_name: Option<String>,
+    file_size: Option<i64>,
+}
+
+impl From<&Document> for DocumentVariables {
+    fn from(value: &Document) -> Self {
+        DocumentVariables {
+            mime_type: value.mime_type.as_ref().map(|mime| mime.to_string()),
+            file_name: value.file_name.clone(),
+            file_size: Some(value.file.size as i64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ToVariables, ContainsVariable)]
+enum ChatType {
+    Private,
+    Group,
+    Supergroup,
+    Channel,
+}
+
+impl From<&teloxide::types::Chat> for ChatType {
+    fn from(value: &teloxide::types::Chat) -> Self {
+        if value.is_channel() {
+            ChatType::Channel
+        } else if value.is_supergroup() {
+            ChatType::Supergroup
+        } else if value.is_group() {
+            ChatType::Group
+        } else {
+            ChatType::Private
+        }
+    }
+}
+
+/// Chat a message is sent on behalf of, the group itself for anonymous
+/// admins or a channel.
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+struct SenderChatVariables {
+    id: Option<i64>,
+    #[to_variables(rename = "type")]
+    kind: Option<ChatType>,
+    username: Option<String>,
+}
+
+impl From<&teloxide::types::Chat> for SenderChatVariables {
+    fn from(value: &teloxide::types::Chat) -> Self {
+        SenderChatVariables {
+            id: Some(value.id.0),
+            kind: Some(ChatType::from(value)),
+            username: value.username().map(|username| username.to_string()),
+        }
+    }
+}
+
+/// Dimensions and size of the largest size of a photo.
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+struct PhotoVariables {
+    width: Option<i64>,
+    height: Option<i64>,
+    file_size: Option<i64>,
+}
+
+impl From<&[PhotoSize]> for PhotoVariables {
+    fn from(value: &[PhotoSize]) -> Self {
+        match value.iter().max_by_key(|size| size.width * size.height) {
+            Some(size) => PhotoVariables {
+                width: Some(size.width as i64),
+                height: Some(size.height as i64),
+                file_size: Some(size.file.size as i64),
+            },
+            None => PhotoVariables::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+struct ReplyVariables {
     from_id: Option<i64>,
     from_is_bot: Option<bool>,
-    from_username: Option<String>,
-    from_is_premium: Option<bool>,
-    has_origin: bool,
-    origin_type: Option<String>,
-    origin_user_id: Option<i64>,
-    origin_user_is_bot: Option<bool>,
-    origin_user_username: Option<String>,
-    origin_hidden_user_username: Option<String>,
-    origin_chat_id: Option<i64>,
-    origin_chat_author_signature: Option<String>,
-    origin_channel_id: Option<i64>,
-    origin_channel_message_id: Option<i64>,
-    origin_channel_author_signature: Option<String>,
     has_text: bool,
     text: Option<String>,
-    has_audio: bool,
-    has_document: bool,
-    has_animation: bool,
-    has_game: bool,
-    has_photo: bool,
-    has_sticker: bool,
-    has_story: bool,
-    has_video: bool,
-    has_voice: bool,
-    has_caption: bool,
-    caption: Option<String>,
 }
 
-impl Default for MessageVariables {
-    fn default() -> Self {
-        MessageVariables {
-            has_from: false,
-            from_id: None,
-            from_is_bot: None,
-            from_username: None,
-            from_is_premium: None,
-            has_origin: false,
-            origin_type: None,
-            origin_user_id: None,
-            origin_user_is_bot: None,
-            origin_user_username: None,
-            origin_hidden_user_username: None,
-            origin_chat_id: None,
-            origin_chat_author_signature: None,
-            origin_channel_id: None,
-            origin_channel_message_id: None,
-            origin_channel_author_signature: None,
-            has_text: false,
-            text: None,
-            has_audio: false,
-            has_document: false,
-            has_animation: false,
-            has_game: false,
-            has_photo: false,
-            has_sticker: false,
-            has_story: false,
-            has_video: false,
-            has_voice: false,
-            has_caption: false,
-            caption: None,
+impl From<&Message> for ReplyVariables {
+    fn from(value: &Message) -> Self {
+        let text = value.text().or_else(|| value.caption());
+        ReplyVariables {
+            from_id: value.from.as_ref().map(|from| from.id.0 as i64),
+            from_is_bot: value.from.as_ref().map(|from| from.is_bot),
+            has_text: text.is_some(),
+            text: text.map(|text| text.to_string()),
         }
     }
 }
 
+#[derive(Debug, Clone, Default, ToVariables, ContainsVariable)]
+#[variables(namespace = "msg")]
+struct MessageVariables {
+    date: DateTime<Utc>,
+    has_from: bool,
+    from: SenderVariables,
+    has_sender_chat: bool,
+    sender_chat: SenderChatVariables,
+    has_origin: bool,
+    origin: OriginVariables,
+    forward_date: Option<DateTime<Utc>>,
+    /// Seconds between the original message and its forward.
+    forward_age_seconds: Option<i64>,
+    has_reply_to: bool,
+    reply_to: ReplyVariables,
+    has_quote: bool,
+    quote_text: Option<String>,
+    /// Whether this is the first message of the sender in the chat.
+    is_first_message: Option<bool>,
+    /// Messages of the sender in the chat, including this one.
+    messages_last_minute: Option<i64>,
+    messages_last_10s: Option<i64>,
+    /// Highest similarity of the text or caption to the recent ones of the
+    /// chat, 0 to 100.
+    similarity_to_recent: Option<i64>,
+    has_text: bool,
+    text: Option<String>,
+    has: MediaVariables,
+    document: DocumentVariables,
+    photo: PhotoVariables,
+    media_group_id: Option<String>,
+    has_new_chat_members: bool,
+    new_members_count: i64,
+    /// Whether any of the new members is a bot.
+    new_member_is_bot: bool,
+    has_left_chat_member: bool,
+    has_caption: bool,
+    caption: Option<String>,
+    has_url: bool,
+    url_count: i64,
+    mention_count: i64,
+    hashtag_count: i64,
+    urls: Vec<String>,
+    domains: Vec<String>,
+}
+
 impl From<&Message> for MessageVariables {
     fn from(value: &Message) -> Self {
-        let mut result = MessageVariables::default();
+        let mut result = MessageVariables {
+            date: value.date,
+            has: MediaVariables::from(value),
+            media_group_id: value.media_group_id().map(|id| id.to_string()),
+            ..Default::default()
+        };
 
         if let Some(from) = &value.from {
             result.has_from = true;
-            result.from_id = Some(from.id.0 as i64);
-            result.from_is_bot = Some(from.is_bot);
-            if let Some(username) = &from.username {
-                result.from_username = Some(username.to_string());
-            }
-            result.from_is_premium = Some(from.is_premium);
+            result.from = SenderVariables {
+                id: Some(from.id.0 as i64),
+                is_bot: Some(from.is_bot),
+                username: from.username.as_ref().map(|username| username.to_string()),
+                has_username: Some(from.username.is_some()),
+                is_premium: Some(from.is_premium),
+                ..Default::default()
+            };
         }
 
-        if let Some(origin) = &value.forward_origin() {
-            result.has_origin = true;
-
-            match origin {
-                MessageOrigin::User {
-                    date: _,
-                    sender_user,
-                } => {
-                    result.origin_type = Some("user".to_string());
-                    result.origin_user_id = Some(sender_user.id.0 as i64);
-                    result.origin_user_is_bot = Some(sender_user.is_bot);
-                    if let Some(username) = &sender_user.username {
-                        result.origin_user_username = Some(username.to_string());
-                    }
-                }
-                MessageOrigin::HiddenUser {
-                    date: _,
-                    sender_user_name,
-                } => {
-                    result.origin_type = Some("hidden_user".to_string());
-                    result.origin_hidden_user_username = Some(sender_user_name.to_string());
-                }
-                MessageOrigin::Chat {
-                    date: _,
-                    sender_chat,
-                    author_signature,
-                } => {
-                    result.origin_type = Some("chat".to_string());
-                    result.origin_chat_id = Some(sender_chat.id.0 as i64);
-                    if let Some(signature) = author_signature {
-                        result.origin_chat_author_signature = Some(signature.to_string());
-                    }
-                }
-                MessageOrigin::Channel {
-                    date: _,
-                    chat,
-                    message_id,
-                    author_signature,
-                } => {
-                    result.origin_type = Some("channel".to_string());
-                    result.origin_channel_id = Some(chat.id.0 as i64);
-                    result.origin_channel_message_id = Some(message_id.0 as i64);
-                    if let Some(signature) = author_signature {
-                        result.origin_channel_author_signature = Some(signature.to_string());
-                    }
-                }
-            }
+        if let Some(sender_chat) = &value.sender_chat {
+            result.has_sender_chat = true;
+            result.sender_chat = SenderChatVariables::from(sender_chat);
         }
 
-        if let Some(text) = value.text() {
-            result.has_text = true;
-            result.text = Some(text.to_string());
+        if let Some(origin) = value.forward_origin() {
+            result.has_origin = true;
+            result.origin = OriginVariables::from(origin);
+            result.forward_date = Some(origin.date());
+            result.forward_age_seconds = Some((value.date - origin.date()).num_seconds());
         }
 
-        if value.audio().is_some() {
-            result.has_audio = true;
-        }
-        if value.document().is_some() {
-            result.has_document = true;
-        }
-        if value.animation().is_some() {
-            result.has_animation = true;
+        if let Some(document) = value.document() {
+            result.document = DocumentVariables::from(document);
         }
-        if value.game().is_some() {
-            result.has_game = true;
-        }
-        if value.photo().is_some() {
-            result.has_photo = true;
+
+        if let Some(photo) = value.photo() {
+            result.photo = PhotoVariables::from(photo);
         }
-        if value.sticker().is_some() {
-            result.has_sticker = true;
+
+        if let Some(quote) = value.quote() {
+            result.has_quote = true;
+            result.quote_text = Some(quote.text.clone());
         }
-        if value.story().is_some() {
-            result.has_story = true;
+
+        if let Some(members) = value.new_chat_members() {
+            result.has_new_chat_members = true;
+            result.new_members_count = members.len() as i64;
+            result.new_member_is_bot = members.iter().any(|member| member.is_bot);
         }
-        if value.video().is_some() {
-            result.has_video = true;
+        result.has_left_chat_member = value.left_chat_member().is_some();
+
+        if let Some(reply_to) = value.reply_to_message() {
+            result.has_reply_to = true;
+            result.reply_to = ReplyVariables::from(reply_to);
         }
-        if value.voice().is_some() {
-            result.has_voice = true;
+
+        if let Some(text) = value.text() {
+            result.has_text = true;
+            result.text = Some(text.to_string());
         }
 
         if let Some(caption) = value.caption() {
@@ -240,6 +1065,30 @@ impl From<&Message> for MessageVariables {
             result.caption = Some(caption.to_string());
         }
 
+        let entities = value
+            .entities()
+            .into_iter()
+            .chain(value.caption_entities())
+            .flatten();
+        for entity in entities {
+            match entity.kind {
+                MessageEntityKind::Url | MessageEntityKind::TextLink { .. } => {
+                    result.url_count += 1
+                }
+                MessageEntityKind::Mention | MessageEntityKind::TextMention { .. } => {
+                    result.mention_count += 1
+                }
+                MessageEntityKind::Hashtag => result.hashtag_count += 1,
+                _ => {}
+            }
+        }
+        result.has_url = result.url_count > 0;
+
+        result.urls = message_urls(value);
+        result.domains = result.urls.iter().filter_map(|url| domain(url)).collect();
+        result.domains.sort_unstable();
+        result.domains.dedup();
+
         result
     }
 }
@@ -251,16 +1100,42 @@ impl Session {
         bot_username: String,
     ) -> Result<Self, Box<dyn Error>> {
         let db_lock = db.lock().await;
-        let chat = db_lock.find_chat_by_id(chat_id.0).await?;
+        let mut chat = db_lock.find_chat_by_id(chat_id.0).await?;
         drop(db_lock);
+
+        let expression_parser = ExpressionParser::new();
+        let mut parse_warnings = Vec::new();
+        for filter in chat.filters.iter_mut().filter(|filter| filter.is_stale()) {
+            match expression_parser.parse(&filter.text) {
+                Ok(expression) => *filter = filter.reparsed(*expression),
+                Err(e) => {
+                    let warning = format!(
+                        "failed to re-parse filter {} written for language version {}: {e}",
+                        filter.name, filter.language_version
+                    );
+                    log::warn!("Filter of {chat_id}: {warning}");
+                    parse_warnings.push(warning);
+                }
+            }
+        }
+
         Ok(Session {
             chat_id,
             bot_username,
             db,
-            expression_parser: ExpressionParser::new(),
+            expression_parser,
             assignment_parser: AssignmentParser::new(),
+            assignments_parser: AssignmentsParser::new(),
             identifier_parser: IdentifierParser::new(),
+            compiled_filters: chat.compile_filters(),
             chat,
+            pending_check: None,
+            parse_warnings,
+            events: EventWindows::new(),
+            seen_users: HashSet::new(),
+            recent_texts: RecentTexts::new(),
+            appeals: VecDeque::new(),
+            admins_fetched: None,
             last_active: Instant::now(),
         })
     }
@@ -282,112 +1157,490 @@ impl Session {
         false
     }
 
-    pub async fn handle_message(
-        &mut self,
-        message: Message,
-        from_admin: bool,
-    ) -> Result<Vec<SendUpdate>, Box<dyn Error + Send + Sync>> {
+    /// Whether `handle_message` should be given the administrators of the
+    /// chat, they are refreshed every ADMINS_TTL.
+    pub fn fetches_admins(&self) -> bool {
+        self.admins_fetched
+            .is_none_or(|fetched| fetched.elapsed() >= ADMINS_TTL)
+    }
+
+    /// Whether the user was an administrator of the chat when its
+    /// administrators were last fetched.
+    pub fn is_known_admin(&self, user_id: UserId) -> bool {
+        self.chat.admin_ids.contains(&(user_id.0 as i64))
+    }
+
+    /// Whether `handle_message` should be given the profile of the sender.
+    pub fn fetches_profiles(&self) -> bool {
+        self.chat.settings.fetch_profiles
+    }
+
+    /// Whether `handle_message` should be told if the sender is a known
+    /// spammer.
+    pub fn checks_reputation(&self) -> bool {
+        self.chat.settings.check_reputation
+    }
+
+    pub async fn handle_message(
+        &mut self,
+        message: Message,
+        admin_ids: Option<&[UserId]>,
+        from_owner: bool,
+        profile: Option<&Profile>,
+        is_known_spammer: Option<bool>,
+        document: Option<&str>,
+    ) -> Result<Vec<SendUpdate>, Box<dyn Error + Send + Sync>> {
         self.refresh();
+        self.update_chat_info(&message, admin_ids);
+        let from_admin = message.chat.is_private()
+            || message
+                .from
+                .as_ref()
+                .is_some_and(|from| self.is_known_admin(from.id));
+        self.record_events(&message);
+        let is_first_message = self.mark_user_seen(&message).await;
+        let similarity_to_recent = message
+            .text()
+            .or_else(|| message.caption())
+            .map(|text| self.recent_texts.similarity_and_record(text));
 
         let mut result = Vec::with_capacity(5);
 
+        let expired = self.chat.expire_filters(message.date.timestamp());
+        if !expired.is_empty() {
+            self.compiled_filters = self.chat.compile_filters();
+        }
+        for name in expired {
+            let text = format!("filter {name} expired and was disabled");
+            result.extend(self.log_update(text.clone()));
+            if self.chat.settings.should_report_actions() {
+                result.push(SendUpdate::message(text));
+            }
+        }
+
+        for warning in std::mem::take(&mut self.parse_warnings) {
+            if self.chat.settings.debug_parse {
+                result.push(SendUpdate::message(format!("warning: {warning}")));
+            }
+        }
+
+        let mut command_texts = message
+            .text()
+            .map(|text| split_commands(text, &self.chat.settings.command_prefix))
+            .unwrap_or_default();
+        let is_batch = command_texts.len() > 1;
+        let skipped_commands = command_texts.len().saturating_sub(MAX_BATCH_COMMANDS);
+        command_texts.truncate(MAX_BATCH_COMMANDS);
+        let mut batch_report = Vec::new();
+        // Answers to a batch without a single command, sent as they are.
+        let mut batch_answers = Vec::new();
+        let mut batch_has_command = false;
         let mut is_valid_command = false;
         let mut command_failed = false;
         let mut command_requires_success_report = false;
-        match message.text() {
-            Some(text) => match Command::new(text, &self.bot_username) {
+        for (index, text) in command_texts.into_iter().enumerate() {
+            let was_valid_command = std::mem::replace(&mut is_valid_command, false);
+            command_failed = false;
+            let first_update = result.len();
+            match Command::new(
+                text,
+                &self.bot_username,
+                &self.chat.settings.command_prefix,
+                &self.chat.aliases,
+            ) {
                 Ok(command) => {
                     if let Some(command) = command {
-                        if command.requires_admin_rights() && !from_admin {
-                            result.push(SendUpdate::Message(format!("error: permission denied")))
+                        batch_has_command = true;
+                        if (command.requires_admin_rights() && !from_admin)
+                            || (command.requires_owner_rights() && !from_owner)
+                        {
+                            result.push(SendUpdate::message(format!(
+                                "error [{PERMISSION_DENIED}]: permission denied"
+                            )))
                         } else {
                             is_valid_command = true;
                             match command {
                                 Command::SetFilter(arg) => {
                                     command_requires_success_report = true;
 
-                                    match self.expression_parser.parse(&arg) {
-                                        Ok(expression) => {
-                                            self.chat.filter =
-                                                Some(Filter::new(arg.clone(), *expression))
+                                    match self.add_filter(&message, DEFAULT_FILTER_NAME, &arg) {
+                                        Ok(warnings) => {
+                                            for warning in warnings {
+                                                result.push(SendUpdate::message(format!(
+                                                    "warning: {warning}"
+                                                )));
+                                            }
                                         }
                                         Err(e) => {
                                             command_failed = true;
-                                            result.push(SendUpdate::Message(format!(
-                                                "parse error: {e}"
-                                            )))
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
+                                }
+                                Command::AddFilter(arg) => {
+                                    command_requires_success_report = true;
+
+                                    let added = match split_first_word(&arg, char::is_whitespace) {
+                                        (name, Some(text)) => self.add_filter(&message, name, text),
+                                        (_, None) => Err(format!(
+                                            "error [{INVALID_ARGUMENTS}]: expected <name> <expr>"
+                                        )),
+                                    };
+                                    match added {
+                                        Ok(warnings) => {
+                                            for warning in warnings {
+                                                result.push(SendUpdate::message(format!(
+                                                    "warning: {warning}"
+                                                )));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
+                                }
+                                Command::RemoveFilter(name) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.remove_filter(&message, &name) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::FilterHistory => {
+                                    if self.chat.filter_history.is_empty() {
+                                        result.push(SendUpdate::message(
+                                            "no filter changes".to_string(),
+                                        ));
+                                    } else {
+                                        let versions: Vec<String> = self
+                                            .chat
+                                            .filter_history
+                                            .iter()
+                                            .rev()
+                                            .enumerate()
+                                            .map(|(i, version)| format!("{}. {version}", i + 1))
+                                            .collect();
+                                        result.push(SendUpdate::message(versions.join("\n")));
+                                    }
+                                }
+                                Command::RevertFilter(arg) => {
+                                    command_requires_success_report = true;
+
+                                    match self.revert_filter(&message, arg.as_deref()) {
+                                        Ok(warnings) => {
+                                            for warning in warnings {
+                                                result.push(SendUpdate::message(format!(
+                                                    "warning: {warning}"
+                                                )));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
+                                }
+                                Command::EnableFilter(name) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_filter_enabled(&name, true) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::DisableFilter(name) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_filter_enabled(&name, false) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::ShadowFilter(name) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_filter_shadow(&name, true) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::EnforceFilter(name) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_filter_shadow(&name, false) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::SetFilterAction(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_filter_action(&arg) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::SetFilterSchedule(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_filter_schedule(&arg) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::SetFilterExpiry(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_filter_expiry(&arg, message.date) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::ListPresets => {
+                                    let presets: Vec<String> = PRESETS
+                                        .iter()
+                                        .map(|preset| {
+                                            format!(
+                                                "- {}: {}\n  {}",
+                                                preset.name, preset.description, preset.text
+                                            )
+                                        })
+                                        .collect();
+                                    result.push(SendUpdate::message(presets.join("\n")));
+                                }
+                                Command::UsePreset(name) => {
+                                    command_requires_success_report = true;
+
+                                    let added = match presets::find(&name) {
+                                        Some(preset) => {
+                                            self.add_filter(&message, preset.name, preset.text)
+                                        }
+                                        None => Err(format!(
+                                            "error [{NOT_FOUND}]: no preset named \"{name}\""
+                                        )),
+                                    };
+                                    match added {
+                                        Ok(warnings) => {
+                                            for warning in warnings {
+                                                result.push(SendUpdate::message(format!(
+                                                    "warning: {warning}"
+                                                )));
+                                            }
+                                        }
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
                                         }
                                     }
                                 }
-                                Command::GetFilter => match &self.chat.filter {
-                                    Some(filter) => {
-                                        result.push(SendUpdate::Message(filter.text.clone()));
+                                Command::ListFilters => {
+                                    if self.chat.filters.is_empty() {
+                                        result
+                                            .push(SendUpdate::message("no filter set".to_string()));
+                                    } else {
+                                        let filters: Vec<String> = self
+                                            .chat
+                                            .filters
+                                            .iter()
+                                            .enumerate()
+                                            .map(|(i, filter)| {
+                                                format!(
+                                                    "{}. {}: {}",
+                                                    i + 1,
+                                                    self.describe_filter(filter),
+                                                    filter.text
+                                                )
+                                            })
+                                            .collect();
+                                        result.push(SendUpdate::message(filters.join("\n")));
                                     }
-                                    None => {
+                                }
+                                Command::LintFilter => {
+                                    if self.chat.filters.is_empty() {
                                         command_failed = true;
                                         result
-                                            .push(SendUpdate::Message("no filter set".to_string()));
+                                            .push(SendUpdate::message("no filter set".to_string()));
+                                    } else {
+                                        let mut found_problems = false;
+                                        for filter in &self.chat.filters {
+                                            for warning in self.lint(&filter.expression) {
+                                                found_problems = true;
+                                                result.push(SendUpdate::message(format!(
+                                                    "warning: {}: {warning}",
+                                                    filter.name
+                                                )));
+                                            }
+                                        }
+                                        if !found_problems {
+                                            result.push(SendUpdate::message(
+                                                "no problems found".to_string(),
+                                            ));
+                                        }
                                     }
-                                },
+                                }
+                                Command::GetFilter(name) => {
+                                    let name = name.as_deref().unwrap_or(DEFAULT_FILTER_NAME);
+                                    match self.chat.filter(name) {
+                                        Some(filter) => {
+                                            result.push(SendUpdate::html(html::code_block(
+                                                &filter.text,
+                                            )));
+                                        }
+                                        None => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(format!(
+                                                "error [{NOT_FOUND}]: no filter named \"{name}\""
+                                            )));
+                                        }
+                                    }
+                                }
                                 Command::SetOption(arg) => {
                                     command_requires_success_report = true;
 
-                                    match self.assignment_parser.parse(&arg) {
-                                        Ok(assignment) => {
-                                            if let Err(e) = self.chat.settings.set_from_assignment(
-                                                &assignment,
-                                                &self.chat.variables,
-                                            ) {
-                                                command_failed = true;
-                                                result.push(SendUpdate::Message(format!(
-                                                    "failed to set option: {e}"
-                                                )));
+                                    match self.assignments_parser.parse(&arg) {
+                                        Ok(assignments) => {
+                                            let mut settings = self.chat.settings.clone();
+                                            let user_variables = self.user_variables();
+                                            match assignments.iter().try_for_each(|assignment| {
+                                                settings.set_from_assignment(
+                                                    assignment,
+                                                    &user_variables,
+                                                )
+                                            }) {
+                                                Ok(_)
+                                                    if settings.log_chat_id
+                                                        != self.chat.settings.log_chat_id
+                                                        && settings.log_chat_id != 0
+                                                        && !self
+                                                            .administers(
+                                                                &message,
+                                                                settings.log_chat_id,
+                                                            )
+                                                            .await =>
+                                                {
+                                                    command_failed = true;
+                                                    result.push(SendUpdate::message(format!(
+                                                        "error [{PERMISSION_DENIED}]: you don't administer chat {}",
+                                                        settings.log_chat_id
+                                                    )));
+                                                }
+                                                Ok(_) => self.chat.settings = settings,
+                                                Err(e) => {
+                                                    command_failed = true;
+                                                    result.push(SendUpdate::message(format!(
+                                                        "failed to set option [{}]: {e}",
+                                                        e.number()
+                                                    )));
+                                                }
                                             }
                                         }
                                         Err(e) => {
                                             command_failed = true;
-                                            result.push(SendUpdate::Message(format!(
-                                                "parse error: {e}"
+                                            result.push(SendUpdate::message(format!(
+                                                "parse error [{}]: {e}",
+                                                parse_error::number(&e)
                                             )))
                                         }
                                     }
                                 }
                                 Command::GetOptions => {
                                     let variables = Variables::from(self.chat.settings.clone());
-                                    result.push(SendUpdate::Message(variables.show(false)));
+                                    result.push(SendUpdate::html(html::code_block(
+                                        &variables.show(false),
+                                    )));
+                                }
+                                Command::Alias(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_alias(&arg) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::Unalias(name) => {
+                                    command_requires_success_report = true;
+
+                                    let name = name.trim().trim_start_matches('/');
+                                    if self.chat.aliases.remove(name).is_none() {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(format!(
+                                            "error [{NOT_FOUND}]: no alias named \"{name}\""
+                                        )));
+                                    }
                                 }
+                                Command::ListAliases => {
+                                    let mut aliases: Vec<String> = self
+                                        .chat
+                                        .aliases
+                                        .iter()
+                                        .map(|(name, command)| format!("/{name}: {command}"))
+                                        .collect();
+                                    aliases.sort();
+                                    if aliases.is_empty() {
+                                        result.push(SendUpdate::message("no aliases".to_string()));
+                                    } else {
+                                        result.push(SendUpdate::message(aliases.join("\n")));
+                                    }
+                                }
+                                Command::SetFilteredMessage(template) => {
+                                    command_requires_success_report = true;
+
+                                    self.chat.filtered_message =
+                                        template.unwrap_or_default().trim().to_string();
+                                }
+                                Command::GetOption(arg) => match self.get_option(&arg) {
+                                    Ok(text) => result.push(SendUpdate::message(text)),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                },
                                 Command::SetVariable(arg) => {
                                     command_requires_success_report = true;
 
                                     match self.assignment_parser.parse(&arg) {
-                                        Ok(assignment) => {
-                                            if MessageVariables::default()
-                                                .contains_variable(&assignment.identifier)
-                                            {
-                                                result.push(SendUpdate::Message(format!(
-                                                    "failed to set variable: \"{}\" is reserved",
-                                                    assignment.identifier
-                                                )));
-
-                                                command_failed = true;
-                                            } else {
-                                                if let Err(e) =
-                                                    self.chat.variables.set_from_assignment(
-                                                        &assignment,
-                                                        &self.chat.variables.clone(),
-                                                    )
+                                        Ok(mut assignment) => {
+                                            match user_variable_name(&assignment.identifier) {
+                                                None => {
+                                                    command_failed = true;
+                                                    result.push(SendUpdate::message(format!(
+                                                        "failed to set variable [{INVALID_INPUT}]: \"{}\" is not in the user namespace",
+                                                        assignment.identifier
+                                                    )));
+                                                }
+                                                Some(name)
+                                                    if self.chat.definitions.contains(name) =>
                                                 {
                                                     command_failed = true;
-                                                    result.push(SendUpdate::Message(format!(
-                                                        "failed to set variable: {e}"
+                                                    result.push(SendUpdate::message(format!(
+                                                        "failed to set variable [{INVALID_INPUT}]: \"{name}\" is reserved"
                                                     )));
                                                 }
+                                                Some(name) => {
+                                                    assignment.identifier = name.to_string();
+                                                    let variables = self.user_variables();
+                                                    if let Err(e) =
+                                                        self.chat.variables.set_from_assignment(
+                                                            &assignment,
+                                                            &variables,
+                                                        )
+                                                    {
+                                                        command_failed = true;
+                                                        result.push(SendUpdate::message(format!(
+                                                            "failed to set variable [{}]: {e}",
+                                                            e.number()
+                                                        )));
+                                                    }
+                                                }
                                             }
                                         }
                                         Err(e) => {
                                             command_failed = true;
-                                            result.push(SendUpdate::Message(format!(
-                                                "parse error: {e}"
+                                            result.push(SendUpdate::message(format!(
+                                                "parse error [{}]: {e}",
+                                                parse_error::number(&e)
                                             )))
                                         }
                                     }
@@ -397,9 +1650,11 @@ impl Session {
 
                                     match self.identifier_parser.parse(&arg) {
                                         Ok(identifier) => {
-                                            if !self.chat.variables.remove(&identifier) {
-                                                result.push(SendUpdate::Message(format!(
-                                                    "variable \"{identifier}\" does not exist"
+                                            if !user_variable_name(&identifier).is_some_and(
+                                                |name| self.chat.variables.remove(name),
+                                            ) {
+                                                result.push(SendUpdate::message(format!(
+                                                    "error [{NOT_FOUND}]: variable \"{identifier}\" does not exist"
                                                 )));
 
                                                 command_failed = true;
@@ -407,113 +1662,3209 @@ impl Session {
                                         }
                                         Err(e) => {
                                             command_failed = true;
-                                            result.push(SendUpdate::Message(format!(
-                                                "parse error: {e}"
+                                            result.push(SendUpdate::message(format!(
+                                                "parse error [{}]: {e}",
+                                                parse_error::number(&e)
                                             )))
                                         }
                                     }
                                 }
                                 Command::GetVariables => {
-                                    if self.chat.variables.count() > 0 {
-                                        result.push(SendUpdate::Message(
-                                            self.chat.variables.show(false),
-                                        ));
+                                    if !self.chat.variables.is_empty() {
+                                        result.push(SendUpdate::html(html::code_block(
+                                            &self.chat.variables.show(false),
+                                        )));
                                     } else {
                                         command_failed = true;
-                                        result.push(SendUpdate::Message("no variables".to_string()))
+                                        result.push(SendUpdate::message("no variables".to_string()))
+                                    }
+                                }
+                                Command::ExportVariables => match self.chat.variables.to_json() {
+                                    Ok(json) => result.push(SendUpdate::message(json)),
+                                    Err(e) => {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(format!(
+                                            "error [{}]: {e}",
+                                            e.number()
+                                        )));
+                                    }
+                                },
+                                Command::ExportConfig => {
+                                    match serde_json::to_string_pretty(&self.chat.config()) {
+                                        Ok(content) => result.push(SendUpdate::Document {
+                                            file_name: format!("config_{}.json", self.chat_id),
+                                            content,
+                                        }),
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(format!(
+                                                "error [{REQUEST_FAILED}]: failed to export config: {e}"
+                                            )));
+                                        }
+                                    }
+                                }
+                                Command::ImportConfig(arg) => {
+                                    command_requires_success_report = true;
+
+                                    let imported = match arg.as_deref().or(document) {
+                                        Some(json) => self.import_config(json),
+                                        None => Err(
+                                            format!("error [{NO_REPLY_MESSAGE}]: reply to a config document or give the config"),
+                                        ),
+                                    };
+                                    if let Err(e) = imported {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::ImportVariables(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.import_variables(&arg) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(format!(
+                                            "failed to import variables [{}]: {e}",
+                                            e.number()
+                                        )));
                                     }
                                 }
                                 Command::GetMessageVariables => {
                                     if let Some(message) = message.reply_to_message() {
                                         let variables = MessageVariables::from(message);
                                         let variables = Variables::from(variables);
-                                        result.push(SendUpdate::Message(format!("{variables}")));
+                                        result.push(SendUpdate::message(format!("{variables}")));
                                     } else {
                                         command_failed = true;
-                                        result.push(SendUpdate::Message(
-                                            "error: no reply message".to_string(),
+                                        result.push(SendUpdate::message(format!(
+                                            "error [{NO_REPLY_MESSAGE}]: no reply message"
+                                        )));
+                                    }
+                                }
+                                Command::Define(arg) => {
+                                    command_requires_success_report = true;
+
+                                    match self.assignment_parser.parse(&arg) {
+                                        Ok(assignment) => {
+                                            let name = assignment.identifier;
+                                            if name.contains(NAMESPACE_SEPARATOR) {
+                                                command_failed = true;
+                                                result.push(SendUpdate::message(format!(
+                                                    "failed to define expression [{INVALID_INPUT}]: \"{name}\" is namespaced"
+                                                )));
+                                            } else if MessageVariables::default()
+                                                .contains_variable(&name)
+                                                || self.chat.variables.contains_variable(&name)
+                                            {
+                                                command_failed = true;
+                                                result.push(SendUpdate::message(format!(
+                                                    "failed to define expression [{INVALID_INPUT}]: \"{name}\" is a variable"
+                                                )));
+                                            } else {
+                                                let text = match arg.split_once(":=") {
+                                                    Some((_, text)) => text.trim().to_string(),
+                                                    None => arg.clone(),
+                                                };
+                                                let definition = Definition {
+                                                    text,
+                                                    expression: assignment.expression,
+                                                };
+                                                if let Err(e) =
+                                                    self.chat.definitions.put(name, definition)
+                                                {
+                                                    command_failed = true;
+                                                    result.push(SendUpdate::message(format!(
+                                                        "failed to define expression [{}]: {e}",
+                                                        e.number()
+                                                    )));
+                                                }
+                                            }
+                                        }
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(format!(
+                                                "parse error [{}]: {e}",
+                                                parse_error::number(&e)
+                                            )))
+                                        }
+                                    }
+                                }
+                                Command::Undefine(arg) => {
+                                    command_requires_success_report = true;
+
+                                    match self.identifier_parser.parse(&arg) {
+                                        Ok(identifier) => {
+                                            if !self.chat.definitions.remove(&identifier) {
+                                                result.push(SendUpdate::message(format!(
+                                                    "error [{NOT_FOUND}]: expression \"{identifier}\" is not defined"
+                                                )));
+
+                                                command_failed = true;
+                                            }
+                                        }
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(format!(
+                                                "parse error [{}]: {e}",
+                                                parse_error::number(&e)
+                                            )))
+                                        }
+                                    }
+                                }
+                                Command::GetDefinitions => {
+                                    if self.chat.definitions.count() > 0 {
+                                        result.push(SendUpdate::message(
+                                            self.chat.definitions.show(),
                                         ));
+                                    } else {
+                                        command_failed = true;
+                                        result
+                                            .push(SendUpdate::message("no definitions".to_string()))
                                     }
                                 }
                                 Command::Eval(arg) => match self.expression_parser.parse(&arg) {
                                     Ok(expression) => {
-                                        match evaluate(&expression, &self.chat.variables) {
+                                        let (evaluation, _) =
+                                            self.evaluate_for_message(&message, |sandbox| {
+                                                self.chat.evaluate(
+                                                    &expression,
+                                                    &self.user_variables(),
+                                                    sandbox,
+                                                )
+                                            });
+                                        match evaluation {
                                             Ok(value) => {
-                                                result.push(SendUpdate::Message(value.to_string()))
+                                                result.push(SendUpdate::message(value.to_string()))
                                             }
                                             Err(e) => {
                                                 command_failed = true;
-                                                result.push(SendUpdate::Message(format!(
-                                                    "error: failed to evalute expression: {e}"
+                                                result.push(SendUpdate::message(format!(
+                                                    "error [{}]: failed to evalute expression: {e}",
+                                                    e.number()
                                                 )));
                                             }
                                         }
                                     }
                                     Err(e) => {
                                         command_failed = true;
-                                        result
-                                            .push(SendUpdate::Message(format!("parse error: {e}")))
+                                        result.push(SendUpdate::message(format!(
+                                            "parse error [{}]: {e}",
+                                            parse_error::number(&e)
+                                        )))
                                     }
                                 },
-                                Command::Help => {
-                                    result.push(SendUpdate::Message(HELP_STRING.to_string()))
+                                Command::TestFilter(arg) => {
+                                    match self.test_filter(&message, arg.as_deref()) {
+                                        Ok(verdict) => result.push(SendUpdate::message(verdict)),
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
                                 }
-                            }
-                        }
-                    }
-                }
-                Err(e) => result.push(SendUpdate::Message(format!("error: {e}"))),
-            },
-            None => {}
-        }
-
-        if is_valid_command
-            && command_requires_success_report
-            && !command_failed
-            && self.chat.settings.report_command_success
-        {
-            result.push(SendUpdate::Message("success".to_string()));
-        }
-
-        if !is_valid_command && self.chat.settings.filter_enabled {
-            let variables = MessageVariables::from(&message);
-            let mut variables: Variables = Variables::from(variables);
-            variables.extend(self.chat.variables.clone());
-            if let Some(filter) = &self.chat.filter {
-                match evaluate(&filter.expression, &variables) {
-                    Ok(value) => match value {
-                        Value::Bool(value) => {
-                            if value {
-                                result.push(SendUpdate::DeleteMessage(message.id));
-                                if self.chat.settings.report_filtered {
-                                    result.push(SendUpdate::Message("message filtered".to_string()))
+                                Command::Explain(arg) => {
+                                    match self.explain(&message, arg.as_deref()) {
+                                        Ok(explanation) => {
+                                            result.push(SendUpdate::message(explanation))
+                                        }
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
                                 }
-                            }
-                        }
-                        _ => {
-                            if self.chat.settings.debug_print {
-                                result.push(SendUpdate::Message(
-                                    "error: filter evaluated to non-bool value".to_string(),
-                                ))
-                            }
-                        }
-                    },
-                    Err(e) => {
-                        if self.chat.settings.debug_print {
-                            result.push(SendUpdate::Message(format!(
-                                "error: failed to evaluate filter: {e}"
-                            )))
-                        }
-                    }
-                }
-            }
-        }
+                                Command::AddTest(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.add_test(&message, &arg) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::RemoveTest(name) => {
+                                    command_requires_success_report = true;
+
+                                    let count = self.chat.test_cases.len();
+                                    self.chat.test_cases.retain(|case| case.name != name);
+                                    if self.chat.test_cases.len() == count {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(format!(
+                                            "error [{NOT_FOUND}]: no test case named \"{name}\""
+                                        )));
+                                    }
+                                }
+                                Command::RunTests => {
+                                    result.push(SendUpdate::message(self.run_tests()));
+                                }
+                                Command::CloneChatConfig(arg) => {
+                                    command_requires_success_report = true;
+
+                                    let ids: Vec<Result<i64, _>> =
+                                        arg.split_whitespace().map(str::parse).collect();
+                                    match ids.as_slice() {
+                                        [Ok(source_id), Ok(destination_id)] => {
+                                            if let Err(e) = self
+                                                .clone_chat_config(
+                                                    *source_id,
+                                                    *destination_id,
+                                                    &mut result,
+                                                )
+                                                .await
+                                            {
+                                                command_failed = true;
+                                                result.push(SendUpdate::message(e));
+                                            }
+                                        }
+                                        _ => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(
+                                                format!("error [{INVALID_ARGUMENTS}]: expected <src_id> <dst_id>"),
+                                            ));
+                                        }
+                                    }
+                                }
+                                Command::ModerationLog(arg) => {
+                                    let count = match arg {
+                                        Some(arg) => arg.parse::<i64>().ok(),
+                                        None => Some(MODERATION_LOG_COUNT),
+                                    };
+                                    match count {
+                                        Some(count)
+                                            if (1..=MAX_MODERATION_LOG_COUNT).contains(&count) =>
+                                        {
+                                            let db_lock = self.db.lock().await;
+                                            let events = db_lock
+                                                .find_latest_events(self.chat_id.0, count)
+                                                .await;
+                                            drop(db_lock);
+
+                                            match events {
+                                                Err(e) => {
+                                                    command_failed = true;
+                                                    log::error!("Failed to read the moderation log of {}: {e}", self.chat_id);
+                                                    result.push(SendUpdate::message(format!(
+                                                        "error [{REQUEST_FAILED}]: failed to read the moderation log: {e}"
+                                                    )));
+                                                }
+                                                Ok(events) if events.is_empty() => {
+                                                    result.push(SendUpdate::message(
+                                                        "no moderation decisions".to_string(),
+                                                    ));
+                                                }
+                                                Ok(events) => {
+                                                    let lines: Vec<String> = events
+                                                        .iter()
+                                                        .map(ToString::to_string)
+                                                        .collect();
+                                                    result.push(SendUpdate::message(
+                                                        lines.join("\n"),
+                                                    ));
+                                                }
+                                            }
+                                        }
+                                        _ => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(format!(
+                                                "error [{INVALID_INPUT}]: count must be between 1 and {MAX_MODERATION_LOG_COUNT}"
+                                            )));
+                                        }
+                                    }
+                                }
+                                Command::RecomputeStats => {
+                                    let db_lock = self.db.lock().await;
+                                    let replayed = db_lock.recompute_stats(self.chat_id.0).await;
+                                    drop(db_lock);
+
+                                    match replayed {
+                                        Ok(replayed) => result.push(SendUpdate::message(format!(
+                                            "recomputed stats from {replayed} moderation decision(s)"
+                                        ))),
+                                        Err(e) => {
+                                            command_failed = true;
+                                            log::error!("Failed to recompute the stats of {}: {e}", self.chat_id);
+                                            result.push(SendUpdate::message(format!(
+                                                "error [{REQUEST_FAILED}]: failed to recompute stats: {e}"
+                                            )));
+                                        }
+                                    }
+                                }
+                                Command::Trust(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_trusted(&message, arg.as_deref(), true)
+                                    {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::Mute(arg) => match mute_command(&message, &arg) {
+                                    Ok(update) => {
+                                        command_requires_success_report = true;
+                                        result.extend(self.log_restriction(&message, &update));
+                                        result.push(update);
+                                    }
+                                    Err(e) => {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                },
+                                Command::Ban(arg) => match ban_command(&message, arg.as_deref()) {
+                                    Ok(update) => {
+                                        command_requires_success_report = true;
+                                        result.extend(self.log_restriction(&message, &update));
+                                        result.push(update);
+                                    }
+                                    Err(e) => {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                },
+                                Command::Kick => match target_user_id(&message, None) {
+                                    Ok(user_id) => {
+                                        command_requires_success_report = true;
+                                        result.extend(self.log_update(format!(
+                                            "{} kicked {user_id}",
+                                            author_name(&message)
+                                        )));
+                                        let user_id = UserId(user_id as u64);
+                                        result.push(SendUpdate::BanUser {
+                                            user_id,
+                                            duration: None,
+                                        });
+                                        result.push(SendUpdate::UnbanUser(user_id));
+                                    }
+                                    Err(e) => {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                },
+                                Command::Unban(arg) => {
+                                    match target_user_id(&message, arg.as_deref()) {
+                                        Ok(user_id) => {
+                                            command_requires_success_report = true;
+                                            let update =
+                                                SendUpdate::UnbanUser(UserId(user_id as u64));
+                                            result.extend(self.log_restriction(&message, &update));
+                                            result.push(update);
+                                        }
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
+                                }
+                                Command::Untrust(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) =
+                                        self.set_trusted(&message, arg.as_deref(), false)
+                                    {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::Exempt(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_exempt(&message, arg.as_deref(), true)
+                                    {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::Unexempt(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_exempt(&message, arg.as_deref(), false)
+                                    {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::BlockDomain(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_blocked_domains(&arg, true) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::UnblockDomain(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_blocked_domains(&arg, false) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::BlockForwardSource(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_blocked_origin(&arg, true) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::UnblockForwardSource(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_blocked_origin(&arg, false) {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::BlockMedia => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_blocked_media(&message, true).await {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::UnblockMedia => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.set_blocked_media(&message, false).await {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::ListForwardSources => {
+                                    let sources: Vec<String> = self
+                                        .chat
+                                        .blocked_origin_ids
+                                        .iter()
+                                        .map(i64::to_string)
+                                        .chain(
+                                            self.chat
+                                                .blocked_origin_usernames
+                                                .iter()
+                                                .map(|username| format!("@{username}")),
+                                        )
+                                        .collect();
+                                    if sources.is_empty() {
+                                        result.push(SendUpdate::message(
+                                            "no forward source blocked".to_string(),
+                                        ));
+                                    } else {
+                                        result.push(SendUpdate::message(sources.join("\n")));
+                                    }
+                                }
+                                Command::ListDomains => {
+                                    if self.chat.blocked_domains.is_empty() {
+                                        result.push(SendUpdate::message(
+                                            "no domain blocked".to_string(),
+                                        ));
+                                    } else {
+                                        result.push(SendUpdate::message(
+                                            self.chat.blocked_domains.join("\n"),
+                                        ));
+                                    }
+                                }
+                                Command::ProjectAdd(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.update_project(&message, &arg, true).await
+                                    {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::ProjectRemove(arg) => {
+                                    command_requires_success_report = true;
+
+                                    if let Err(e) = self.update_project(&message, &arg, false).await
+                                    {
+                                        command_failed = true;
+                                        result.push(SendUpdate::message(e));
+                                    }
+                                }
+                                Command::Stats(arg) => {
+                                    match self.chat_stats(&message, arg.as_deref()).await {
+                                        Ok(stats) => result.push(SendUpdate::message(stats)),
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
+                                }
+                                Command::ProjectStats(arg) => {
+                                    match self.project_stats(&message, &arg).await {
+                                        Ok(stats) => result.push(SendUpdate::message(stats)),
+                                        Err(e) => {
+                                            command_failed = true;
+                                            result.push(SendUpdate::message(e));
+                                        }
+                                    }
+                                }
+                                Command::Help => {
+                                    result.push(SendUpdate::html(help_html(&self.bot_username)))
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    if self.chat.settings.should_report_invalid_commands() {
+                        result.push(SendUpdate::message(format!("error [{}]: {e}", e.number())))
+                    }
+                }
+            }
+
+            if is_valid_command {
+                self.compiled_filters = self.chat.compile_filters();
+            }
+            if is_batch {
+                // Plain answers go in the report, under the line they answer.
+                let (command, _) = split_first_word(text, char::is_whitespace);
+                let status = if is_valid_command && !command_failed {
+                    "success"
+                } else {
+                    "failed"
+                };
+                batch_report.push(format!("{}. {command}: {status}", index + 1));
+                for update in result.split_off(first_update) {
+                    match update {
+                        SendUpdate::Message {
+                            text,
+                            reply_to: None,
+                            parse_mode: None,
+                        } => {
+                            batch_answers.push(text.clone());
+                            batch_report.push(text);
+                        }
+                        update => result.push(update),
+                    }
+                }
+            }
+            is_valid_command |= was_valid_command;
+        }
+
+        if is_batch && batch_has_command {
+            if skipped_commands > 0 {
+                batch_report.push(format!(
+                    "{skipped_commands} more line(s) skipped, a message runs at most {MAX_BATCH_COMMANDS} commands"
+                ));
+            }
+            result.push(SendUpdate::message(batch_report.join("\n")));
+        } else {
+            result.extend(batch_answers.into_iter().map(SendUpdate::message));
+        }
+
+        if is_valid_command
+            && !is_batch
+            && command_requires_success_report
+            && !command_failed
+            && self.chat.settings.should_report_command_success()
+        {
+            result.push(SendUpdate::message("success".to_string()));
+        }
+
+        if is_valid_command
+            && from_admin
+            && !message.chat.is_private()
+            && self.chat.settings.delete_command_messages
+        {
+            result.push(SendUpdate::DeleteMessage(message.id));
+        }
+
+        if !is_valid_command && !message.chat.is_private() {
+            let mut db_lock = self.db.lock().await;
+            let recorded = db_lock
+                .write_or_queue(PendingWrite::Messages {
+                    chat_id: self.chat_id.0,
+                    time: message.date.timestamp(),
+                    count: 1,
+                })
+                .await;
+            drop(db_lock);
+            self.report_persistence(recorded, "statistics", &mut result);
+        }
+
+        let skips_filters =
+            (from_admin && !self.chat.settings.apply_to_admins && !message.chat.is_private())
+                || message.from.as_ref().is_some_and(|from| {
+                    self.chat
+                        .is_exempt(from.id.0 as i64, from.username.as_deref())
+                });
+        let checks_forwarded =
+            !is_valid_command && message.chat.is_private() && message.forward_origin().is_some();
+        let flood_count = self
+            .flood_count(&message)
+            .filter(|_| !is_valid_command && !skips_filters && !checks_forwarded);
+        let has_blocked_media =
+            !is_valid_command && !skips_filters && self.has_blocked_media(&message).await;
+        // A flood action that doesn't delete leaves the message to the checks
+        // below, so flooding can't get spam past them.
+        let mut flood_deleted = false;
+        if let Some(count) = flood_count {
+            result.extend(self.flood_updates(&message, count, from_admin));
+
+            let action = self.chat.settings.flood_action();
+            flood_deleted = action.deletes();
+            if action.deletes() || count == self.chat.settings.flood_messages + 1 {
+                let mut db_lock = self.db.lock().await;
+                let recorded = db_lock
+                    .write_or_queue(PendingWrite::Filtered {
+                        chat_id: self.chat_id.0,
+                        time: message.date.timestamp(),
+                        filter_name: FLOOD_FILTER_NAME.to_string(),
+                        user_id: message.from.as_ref().map(|from| from.id.0 as i64),
+                        action,
+                        shadow: false,
+                        event_id: ObjectId::new(),
+                    })
+                    .await;
+                drop(db_lock);
+                self.report_persistence(recorded, "statistics", &mut result);
+            }
+        }
+
+        if checks_forwarded {
+            result.push(self.check_forwarded_message(&message).await);
+        } else if flood_deleted {
+            // already deleted for flooding
+        } else if !is_valid_command && !skips_filters && self.links_to_blocked_domain(&message) {
+            self.delete_blocked(
+                &message,
+                BLOCKED_DOMAIN_FILTER_NAME,
+                "links to a blocked domain",
+                &mut result,
+            )
+            .await;
+        } else if !is_valid_command && !skips_filters && self.is_blocked_forward(&message) {
+            self.delete_blocked(
+                &message,
+                BLOCKED_ORIGIN_FILTER_NAME,
+                "forwards from this source are blocked",
+                &mut result,
+            )
+            .await;
+        } else if has_blocked_media {
+            self.delete_blocked(
+                &message,
+                BLOCKED_MEDIA_FILTER_NAME,
+                "this media is blocked",
+                &mut result,
+            )
+            .await;
+        } else if !is_valid_command && self.chat.settings.filter_enabled && !skips_filters {
+            let local_time = self.local_time(message.date);
+            let mut message_variables = MessageVariables::from(&message);
+            if let Some(profile) = profile {
+                message_variables.from.set_profile(profile);
+            }
+            message_variables.from.is_known_spammer = is_known_spammer;
+            message_variables.is_first_message = is_first_message;
+            if let Some(id) = message_variables.from.id {
+                message_variables.from.is_trusted = Some(self.chat.trusted_ids.contains(&id));
+            }
+            message_variables.similarity_to_recent = similarity_to_recent;
+            if let Some(from) = &message.from {
+                let event = events::user_event(events::MESSAGES, from.id.0 as i64);
+                message_variables.messages_last_minute = Some(
+                    self.events
+                        .count(&event, message.date - TimeDelta::seconds(60)),
+                );
+                message_variables.messages_last_10s = Some(
+                    self.events
+                        .count(&event, message.date - TimeDelta::seconds(10)),
+                );
+            }
+            let mut errors = Vec::new();
+            let mut shadow_matched = Vec::new();
+            let matched = match filter_variables(message_variables.into(), &self.chat) {
+                Err(e) => {
+                    errors.push(format!(
+                        "error [{}]: failed to evaluate filters: {e}",
+                        e.number()
+                    ));
+                    None
+                }
+                Ok(_) if self.compiled_filters.is_empty() => (self.chat.settings.default_verdict()
+                    == Verdict::Deny)
+                    .then(|| (DEFAULT_VERDICT_FILTER_NAME.to_string(), Vec::new())),
+                Ok(variables) => {
+                    let evaluate =
+                        |compiled: &Result<CompiledExpression, EvaluationError>| match compiled {
+                            Ok(compiled) => self.evaluate_for_message(&message, |sandbox| {
+                                self.chat.evaluate_compiled(compiled, &variables, sandbox)
+                            }),
+                            Err(e) => (Err(e.clone()), Vec::new()),
+                        };
+                    let chat = &self.chat;
+                    let active = |shadow: bool| {
+                        self.compiled_filters
+                            .iter()
+                            .filter(move |(name, _)| {
+                                chat.filter(name).map_or(!shadow, |filter| {
+                                    filter.is_active(local_time) && filter.shadow == shadow
+                                })
+                            })
+                            .map(|(name, compiled)| (name.as_str(), compiled))
+                    };
+                    // Shadow filters are all evaluated, their effects are dropped.
+                    for (name, compiled) in active(true) {
+                        if is_match(name, &evaluate(compiled).0, &mut errors) {
+                            shadow_matched.push(name.to_string());
+                        }
+                    }
+                    first_match(active(false), evaluate, &mut errors)
+                }
+            };
+            if self.chat.settings.debug_evaluation {
+                for error in errors {
+                    result.push(SendUpdate::message(error));
+                }
+            }
+
+            for filter_name in shadow_matched {
+                let action = self
+                    .chat
+                    .filter(&filter_name)
+                    .map_or(Action::Delete, |filter| filter.action);
+                result.extend(self.log_update(format!(
+                    "shadow filter {filter_name} matched a message of {}, action would be {action}:\n{}",
+                    describe_sender(&message),
+                    message.text().or(message.caption()).unwrap_or("")
+                )));
+
+                let mut db_lock = self.db.lock().await;
+                let recorded = db_lock
+                    .write_or_queue(PendingWrite::Filtered {
+                        chat_id: self.chat_id.0,
+                        time: message.date.timestamp(),
+                        filter_name,
+                        user_id: message.from.as_ref().map(|from| from.id.0 as i64),
+                        action,
+                        shadow: true,
+                        event_id: ObjectId::new(),
+                    })
+                    .await;
+                drop(db_lock);
+                self.report_persistence(recorded, "statistics", &mut result);
+            }
+
+            if let Some((filter_name, effects)) = matched {
+                let action = self
+                    .chat
+                    .filter(&filter_name)
+                    .map_or(Action::Delete, |filter| filter.action);
+                if self.chat.settings.should_report_actions() {
+                    result.push(SendUpdate::message(describe_actions(
+                        &message, action, &effects,
+                    )));
+                }
+                self.chat.apply_effects(effects, message.date.timestamp());
+                self.record_filtered_event(&message);
+                // Quarantined messages are logged with the buttons to review them.
+                if action != Action::Quarantine {
+                    result.extend(self.log_update(format!(
+                        "filter {filter_name} matched a message of {}, action {action}:\n{}",
+                        describe_sender(&message),
+                        message.text().or(message.caption()).unwrap_or("")
+                    )));
+                }
+                match action {
+                    Action::Delete | Action::Mute(_) | Action::Ban => {
+                        result.push(self.delete_message(message.id));
+                        if self.chat.settings.rewrite_links {
+                            if let Some(text) = remove_links(&message) {
+                                result.push(SendUpdate::message(format!(
+                                    "{} wrote (links removed):\n{text}",
+                                    author_name(&message)
+                                )));
+                            }
+                        }
+                    }
+                    Action::Warn => result.push(SendUpdate::Message {
+                        text: format!("warning: this message matched filter {filter_name}"),
+                        reply_to: Some(message.id),
+                        parse_mode: None,
+                    }),
+                    Action::Report => result.push(SendUpdate::message(format!(
+                        "report: message {} from {} matched filter {filter_name}",
+                        message.id,
+                        author_name(&message)
+                    ))),
+                    Action::Quarantine => {
+                        result.extend(self.quarantine_updates(&message, &filter_name))
+                    }
+                }
+                if let Some(from) = message.from.as_ref().filter(|_| !from_admin) {
+                    match action {
+                        Action::Mute(minutes) => result.push(mute(from.id, minutes)),
+                        Action::Ban => result.push(SendUpdate::BanUser {
+                            user_id: from.id,
+                            duration: None,
+                        }),
+                        _ => {}
+                    }
+                }
+                if self.chat.settings.should_report_filtered() {
+                    if action.deletes() && self.chat.settings.appeal_button {
+                        let report = self.appealable_report(&message, &filter_name);
+                        result.push(report);
+                    } else {
+                        let text = self
+                            .filtered_message(&message, &filter_name)
+                            .unwrap_or_else(|| {
+                                format!(
+                                    "message of {} filtered by {filter_name}",
+                                    author_name(&message)
+                                )
+                            });
+                        result.push(SendUpdate::message(text))
+                    }
+                }
+
+                let mut db_lock = self.db.lock().await;
+                let recorded = db_lock
+                    .write_or_queue(PendingWrite::Filtered {
+                        chat_id: self.chat_id.0,
+                        time: message.date.timestamp(),
+                        filter_name,
+                        user_id: message.from.as_ref().map(|from| from.id.0 as i64),
+                        action,
+                        shadow: false,
+                        event_id: ObjectId::new(),
+                    })
+                    .await;
+                drop(db_lock);
+                self.report_persistence(recorded, "statistics", &mut result);
+            }
+        }
+
+        let mut db_lock = self.db.lock().await;
+        let saved = db_lock
+            .write_or_queue(PendingWrite::Chat(Box::new(self.chat.clone())))
+            .await;
+        drop(db_lock);
+        self.report_persistence(saved, "chat", &mut result);
+
+        if !self.chat.settings.should_show_hints() {
+            strip_hints(&mut result);
+        }
+        thread_replies(&mut result, message.id);
+
+        Ok(result)
+    }
+
+    /// Logs a failed write, which the database has queued for replay, and
+    /// reports it to the chat if debug_persistence is enabled. Moderation goes
+    /// on either way.
+    fn report_persistence(
+        &self,
+        outcome: Result<(), Box<dyn Error + Send + Sync>>,
+        what: &str,
+        result: &mut Vec<SendUpdate>,
+    ) {
+        if let Err(e) = outcome {
+            log::error!("Failed to save {what} of {}: {e}", self.chat_id);
+            if self.chat.settings.debug_persistence {
+                result.push(SendUpdate::message(format!(
+                    "error [{REQUEST_FAILED}]: failed to save {what}, it is saved once the database is back unless the database refused it: {e}"
+                )));
+            }
+        }
+    }
+
+    /// Adds a chat the sender administers to one of their projects, or
+    /// removes a chat from it. `arg` is `<name> <chat_id>`.
+    async fn update_project(&self, message: &Message, arg: &str, add: bool) -> Result<(), String> {
+        let failed = |e: Box<dyn Error + Send + Sync>| {
+            format!("error [{REQUEST_FAILED}]: failed to update project: {e}")
+        };
+        let owner_id = sender_id(message).map_err(&failed)?;
+        let (name, chat_id) = match arg.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [name, chat_id] => match chat_id.parse::<i64>() {
+                Ok(chat_id) => (name.to_string(), chat_id),
+                Err(_) => {
+                    return Err(format!(
+                        "error [{INVALID_INPUT}]: invalid chat id \"{chat_id}\""
+                    ))
+                }
+            },
+            _ => {
+                return Err(format!(
+                    "error [{INVALID_ARGUMENTS}]: expected <name> <chat_id>"
+                ))
+            }
+        };
+
+        let db_lock = self.db.lock().await;
+        let mut project = db_lock
+            .find_project(owner_id, &name)
+            .await
+            .map_err(&failed)?
+            .unwrap_or(Project {
+                owner_id,
+                name: name.clone(),
+                chat_ids: Vec::new(),
+            });
+
+        if add {
+            let chats = db_lock
+                .find_chats_by_admin(owner_id)
+                .await
+                .map_err(&failed)?;
+            if !chats.iter().any(|chat| chat.chat_id == chat_id) {
+                return Err(format!(
+                    "error [{PERMISSION_DENIED}]: you are not an admin of chat {chat_id}"
+                ));
+            }
+            if !project.chat_ids.contains(&chat_id) {
+                project.chat_ids.push(chat_id);
+            }
+        } else if project.chat_ids.contains(&chat_id) {
+            project.chat_ids.retain(|id| *id != chat_id);
+        } else {
+            return Err(format!(
+                "error [{NOT_FOUND}]: chat {chat_id} is not part of project \"{name}\""
+            ));
+        }
+
+        db_lock.insert_project(&project).await.map_err(&failed)?;
+        drop(db_lock);
+
+        Ok(())
+    }
+
+    /// Sums the stats of the chat over the last `arg` days, 7 by default.
+    async fn chat_stats(&self, message: &Message, arg: Option<&str>) -> Result<String, String> {
+        let failed = |e: Box<dyn Error + Send + Sync>| {
+            format!("error [{REQUEST_FAILED}]: failed to collect stats: {e}")
+        };
+        let days = match arg {
+            Some(arg) => match arg.trim().trim_end_matches('d').parse::<i64>() {
+                Ok(days) if (1..=MAX_STATS_DAYS).contains(&days) => days,
+                _ => {
+                    return Err(format!(
+                        "error [{INVALID_INPUT}]: invalid number of days \"{arg}\""
+                    ))
+                }
+            },
+            None => STATS_DAYS,
+        };
+
+        let first_day = message.date.timestamp().div_euclid(SECONDS_PER_DAY) - days + 1;
+        let db_lock = self.db.lock().await;
+        let stats = db_lock
+            .find_stats_since(self.chat_id.0, first_day)
+            .await
+            .map_err(&failed)?;
+        drop(db_lock);
+
+        let mut messages = 0;
+        let mut filtered = 0;
+        let mut shadowed = 0;
+        let mut deleted = 0;
+        let mut warned = 0;
+        let mut restored = 0;
+        let mut filters: HashMap<&str, i64> = HashMap::new();
+        for day in &stats {
+            messages += day.messages;
+            filtered += day.filtered;
+            shadowed += day.shadowed;
+            deleted += day.deleted;
+            warned += day.warned;
+            restored += day.restored;
+            for (name, count) in &day.filters {
+                *filters.entry(name).or_insert(0) += count;
+            }
+        }
+        let mut filters: Vec<(&str, i64)> = filters.into_iter().collect();
+        filters.sort_by(|(l_name, l_count), (r_name, r_count)| {
+            r_count.cmp(l_count).then_with(|| l_name.cmp(r_name))
+        });
+
+        let mut res = format!(
+            "stats for the last {days} day(s)\nmessages seen: {messages}\n\
+             matched by filters: {filtered}\ndeleted: {deleted}\nwarned: {warned}\n\
+             restored: {restored}"
+        );
+        if shadowed > 0 {
+            res.push_str(&format!("\nmatched by shadow filters: {shadowed}"));
+        }
+        if !filters.is_empty() {
+            res.push_str("\nmatches per filter:");
+            for (name, count) in filters {
+                res.push_str(&format!("\n- {name}: {count}"));
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Sums the stats over the chats of one of the sender's projects they
+    /// still administer. `arg` is `<name> [days]`.
+    async fn project_stats(&self, message: &Message, arg: &str) -> Result<String, String> {
+        let failed = |e: Box<dyn Error + Send + Sync>| {
+            format!("error [{REQUEST_FAILED}]: failed to collect project stats: {e}")
+        };
+        let owner_id = sender_id(message).map_err(&failed)?;
+        let (name, days) = match arg.split_whitespace().collect::<Vec<&str>>().as_slice() {
+            [name] => (name.to_string(), PROJECT_STATS_DAYS),
+            [name, days] => match days.parse::<i64>() {
+                Ok(days) if days > 0 => (name.to_string(), days),
+                _ => {
+                    return Err(format!(
+                        "error [{INVALID_INPUT}]: invalid number of days \"{days}\""
+                    ))
+                }
+            },
+            _ => {
+                return Err(format!(
+                    "error [{INVALID_ARGUMENTS}]: expected <name> [days]"
+                ))
+            }
+        };
+
+        let db_lock = self.db.lock().await;
+        let Some(project) = db_lock
+            .find_project(owner_id, &name)
+            .await
+            .map_err(&failed)?
+        else {
+            return Err(format!("error [{NOT_FOUND}]: no project named \"{name}\""));
+        };
+
+        let admin_chats = db_lock
+            .find_chats_by_admin(owner_id)
+            .await
+            .map_err(&failed)?;
+        let chat_ids: Vec<i64> = project
+            .chat_ids
+            .into_iter()
+            .filter(|chat_id| admin_chats.iter().any(|chat| chat.chat_id == *chat_id))
+            .collect();
+
+        let first_day = message.date.timestamp().div_euclid(SECONDS_PER_DAY) - days + 1;
+        let stats = db_lock
+            .find_stats_of_chats_since(&chat_ids, first_day)
+            .await
+            .map_err(&failed)?;
+        drop(db_lock);
+
+        Ok(format!(
+            "project \"{name}\" ({} chat(s))\n{}",
+            chat_ids.len(),
+            Digest::from_stats(days, &stats)
+        ))
+    }
+
+    /// Copies filter, settings, variables and definitions of one chat to
+    /// another. Sessions of the destination chat are asked to reload, so they
+    /// don't overwrite the copied config with their own.
+    async fn clone_chat_config(
+        &mut self,
+        source_id: i64,
+        destination_id: i64,
+        result: &mut Vec<SendUpdate>,
+    ) -> Result<(), String> {
+        let failed = |e: Box<dyn Error + Send + Sync>| {
+            format!("error [{REQUEST_FAILED}]: failed to clone chat config: {e}")
+        };
+        let db_lock = self.db.lock().await;
+        let Some(source) = db_lock.find_chat(source_id).await.map_err(&failed)? else {
+            return Err(format!("error [{NOT_FOUND}]: chat {source_id} is unknown"));
+        };
+
+        if destination_id == self.chat_id.0 {
+            copy_chat_config(&source, &mut self.chat);
+            self.compiled_filters = self.chat.compile_filters();
+            return Ok(());
+        }
+
+        let mut destination = db_lock
+            .find_chat(destination_id)
+            .await
+            .map_err(&failed)?
+            .unwrap_or(Chat {
+                chat_id: destination_id,
+                ..Default::default()
+            });
+        copy_chat_config(&source, &mut destination);
+        db_lock.insert_chat(&destination).await.map_err(&failed)?;
+        drop(db_lock);
+
+        result.push(SendUpdate::ReloadChat(ChatId(destination_id)));
+        Ok(())
+    }
+
+    /// Handles a press of a button of the bot, on the message `message_id` of
+    /// the chat which reads `message_text`.
+    pub async fn handle_callback_query(
+        &mut self,
+        data: &str,
+        from_id: i64,
+        message_id: MessageId,
+        message_text: &str,
+    ) -> Result<Vec<SendUpdate>, Box<dyn Error + Send + Sync>> {
+        self.refresh();
+
+        let mut result = Vec::new();
+
+        match data.parse::<Callback>() {
+            Ok(Callback::Check { chat_id }) => {
+                let Some(variables) = &self.pending_check else {
+                    result.push(SendUpdate::AnswerCallback {
+                        text: format!("error [{NO_REPLY_MESSAGE}]: no forwarded message to check, forward it again"),
+                        alert: true,
+                    });
+                    return Ok(result);
+                };
+
+                let db_lock = self.db.lock().await;
+                let chats = db_lock.find_chats_by_admin(from_id).await?;
+                drop(db_lock);
+
+                match chats.iter().find(|chat| chat.chat_id == chat_id) {
+                    Some(chat) => {
+                        result.push(SendUpdate::message(describe_verdict(chat, variables)))
+                    }
+                    None => result.push(SendUpdate::AnswerCallback {
+                        text: format!("error [{PERMISSION_DENIED}]: permission denied"),
+                        alert: true,
+                    }),
+                }
+            }
+            Ok(Callback::Quarantine {
+                approve,
+                chat_id,
+                message_id: quarantined_id,
+            }) => {
+                match self
+                    .review_quarantined(
+                        approve,
+                        chat_id,
+                        MessageId(quarantined_id),
+                        from_id,
+                        &mut result,
+                    )
+                    .await
+                {
+                    Ok(outcome) => {
+                        result.push(SendUpdate::EditMessage {
+                            message_id,
+                            text: format!("{message_text}\n\n{outcome}"),
+                            buttons: Vec::new(),
+                        });
+                        result.push(SendUpdate::AnswerCallback {
+                            text: outcome,
+                            alert: false,
+                        });
+                    }
+                    Err(e) => result.push(SendUpdate::AnswerCallback {
+                        text: e,
+                        alert: true,
+                    }),
+                }
+            }
+            Ok(Callback::Appeal {
+                message_id: appealed_id,
+            }) => match self.appeal(MessageId(appealed_id), from_id, &mut result) {
+                Ok(()) => {
+                    result.push(SendUpdate::EditMessage {
+                        message_id,
+                        text: message_text.to_string(),
+                        buttons: Vec::new(),
+                    });
+                    result.push(SendUpdate::AnswerCallback {
+                        text: "appeal sent to the admins".to_string(),
+                        alert: false,
+                    });
+                }
+                Err(e) => result.push(SendUpdate::AnswerCallback {
+                    text: e,
+                    alert: true,
+                }),
+            },
+            Err(()) => {
+                log::warn!("Unknown callback data in {}: {data}", self.chat_id);
+                result.push(SendUpdate::AnswerCallback {
+                    text: format!("error [{NOT_FOUND}]: unknown button"),
+                    alert: true,
+                });
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Update for the admins of the chat, sent to the log chat if it has one
+    /// and to the chat itself otherwise.
+    fn admin_update(&self, text: String, buttons: Vec<(String, String)>) -> SendUpdate {
+        match self.log_update(text.clone()) {
+            Some(SendUpdate::Log { chat_id, text, .. }) => SendUpdate::Log {
+                chat_id,
+                text,
+                buttons,
+            },
+            _ if buttons.is_empty() => SendUpdate::message(text),
+            _ => SendUpdate::MessageWithButtons {
+                text,
+                buttons,
+                reply_to: None,
+            },
+        }
+    }
+
+    /// Asks admins to approve or delete `message`, which matched the filter
+    /// `filter_name`. In the log chat the message is forwarded first, in the
+    /// chat the question replies to it.
+    fn quarantine_updates(&self, message: &Message, filter_name: &str) -> Vec<SendUpdate> {
+        let text = format!(
+            "filter {filter_name} quarantined message {} of {}:\n{}",
+            message.id,
+            describe_sender(message),
+            message.text().or(message.caption()).unwrap_or("")
+        );
+        let buttons = [("approve", true), ("delete", false)]
+            .into_iter()
+            .map(|(label, approve)| {
+                let callback = Callback::Quarantine {
+                    approve,
+                    chat_id: self.chat_id.0,
+                    message_id: message.id.0,
+                };
+                (label.to_string(), callback.to_string())
+            })
+            .collect();
+
+        match self.log_update(text.clone()) {
+            Some(SendUpdate::Log { chat_id, text, .. }) => vec![
+                SendUpdate::ForChat {
+                    chat_id,
+                    update: Box::new(SendUpdate::ForwardMessage {
+                        from_chat_id: self.chat_id,
+                        message_id: message.id,
+                    }),
+                },
+                SendUpdate::Log {
+                    chat_id,
+                    text,
+                    buttons,
+                },
+            ],
+            _ => vec![SendUpdate::MessageWithButtons {
+                text,
+                buttons,
+                reply_to: Some(message.id),
+            }],
+        }
+    }
+
+    /// Applies the decision of an admin about a quarantined message of the
+    /// chat `chat_id`, returns what was done.
+    async fn review_quarantined(
+        &self,
+        approve: bool,
+        chat_id: i64,
+        message_id: MessageId,
+        from_id: i64,
+        result: &mut Vec<SendUpdate>,
+    ) -> Result<String, String> {
+        let db_lock = self.db.lock().await;
+        let chat = db_lock.find_chat(chat_id).await;
+        drop(db_lock);
+        let allowed = match chat {
+            Ok(chat) => chat.is_some_and(|chat| chat.admin_ids.contains(&from_id)),
+            Err(e) => {
+                log::error!("Failed to find chat {chat_id}: {e}");
+                false
+            }
+        };
+        if !allowed {
+            return Err(format!("error [{PERMISSION_DENIED}]: permission denied"));
+        }
+
+        if approve {
+            let mut db_lock = self.db.lock().await;
+            let recorded = db_lock
+                .write_or_queue(PendingWrite::Restored {
+                    chat_id,
+                    time: Utc::now().timestamp(),
+                    event_id: ObjectId::new(),
+                })
+                .await;
+            drop(db_lock);
+            self.report_persistence(recorded, "statistics", result);
+            result.push(SendUpdate::ForChat {
+                chat_id: ChatId(chat_id),
+                update: Box::new(SendUpdate::Message {
+                    text: "an admin reviewed and approved this message".to_string(),
+                    reply_to: Some(message_id),
+                    parse_mode: None,
+                }),
+            });
+            Ok(format!("message {message_id} of {chat_id} approved"))
+        } else {
+            result.push(SendUpdate::ForChat {
+                chat_id: ChatId(chat_id),
+                update: Box::new(SendUpdate::DeleteMessage(message_id)),
+            });
+            Ok(format!("message {message_id} of {chat_id} deleted"))
+        }
+    }
+
+    /// `arg` is `<name> <command>`, the name may start with a /.
+    fn set_alias(&mut self, arg: &str) -> Result<(), String> {
+        let (name, Some(command)) = split_first_word(arg, char::is_whitespace) else {
+            return Err(format!(
+                "error [{INVALID_ARGUMENTS}]: expected <name> <command>"
+            ));
+        };
+        let name = name.trim_start_matches('/');
+        let command = command.trim();
+
+        self.check_alias(name, command)?;
+        if !self.chat.aliases.contains_key(name) && self.chat.aliases.len() >= MAX_ALIASES {
+            return Err(format!(
+                "error [{LIMIT_EXCEEDED}]: a chat can have at most {MAX_ALIASES} aliases"
+            ));
+        }
+
+        self.chat
+            .aliases
+            .insert(name.to_string(), command.to_string());
+        Ok(())
+    }
+
+    /// Whether `name` can be an alias of `command`, `name` without the /.
+    fn check_alias(&self, name: &str, command: &str) -> Result<(), String> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!(
+                "error [{INVALID_INPUT}]: \"{name}\" is not a valid alias name"
+            ));
+        }
+        let no_aliases = HashMap::new();
+        if !matches!(
+            Command::new(&format!("/{name}"), &self.bot_username, "/", &no_aliases),
+            Err(CommandError::InvalidCommand(_))
+        ) {
+            return Err(format!(
+                "error [{INVALID_INPUT}]: /{name} is already a command"
+            ));
+        }
+        if matches!(
+            Command::new(command, &self.bot_username, "/", &no_aliases),
+            Ok(None) | Err(CommandError::InvalidCommand(_))
+        ) {
+            return Err(format!(
+                "error [{INVALID_INPUT}]: \"{command}\" is not a command"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The filtered_message template of the chat rendered for `message`,
+    /// `None` if the chat has none.
+    fn filtered_message(&self, message: &Message, rule_name: &str) -> Option<String> {
+        if self.chat.filtered_message.is_empty() {
+            return None;
+        }
+
+        let mut variables = filter_variables(MessageVariables::from(message).into(), &self.chat)
+            .unwrap_or_else(|_| MessageVariables::from(message).into());
+        variables.extend([("rule_name".to_string(), Value::Str(rule_name.to_string()))]);
+        Some(render_template(&self.chat.filtered_message, &variables))
+    }
+
+    /// Report of a deleted message with a button its sender can press to
+    /// appeal, remembered until the appeal or until it is one of the oldest
+    /// MAX_APPEALS.
+    fn appealable_report(&mut self, message: &Message, filter_name: &str) -> SendUpdate {
+        let text = self
+            .filtered_message(message, filter_name)
+            .unwrap_or_else(|| {
+                format!(
+                    "message of {} filtered by {filter_name}",
+                    author_name(message)
+                )
+            });
+        let Some(from) = &message.from else {
+            return SendUpdate::message(text);
+        };
+
+        if self.appeals.len() == MAX_APPEALS {
+            self.appeals.pop_front();
+        }
+        self.appeals.push_back(Appeal {
+            message_id: message.id,
+            user_id: from.id.0 as i64,
+            text: format!(
+                "{} appeals the deletion of message {} by filter {filter_name}:\n{}",
+                describe_sender(message),
+                message.id,
+                message.text().or(message.caption()).unwrap_or("")
+            ),
+        });
+
+        let callback = Callback::Appeal {
+            message_id: message.id.0,
+        };
+        SendUpdate::MessageWithButtons {
+            text,
+            buttons: vec![("appeal".to_string(), callback.to_string())],
+            reply_to: None,
+        }
+    }
+
+    /// Forwards the appeal of `from_id` against the deletion of `message_id`
+    /// to the admins. Each deletion can be appealed once.
+    fn appeal(
+        &mut self,
+        message_id: MessageId,
+        from_id: i64,
+        result: &mut Vec<SendUpdate>,
+    ) -> Result<(), String> {
+        let Some(index) = self
+            .appeals
+            .iter()
+            .position(|appeal| appeal.message_id == message_id)
+        else {
+            return Err(format!(
+                "error [{NOT_FOUND}]: this message can't be appealed anymore"
+            ));
+        };
+        if self.appeals[index].user_id != from_id {
+            return Err(format!(
+                "error [{PERMISSION_DENIED}]: only the sender can appeal"
+            ));
+        }
+
+        if let Some(appeal) = self.appeals.remove(index) {
+            result.push(self.admin_update(appeal.text, Vec::new()));
+        }
+        Ok(())
+    }
+
+    fn record_events(&mut self, message: &Message) {
+        if message.chat.is_private() {
+            return;
+        }
+
+        if let Some(members) = message.new_chat_members() {
+            for _ in members {
+                self.events.record(events::JOINS.to_string(), message.date);
+            }
+        }
+
+        self.events
+            .record(events::MESSAGES.to_string(), message.date);
+        if let Some(from) = &message.from {
+            self.events.record(
+                events::user_event(events::MESSAGES, from.id.0 as i64),
+                message.date,
+            );
+        }
+    }
+
+    /// Evaluates the filters, or the expression in `arg`, against the message
+    /// replied to without deleting it or changing cooldowns and counters.
+    fn test_filter(&self, message: &Message, arg: Option<&str>) -> Result<String, String> {
+        let Some(target) = message.reply_to_message() else {
+            return Err(format!("error [{NO_REPLY_MESSAGE}]: no reply message"));
+        };
+
+        let variables = filter_variables(MessageVariables::from(target).into(), &self.chat)
+            .map_err(|e| format!("error [{}]: {e}", e.number()))?;
+
+        let Some(arg) = arg else {
+            if self.chat.enabled_filters().next().is_none() {
+                return Ok(describe_default_verdict(&self.chat));
+            }
+            let mut errors = Vec::new();
+            let matched = first_match(
+                self.chat
+                    .enabled_filters()
+                    .map(|filter| (filter.name.as_str(), filter)),
+                |filter| {
+                    self.evaluate_for_message(target, |sandbox| {
+                        self.chat.evaluate(&filter.expression, &variables, sandbox)
+                    })
+                },
+                &mut errors,
+            );
+            return Ok(describe_match(
+                &self.chat,
+                matched.map(|(name, _)| name),
+                errors,
+            ));
+        };
+
+        let expression = self
+            .expression_parser
+            .parse(arg)
+            .map_err(|e| format!("parse error [{}]: {e}", parse_error::number(&e)))?;
+        let (evaluation, _) = self.evaluate_for_message(target, |sandbox| {
+            self.chat.evaluate(&expression, &variables, sandbox)
+        });
+        match evaluation {
+            Ok(Value::Bool(true)) => Ok("filter matched, the message would be deleted".to_string()),
+            Ok(Value::Bool(false)) => Ok("filter did not match".to_string()),
+            Ok(value) => Err(format!(
+                "error [{}]: filter evaluated to non-bool value {value}",
+                error_code::number("type_mismatch")
+            )),
+            Err(e) => Err(format!(
+                "error [{}]: failed to evaluate filter: {e}",
+                e.number()
+            )),
+        }
+    }
+
+    /// Traces the filter `name`, or the first enabled filter matching the
+    /// replied message, on the replied message.
+    fn explain(&self, message: &Message, name: Option<&str>) -> Result<String, String> {
+        let Some(target) = message.reply_to_message() else {
+            return Err(format!("error [{NO_REPLY_MESSAGE}]: no reply message"));
+        };
+
+        let variables = filter_variables(MessageVariables::from(target).into(), &self.chat)
+            .map_err(|e| format!("error [{}]: {e}", e.number()))?;
+        let evaluate = |expression: &Expression| {
+            self.evaluate_for_message(target, |sandbox| {
+                self.chat.evaluate(expression, &variables, sandbox)
+            })
+        };
+
+        let filter = match name {
+            Some(name) => self.chat.filter(name.trim()).ok_or_else(|| {
+                format!("error [{NOT_FOUND}]: no filter named \"{}\"", name.trim())
+            })?,
+            None => {
+                let mut errors = Vec::new();
+                let matched = first_match(
+                    self.chat
+                        .enabled_filters()
+                        .map(|filter| (filter.name.as_str(), filter)),
+                    |filter| evaluate(&filter.expression),
+                    &mut errors,
+                );
+                match matched.and_then(|(name, _)| self.chat.filter(&name)) {
+                    Some(filter) => filter,
+                    None => return Ok(describe_match(&self.chat, None, errors)),
+                }
+            }
+        };
+
+        let trace = trace::trace(&filter.expression, &variables, |expression| {
+            evaluate(expression).0
+        });
+        let mut lines = vec![match &trace.operator {
+            Some(operator) => format!("filter {} ({operator} of):", filter.name),
+            None => format!("filter {}:", filter.name),
+        }];
+        for step in trace.steps {
+            let result = match step.result {
+                Ok(value) => value.to_string(),
+                Err(e) => format!("error [{}]: {e}", e.number()),
+            };
+            lines.push(format!(
+                "- {}: {result}",
+                step.expression.to_canonical_string()
+            ));
+            if !step.variables.is_empty() {
+                let variables: Vec<String> = step
+                    .variables
+                    .iter()
+                    .map(|(name, value)| format!("{name} = {value}"))
+                    .collect();
+                lines.push(format!("  {}", variables.join(", ")));
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    /// Keeps the variables of the replied message as a test case, `arg` is
+    /// `<match|no_match> [name]`.
+    fn add_test(&mut self, message: &Message, arg: &str) -> Result<(), String> {
+        let Some(target) = message.reply_to_message() else {
+            return Err(format!("error [{NO_REPLY_MESSAGE}]: no reply message"));
+        };
+
+        let (expected, name) = split_first_word(arg, char::is_whitespace);
+        let expect_match = match expected {
+            "match" => true,
+            "no_match" => false,
+            _ => {
+                return Err(format!(
+                    "error [{INVALID_INPUT}]: invalid expectation \"{expected}\", expected match or no_match"
+                ))
+            }
+        };
+        let name = match name.map(str::trim) {
+            Some(name) => {
+                let is_identifier = self
+                    .identifier_parser
+                    .parse(name)
+                    .is_ok_and(|parsed| parsed == name);
+                if !is_identifier || name.contains(NAMESPACE_SEPARATOR) {
+                    return Err(format!(
+                        "error [{INVALID_INPUT}]: \"{name}\" is not a valid test case name"
+                    ));
+                }
+                name.to_string()
+            }
+            None => (1..)
+                .map(|i| format!("test_{i}"))
+                .find(|name| self.chat.test_cases.iter().all(|case| &case.name != name))
+                .expect("some test name is free"),
+        };
+
+        let case = TestCase {
+            name,
+            expect_match,
+            variables: MessageVariables::from(target).into(),
+        };
+        let test_cases = &mut self.chat.test_cases;
+        match test_cases
+            .iter()
+            .position(|existing| existing.name == case.name)
+        {
+            Some(index) => test_cases[index] = case,
+            None if test_cases.len() >= MAX_TEST_CASES => {
+                return Err(format!(
+                    "error [{LIMIT_EXCEEDED}]: a chat can have at most {MAX_TEST_CASES} test cases"
+                ))
+            }
+            None => test_cases.push(case),
+        }
+
+        Ok(())
+    }
+
+    /// Checks the test cases against the enforced filters.
+    fn run_tests(&self) -> String {
+        if self.chat.test_cases.is_empty() {
+            return "no test cases".to_string();
+        }
+
+        let describe = |matched: bool| if matched { "match" } else { "no match" };
+        let mut passed = 0;
+        let mut lines = Vec::new();
+        for case in &self.chat.test_cases {
+            let mut message_variables = case.variables.clone();
+            message_variables.add_namespace(MESSAGE_NAMESPACE);
+            let variables = match filter_variables(message_variables, &self.chat) {
+                Ok(variables) => variables,
+                Err(e) => {
+                    lines.push(format!("- {}: error [{}]: {e}", case.name, e.number()));
+                    continue;
+                }
+            };
+
+            let mut errors = Vec::new();
+            let matched = if self.chat.enabled_filters().next().is_none() {
+                (self.chat.settings.default_verdict() == Verdict::Deny)
+                    .then(|| DEFAULT_VERDICT_FILTER_NAME.to_string())
+            } else {
+                first_match(
+                    self.chat
+                        .enabled_filters()
+                        .filter(|filter| !filter.shadow)
+                        .map(|filter| (filter.name.as_str(), filter)),
+                    |filter| {
+                        let evaluation = self.chat.evaluate(
+                            &filter.expression,
+                            &variables,
+                            &Sandbox::new(&EmptyEnvironment),
+                        );
+                        (evaluation, Vec::new())
+                    },
+                    &mut errors,
+                )
+                .map(|(name, _)| name)
+            };
+
+            let outcome = match &matched {
+                Some(name) => format!("matched by {name}"),
+                None => "no match".to_string(),
+            };
+            if matched.is_some() == case.expect_match {
+                passed += 1;
+                lines.push(format!("- {}: pass, {outcome}", case.name));
+            } else {
+                lines.push(format!(
+                    "- {}: fail, expected {}, got {outcome}",
+                    case.name,
+                    describe(case.expect_match)
+                ));
+            }
+            lines.extend(errors.into_iter().map(|error| format!("  {error}")));
+        }
+
+        format!(
+            "{passed}/{} test cases passed\n{}",
+            self.chat.test_cases.len(),
+            lines.join("\n")
+        )
+    }
+
+    /// Adds the user given by `arg` or by the replied message to the trusted
+    /// users of the chat, or removes them.
+    fn set_trusted(
+        &mut self,
+        message: &Message,
+        arg: Option<&str>,
+        trust: bool,
+    ) -> Result<(), String> {
+        let user_id = target_user_id(message, arg)?;
+        self.chat.trusted_ids.retain(|id| *id != user_id);
+        if trust {
+            self.chat.trusted_ids.push(user_id);
+        }
+
+        Ok(())
+    }
+
+    /// Exempts the user given by `arg`, either an id or an @username, or by
+    /// the replied message from filtering, or filters them again.
+    fn set_exempt(
+        &mut self,
+        message: &Message,
+        arg: Option<&str>,
+        exempt: bool,
+    ) -> Result<(), String> {
+        if let Some(username) = arg.and_then(|arg| arg.trim().strip_prefix('@')) {
+            let username = username.to_lowercase();
+            self.chat.exempt_usernames.retain(|name| *name != username);
+            if exempt {
+                self.chat.exempt_usernames.push(username);
+            }
+            return Ok(());
+        }
+
+        let user_id = target_user_id(message, arg)?;
+        self.chat.exempt_ids.retain(|id| *id != user_id);
+        if exempt {
+            self.chat.exempt_ids.push(user_id);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the domains of `arg`, given bare or as links, to the blocked
+    /// domains or removes them.
+    fn set_blocked_domains(&mut self, arg: &str, block: bool) -> Result<(), String> {
+        let mut domains = Vec::new();
+        for word in arg.split_whitespace() {
+            match domain(word) {
+                Some(domain) => domains.push(domain),
+                None => {
+                    return Err(format!(
+                        "error [{INVALID_INPUT}]: \"{word}\" is not a domain"
+                    ))
+                }
+            }
+        }
+
+        let blocked = &mut self.chat.blocked_domains;
+        if block {
+            for domain in domains {
+                if let Err(index) = blocked.binary_search(&domain) {
+                    if blocked.len() >= MAX_BLOCKED_DOMAINS {
+                        return Err(format!(
+                            "error [{LIMIT_EXCEEDED}]: a chat can block at most {MAX_BLOCKED_DOMAINS} domains"
+                        ));
+                    }
+                    blocked.insert(index, domain);
+                }
+            }
+        } else {
+            blocked.retain(|blocked| !domains.contains(blocked));
+        }
+
+        Ok(())
+    }
+
+    /// Adds the forward origin given by `arg`, an id or a @username, to the
+    /// blocked ones or removes it.
+    fn set_blocked_origin(&mut self, arg: &str, block: bool) -> Result<(), String> {
+        let arg = arg.trim();
+        let count = self.chat.blocked_origin_ids.len() + self.chat.blocked_origin_usernames.len();
+        if let Some(username) = arg.strip_prefix('@') {
+            let username = username.to_lowercase();
+            self.chat
+                .blocked_origin_usernames
+                .retain(|name| *name != username);
+            if block {
+                if count >= MAX_BLOCKED_ORIGINS {
+                    return Err(format!(
+                        "error [{LIMIT_EXCEEDED}]: a chat can block at most {MAX_BLOCKED_ORIGINS} forward sources"
+                    ));
+                }
+                self.chat.blocked_origin_usernames.push(username);
+            }
+            return Ok(());
+        }
+
+        let Ok(id) = arg.parse::<i64>() else {
+            return Err(format!(
+                "error [{INVALID_INPUT}]: invalid forward source \"{arg}\", expected an id or a @username"
+            ));
+        };
+        self.chat
+            .blocked_origin_ids
+            .retain(|blocked| *blocked != id);
+        if block {
+            if count >= MAX_BLOCKED_ORIGINS {
+                return Err(format!(
+                    "error [{LIMIT_EXCEEDED}]: a chat can block at most {MAX_BLOCKED_ORIGINS} forward sources"
+                ));
+            }
+            self.chat.blocked_origin_ids.push(id);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a message caught by a blocklist, whatever the filters say.
+    async fn delete_blocked(
+        &self,
+        message: &Message,
+        filter_name: &str,
+        reason: &str,
+        result: &mut Vec<SendUpdate>,
+    ) {
+        result.push(self.delete_message(message.id));
+        result.extend(self.log_update(format!(
+            "deleted a message by {} ({reason}):\n{}",
+            describe_sender(message),
+            message.text().or(message.caption()).unwrap_or("")
+        )));
+        if self.chat.settings.should_report_filtered() {
+            let text = self
+                .filtered_message(message, filter_name)
+                .unwrap_or_else(|| {
+                    format!("message of {} filtered: {reason}", author_name(message))
+                });
+            result.push(SendUpdate::message(text));
+        }
+
+        let mut db_lock = self.db.lock().await;
+        let recorded = db_lock
+            .write_or_queue(PendingWrite::Filtered {
+                chat_id: self.chat_id.0,
+                time: message.date.timestamp(),
+                filter_name: filter_name.to_string(),
+                user_id: message.from.as_ref().map(|from| from.id.0 as i64),
+                action: Action::Delete,
+                shadow: false,
+                event_id: ObjectId::new(),
+            })
+            .await;
+        drop(db_lock);
+        self.report_persistence(recorded, "statistics", result);
+    }
+
+    /// Blocks the media of the replied message, or unblocks it.
+    async fn set_blocked_media(&self, message: &Message, block: bool) -> Result<(), String> {
+        let Some(target) = message.reply_to_message() else {
+            return Err(format!("error [{NO_REPLY_MESSAGE}]: no reply message"));
+        };
+        let Some(file_unique_id) = media_ids(target).pop() else {
+            return Err(format!(
+                "error [{NO_REPLY_MESSAGE}]: the replied message has no media"
+            ));
+        };
+
+        let db_lock = self.db.lock().await;
+        if !block {
+            // A photo is blocked by its largest size, but any of them is
+            // removed in case the replied copy was sent in fewer sizes.
+            for file_unique_id in media_ids(target) {
+                db_lock
+                    .remove_blocked_media(self.chat_id.0, &file_unique_id)
+                    .await
+                    .map_err(|e| {
+                        format!("error [{REQUEST_FAILED}]: failed to unblock the media: {e}")
+                    })?;
+            }
+            return Ok(());
+        }
+
+        let count = db_lock
+            .count_blocked_media(self.chat_id.0)
+            .await
+            .map_err(|e| format!("error [{REQUEST_FAILED}]: failed to block the media: {e}"))?;
+        if count >= MAX_BLOCKED_MEDIA {
+            return Err(format!(
+                "error [{LIMIT_EXCEEDED}]: a chat can block at most {MAX_BLOCKED_MEDIA} media"
+            ));
+        }
+        db_lock
+            .insert_blocked_media(&BlockedMedia {
+                chat_id: self.chat_id.0,
+                file_unique_id,
+                author_id: message.from.as_ref().map(|from| from.id.0 as i64),
+                time: message.date.timestamp(),
+            })
+            .await
+            .map_err(|e| format!("error [{REQUEST_FAILED}]: failed to block the media: {e}"))
+    }
+
+    async fn has_blocked_media(&self, message: &Message) -> bool {
+        let file_unique_ids = media_ids(message);
+        if file_unique_ids.is_empty() {
+            return false;
+        }
+
+        let db_lock = self.db.lock().await;
+        if db_lock.pending_write_count() > 0 {
+            // The database is unavailable, do not wait for it to time out.
+            return false;
+        }
+        match db_lock
+            .find_blocked_media(self.chat_id.0, &file_unique_ids)
+            .await
+        {
+            Ok(media) => media.is_some(),
+            Err(e) => {
+                log::error!("Failed to look up blocked media in {}: {e}", self.chat_id);
+                false
+            }
+        }
+    }
+
+    fn is_blocked_forward(&self, message: &Message) -> bool {
+        let Some(origin) = message.forward_origin() else {
+            return false;
+        };
+
+        let (id, username) = match origin {
+            MessageOrigin::User { sender_user, .. } => (
+                Some(sender_user.id.0 as i64),
+                sender_user.username.as_deref(),
+            ),
+            MessageOrigin::HiddenUser { .. } => (None, None),
+            MessageOrigin::Chat { sender_chat, .. } => {
+                (Some(sender_chat.id.0), sender_chat.username())
+            }
+            MessageOrigin::Channel { chat, .. } => (Some(chat.id.0), chat.username()),
+        };
+        self.chat.is_blocked_origin(id, username)
+    }
+
+    fn links_to_blocked_domain(&self, message: &Message) -> bool {
+        !self.chat.blocked_domains.is_empty()
+            && message_urls(message)
+                .iter()
+                .filter_map(|url| domain(url))
+                .any(|domain| self.chat.is_blocked_domain(&domain))
+    }
+
+    /// Whether this is the first message of the sender in the chat, `None` in
+    /// private chats or if the database could not tell.
+    async fn mark_user_seen(&mut self, message: &Message) -> Option<bool> {
+        let from = message.from.as_ref()?;
+        if message.chat.is_private() {
+            return None;
+        }
+
+        let user_id = from.id.0 as i64;
+        if self.seen_users.contains(&user_id) {
+            return Some(false);
+        }
+
+        let db_lock = self.db.lock().await;
+        if db_lock.pending_write_count() > 0 {
+            // The database is unavailable, do not wait for it to time out.
+            return None;
+        }
+        let marked = db_lock
+            .mark_user_seen(self.chat_id.0, user_id, message.date.timestamp())
+            .await;
+        drop(db_lock);
+        match marked {
+            Ok(is_first) => {
+                self.seen_users.insert(user_id);
+                Some(is_first)
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to mark user {user_id} seen in {}: {e}",
+                    self.chat_id
+                );
+                None
+            }
+        }
+    }
+
+    fn record_filtered_event(&mut self, message: &Message) {
+        self.events
+            .record(events::FILTERED.to_string(), message.date);
+        if let Some(from) = &message.from {
+            self.events.record(
+                events::user_event(events::FILTERED, from.id.0 as i64),
+                message.date,
+            );
+        }
+    }
+
+    /// Messages the sender sent in the last flood_window seconds, if that is
+    /// more than flood_messages.
+    fn flood_count(&self, message: &Message) -> Option<i64> {
+        let limit = self.chat.settings.flood_messages;
+        let from = message.from.as_ref()?;
+        if limit == 0 {
+            return None;
+        }
+
+        let event = events::user_event(events::MESSAGES, from.id.0 as i64);
+        let since = message.date - TimeDelta::seconds(self.chat.settings.flood_window);
+        let count = self.events.count(&event, since);
+        (count > limit).then_some(count)
+    }
+
+    /// Applies flood_action to a message over the flood limit, `count` being
+    /// the messages of its sender in the window. Only the first message over
+    /// the limit is warned about, reported or gets its sender restricted.
+    fn flood_updates(&self, message: &Message, count: i64, from_admin: bool) -> Vec<SendUpdate> {
+        let action = self.chat.settings.flood_action();
+        let mut result = Vec::new();
+        if action.deletes() {
+            result.push(self.delete_message(message.id));
+        }
+        if count != self.chat.settings.flood_messages + 1 {
+            return result;
+        }
+
+        result.extend(self.log_update(format!(
+            "{} sent {count} messages in {} seconds, action {action}",
+            describe_sender(message),
+            self.chat.settings.flood_window
+        )));
+        match action {
+            Action::Warn => result.push(SendUpdate::Message {
+                text: "warning: you are sending messages too fast".to_string(),
+                reply_to: Some(message.id),
+                parse_mode: None,
+            }),
+            Action::Report => result.push(SendUpdate::message(format!(
+                "report: {} is flooding the chat",
+                author_name(message)
+            ))),
+            _ => {}
+        }
+        if let Some(from) = message.from.as_ref().filter(|_| !from_admin) {
+            match action {
+                Action::Mute(minutes) => result.push(mute(from.id, minutes)),
+                Action::Ban => result.push(SendUpdate::BanUser {
+                    user_id: from.id,
+                    duration: None,
+                }),
+                _ => {}
+            }
+        }
+
+        result
+    }
+
+    fn delete_message(&self, message_id: MessageId) -> SendUpdate {
+        let max_delay = self.chat.settings.max_delete_delay_seconds;
+        if max_delay <= 0 {
+            return SendUpdate::DeleteMessage(message_id);
+        }
+
+        let delay = rand::thread_rng().gen_range(1..=max_delay) as u64;
+        SendUpdate::DelayedDeleteMessage {
+            message_id,
+            delay: Duration::from_secs(delay),
+        }
+    }
+
+    /// Evaluates for a message without applying the effects, which are
+    /// returned for the caller to commit if it acts on the message.
+    fn evaluate_for_message(
+        &self,
+        message: &Message,
+        evaluate: impl FnOnce(&Sandbox) -> EvaluationResult,
+    ) -> (EvaluationResult, Vec<Effect>) {
+        let environment = MessageEnvironment::new(
+            &self.events,
+            &self.chat,
+            message.from.as_ref().map(|from| from.id.0 as i64),
+            message.date,
+        );
+        let sandbox = Sandbox::new(&environment);
+        let result = evaluate(&sandbox);
+        (result, sandbox.into_effects())
+    }
+
+    fn get_option(&self, arg: &str) -> Result<String, String> {
+        let name = self
+            .identifier_parser
+            .parse(arg.trim())
+            .map_err(|e| format!("parse error [{}]: {e}", parse_error::number(&e)))?;
+        let variables = Variables::from(self.chat.settings.clone());
+        match variables.get(&name) {
+            Some(value) => Ok(format!("{name} = {value} ({})", value.type_str())),
+            None => Err(format!("error [{NOT_FOUND}]: no option named \"{name}\"")),
+        }
+    }
+
+    /// Replaces the setup of the chat with one exported by /export_config,
+    /// checked like the commands setting each part would. Filters and
+    /// definitions are parsed again from their text. The log chat is kept,
+    /// since /set_option checks the sender administers it.
+    fn import_config(&mut self, json: &str) -> Result<(), String> {
+        let mut config: ChatConfig = serde_json::from_str(json)
+            .map_err(|e| format!("error [{INVALID_CONFIG}]: invalid config: {e}"))?;
+        config.settings.log_chat_id = self.chat.settings.log_chat_id;
+        config
+            .settings
+            .check_constraints()
+            .map_err(|e| format!("error [{INVALID_CONFIG}]: invalid config: {e}"))?;
+
+        let mut names = HashSet::new();
+        for filter in &mut config.filters {
+            self.check_filter_name(&filter.name)?;
+            if !names.insert(filter.name.clone()) {
+                return Err(format!(
+                    "error [{INVALID_CONFIG}]: invalid config: two filters named \"{}\"",
+                    filter.name
+                ));
+            }
+            if filter.text.len() > MAX_FILTER_LENGTH {
+                return Err(format!(
+                    "error [{INVALID_CONFIG}]: invalid config: filter {} is longer than {MAX_FILTER_LENGTH} bytes",
+                    filter.name
+                ));
+            }
+            let expression = self.expression_parser.parse(&filter.text).map_err(|e| {
+                format!(
+                    "parse error [{}]: failed to parse filter {}: {e}",
+                    parse_error::number(&e),
+                    filter.name
+                )
+            })?;
+            *filter = filter.reparsed(*expression);
+        }
+
+        for name in config.variables.keys() {
+            if !self.is_variable_name(name) {
+                return Err(format!(
+                    "error [{INVALID_CONFIG}]: invalid config: \"{name}\" is not a valid variable name"
+                ));
+            }
+            if config.definitions.contains(name) {
+                return Err(format!(
+                    "error [{INVALID_CONFIG}]: invalid config: \"{name}\" is both a variable and a definition"
+                ));
+            }
+        }
+
+        let mut definitions = DefinedExpressions::new();
+        for (name, definition) in config.definitions.iter() {
+            if name.contains(NAMESPACE_SEPARATOR) {
+                return Err(format!(
+                    "error [{INVALID_CONFIG}]: invalid config: definition \"{name}\" is namespaced"
+                ));
+            }
+            if MessageVariables::default().contains_variable(name) {
+                return Err(format!(
+                    "error [{INVALID_CONFIG}]: invalid config: definition \"{name}\" is a variable"
+                ));
+            }
+            if definition.text.len() > MAX_FILTER_LENGTH {
+                return Err(format!(
+                    "error [{INVALID_CONFIG}]: invalid config: definition {name} is longer than {MAX_FILTER_LENGTH} bytes"
+                ));
+            }
+            let expression = self
+                .expression_parser
+                .parse(&definition.text)
+                .map_err(|e| {
+                    format!(
+                        "parse error [{}]: failed to parse definition {name}: {e}",
+                        parse_error::number(&e)
+                    )
+                })?;
+            let definition = Definition {
+                text: definition.text.clone(),
+                expression: *expression,
+            };
+            definitions.put(name.clone(), definition).map_err(|e| {
+                format!("error [{INVALID_CONFIG}]: invalid config: definition {name}: {e}")
+            })?;
+        }
+        config.definitions = definitions;
+
+        if config.aliases.len() > MAX_ALIASES {
+            return Err(format!(
+                "error [{LIMIT_EXCEEDED}]: a chat can have at most {MAX_ALIASES} aliases"
+            ));
+        }
+        for (name, command) in &config.aliases {
+            self.check_alias(name, command)?;
+        }
+
+        if config.blocked_domains.len() > MAX_BLOCKED_DOMAINS {
+            return Err(format!(
+                "error [{LIMIT_EXCEEDED}]: a chat can block at most {MAX_BLOCKED_DOMAINS} domains"
+            ));
+        }
+        for blocked in &mut config.blocked_domains {
+            *blocked = domain(blocked)
+                .ok_or_else(|| format!("error [{INVALID_INPUT}]: \"{blocked}\" is not a domain"))?;
+        }
+        config.blocked_domains.sort();
+        config.blocked_domains.dedup();
+
+        if config.blocked_origin_ids.len() + config.blocked_origin_usernames.len()
+            > MAX_BLOCKED_ORIGINS
+        {
+            return Err(format!(
+                "error [{LIMIT_EXCEEDED}]: a chat can block at most {MAX_BLOCKED_ORIGINS} forward sources"
+            ));
+        }
+        for username in &mut config.blocked_origin_usernames {
+            *username = username.trim_start_matches('@').to_lowercase();
+        }
+
+        self.chat.apply_config(config);
+        Ok(())
+    }
+
+    /// Sets the user variables exported with `Variables::to_json`, nothing is
+    /// set if any of them can't be.
+    fn import_variables(&mut self, json: &str) -> Result<(), EvaluationError> {
+        let variables = Variables::from_json(json)?;
+        for name in variables.keys() {
+            if !self.is_variable_name(name) {
+                return Err(ValueError::new_other(format!(
+                    "\"{name}\" is not a valid variable name"
+                ))
+                .into());
+            }
+            if self.chat.definitions.contains(name) {
+                return Err(ValueError::new_other(format!("\"{name}\" is reserved")).into());
+            }
+        }
+
+        self.chat
+            .variables
+            .merge(variables, MergePolicy::PreferRight)
+    }
+
+    /// Whether `name` is an identifier outside of any namespace.
+    fn is_variable_name(&self, name: &str) -> bool {
+        self.identifier_parser
+            .parse(name)
+            .is_ok_and(|parsed| parsed == name)
+            && !name.contains(NAMESPACE_SEPARATOR)
+    }
+
+    fn user_variables(&self) -> Variables {
+        self.chat.variables.clone().into_namespace(USER_NAMESPACE)
+    }
+
+    /// Report for the log chat, `None` if the chat has none.
+    fn log_update(&self, text: String) -> Option<SendUpdate> {
+        let log_chat_id = self.chat.settings.log_chat_id;
+        if log_chat_id == 0 {
+            return None;
+        }
+
+        let title = self
+            .chat
+            .title
+            .clone()
+            .unwrap_or_else(|| self.chat_id.to_string());
+        Some(SendUpdate::Log {
+            chat_id: ChatId(log_chat_id),
+            text: format!("{title}: {text}"),
+            buttons: Vec::new(),
+        })
+    }
+
+    /// Report of a restriction an admin asked for with `message`.
+    fn log_restriction(&self, message: &Message, update: &SendUpdate) -> Option<SendUpdate> {
+        let restriction = match update {
+            SendUpdate::RestrictUser {
+                user_id, duration, ..
+            } => format!("muted {user_id} for {} minutes", duration.as_secs() / 60),
+            SendUpdate::BanUser {
+                user_id,
+                duration: Some(duration),
+            } => format!("banned {user_id} for {} minutes", duration.as_secs() / 60),
+            SendUpdate::BanUser {
+                user_id,
+                duration: None,
+            } => format!("banned {user_id}"),
+            SendUpdate::UnbanUser(user_id) => format!("unbanned {user_id}"),
+            _ => return None,
+        };
+
+        self.log_update(format!("{} {restriction}", author_name(message)))
+    }
+
+    /// Whether the sender of `message` administers `chat_id`, as far as the
+    /// database knows, or it is their private chat with the bot.
+    async fn administers(&self, message: &Message, chat_id: i64) -> bool {
+        let Some(from) = &message.from else {
+            return false;
+        };
+        let user_id = from.id.0 as i64;
+        if chat_id == user_id {
+            return true;
+        }
 
         let db_lock = self.db.lock().await;
-        db_lock.insert_chat(&self.chat).await?;
+        let chat = db_lock.find_chat(chat_id).await;
         drop(db_lock);
+        match chat {
+            Ok(chat) => chat.is_some_and(|chat| chat.admin_ids.contains(&user_id)),
+            Err(e) => {
+                log::error!("Failed to find chat {chat_id}: {e}");
+                false
+            }
+        }
+    }
+
+    /// Parses `text` and stores it as the filter `name`, replacing a filter
+    /// with the same name in place. Returns the lint warnings of the filter.
+    fn add_filter(
+        &mut self,
+        message: &Message,
+        name: &str,
+        text: &str,
+    ) -> Result<Vec<Warning>, String> {
+        self.check_filter_name(name)?;
+        if text.len() > MAX_FILTER_LENGTH {
+            return Err(format!(
+                "error [{LIMIT_EXCEEDED}]: a filter can't be longer than {MAX_FILTER_LENGTH} bytes"
+            ));
+        }
+
+        let expression = self
+            .expression_parser
+            .parse(text)
+            .map_err(|e| format!("parse error [{}]: {e}", parse_error::number(&e)))?;
+        let undeclared = self.undeclared_identifiers(&expression);
+        if self.chat.settings.identifier_mode() == IdentifierMode::Strict && !undeclared.is_empty()
+        {
+            return Err(format!(
+                "failed to set filter [{}]: undeclared identifier(s) {}",
+                error_code::number("undeclared_identifier"),
+                undeclared.join(", ")
+            ));
+        }
+
+        let warnings = self.lint(&expression);
+        self.record_filter_version(message, name);
+        self.chat
+            .set_filter(Filter::new(name.to_string(), text.to_string(), *expression));
+        Ok(warnings)
+    }
+
+    /// Filter names end up in database paths like `filters.<name>`, so they
+    /// are plain identifiers.
+    fn check_filter_name(&self, name: &str) -> Result<(), String> {
+        if !self.is_variable_name(name) {
+            return Err(format!(
+                "error [{INVALID_INPUT}]: \"{name}\" is not a valid filter name"
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn remove_filter(&mut self, message: &Message, name: &str) -> Result<(), String> {
+        if self.chat.filter(name).is_none() {
+            return Err(format!("error [{NOT_FOUND}]: no filter named \"{name}\""));
+        }
+
+        self.record_filter_version(message, name);
+        self.chat.remove_filter(name);
+        Ok(())
+    }
+
+    /// Restores the text a filter had before the `arg`th latest change, 1 by
+    /// default. The revert is itself recorded so it can be undone.
+    fn revert_filter(
+        &mut self,
+        message: &Message,
+        arg: Option<&str>,
+    ) -> Result<Vec<Warning>, String> {
+        let n = match arg {
+            Some(arg) => arg
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| format!("error [{INVALID_INPUT}]: invalid change number \"{arg}\""))?,
+            None => 1,
+        };
+        let history = &self.chat.filter_history;
+        let Some(version) = n
+            .checked_sub(1)
+            .and_then(|i| history.iter().rev().nth(i))
+            .cloned()
+        else {
+            return Err(format!("error [{NOT_FOUND}]: no filter change number {n}"));
+        };
+
+        match version.text {
+            Some(text) => self.add_filter(message, &version.name, &text),
+            None => self
+                .remove_filter(message, &version.name)
+                .map(|_| Vec::new()),
+        }
+    }
+
+    fn record_filter_version(&mut self, message: &Message, name: &str) {
+        self.chat.record_filter_version(
+            name,
+            message.from.as_ref().map(|from| from.id.0 as i64),
+            message.date.timestamp(),
+        );
+    }
+
+    /// Sets the action of a filter, `arg` is `<name> <action>`.
+    fn set_filter_action(&mut self, arg: &str) -> Result<(), String> {
+        let (name, Some(action)) = split_first_word(arg, char::is_whitespace) else {
+            return Err(format!(
+                "error [{INVALID_ARGUMENTS}]: expected <name> <action>"
+            ));
+        };
+        let action = action
+            .parse::<Action>()
+            .map_err(|e| format!("error [{INVALID_INPUT}]: {e}"))?;
+        match self.chat.filter_mut(name) {
+            Some(filter) => {
+                filter.action = action;
+                Ok(())
+            }
+            None => Err(format!("error [{NOT_FOUND}]: no filter named \"{name}\"")),
+        }
+    }
+
+    fn set_filter_schedule(&mut self, arg: &str) -> Result<(), String> {
+        let (name, Some(schedule)) = split_first_word(arg, char::is_whitespace) else {
+            return Err(format!(
+                "error [{INVALID_ARGUMENTS}]: expected <name> <schedule>"
+            ));
+        };
+        let schedule = match schedule.trim() {
+            "always" => None,
+            schedule => Some(
+                schedule
+                    .parse::<Schedule>()
+                    .map_err(|e| format!("error [{INVALID_INPUT}]: {e}"))?,
+            ),
+        };
+        match self.chat.filter_mut(name) {
+            Some(filter) => {
+                filter.schedule = schedule;
+                Ok(())
+            }
+            None => Err(format!("error [{NOT_FOUND}]: no filter named \"{name}\"")),
+        }
+    }
+
+    /// `arg` is `<name> <time>`, with a duration from `now` or a local time.
+    fn set_filter_expiry(&mut self, arg: &str, now: DateTime<Utc>) -> Result<(), String> {
+        let (name, Some(time)) = split_first_word(arg, char::is_whitespace) else {
+            return Err(format!(
+                "error [{INVALID_ARGUMENTS}]: expected <name> <time>"
+            ));
+        };
+        let time = time.trim();
+        let expires_at = if time == "never" {
+            None
+        } else if let Ok(local) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M") {
+            let expires_at = local
+                .and_local_timezone(self.utc_offset())
+                .single()
+                .ok_or_else(|| format!("error [{INVALID_INPUT}]: invalid time \"{time}\""))?;
+            Some(expires_at.timestamp())
+        } else {
+            let duration = builtins::parse_duration(&Value::Str(time.to_string()))
+                .map_err(|_| {
+                    format!(
+                        "error [{INVALID_INPUT}]: invalid time \"{time}\", expected a duration like 12h, YYYY-MM-DD HH:MM or never"
+                    )
+                })?;
+            let expires_at = now
+                .checked_add_signed(duration)
+                .ok_or_else(|| format!("error [{INVALID_INPUT}]: invalid time \"{time}\""))?;
+            Some(expires_at.timestamp())
+        };
+        if expires_at.is_some_and(|expires_at| expires_at <= now.timestamp()) {
+            return Err(format!(
+                "error [{INVALID_INPUT}]: the time has already passed"
+            ));
+        }
+
+        match self.chat.filter_mut(name) {
+            Some(filter) => {
+                filter.expires_at = expires_at;
+                Ok(())
+            }
+            None => Err(format!("error [{NOT_FOUND}]: no filter named \"{name}\"")),
+        }
+    }
 
-        Ok(result)
+    /// Time zone of the chat, UTC if utc_offset_minutes is out of range.
+    fn utc_offset(&self) -> FixedOffset {
+        self.chat
+            .settings
+            .utc_offset_minutes
+            .checked_mul(60)
+            .and_then(|seconds| i32::try_from(seconds).ok())
+            .and_then(FixedOffset::east_opt)
+            .unwrap_or(FixedOffset::east_opt(0).expect("zero offset is valid"))
+    }
+
+    /// Current time in the time zone of the chat.
+    fn local_time(&self, time: DateTime<Utc>) -> NaiveDateTime {
+        time.with_timezone(&self.utc_offset()).naive_local()
+    }
+
+    /// Name of a filter with its action and state, for listings.
+    fn describe_filter(&self, filter: &Filter) -> String {
+        let mut details = vec![filter.action.to_string()];
+        if !filter.enabled {
+            details.push("disabled".to_string());
+        }
+        if filter.shadow {
+            details.push("shadow".to_string());
+        }
+        if let Some(schedule) = &filter.schedule {
+            details.push(format!("active {schedule}"));
+        }
+        if let Some(expires_at) = filter.expires_at {
+            let expires_at = DateTime::from_timestamp(expires_at, 0).unwrap_or_default();
+            details.push(format!(
+                "expires {}",
+                self.local_time(expires_at).format("%Y-%m-%d %H:%M")
+            ));
+        }
+
+        format!("{} ({})", filter.name, details.join(", "))
+    }
+
+    fn set_filter_shadow(&mut self, name: &str, shadow: bool) -> Result<(), String> {
+        match self.chat.filter_mut(name) {
+            Some(filter) => {
+                filter.shadow = shadow;
+                Ok(())
+            }
+            None => Err(format!("error [{NOT_FOUND}]: no filter named \"{name}\"")),
+        }
+    }
+
+    fn set_filter_enabled(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        match self.chat.filter_mut(name) {
+            Some(filter) => {
+                filter.enabled = enabled;
+                Ok(())
+            }
+            None => Err(format!("error [{NOT_FOUND}]: no filter named \"{name}\"")),
+        }
+    }
+
+    /// Likely mistakes in a filter, with definitions expanded.
+    fn lint(&self, expression: &Expression) -> Vec<Warning> {
+        let Ok(expression) = self.chat.definitions.expand(expression) else {
+            return Vec::new();
+        };
+
+        let Ok(variables) = filter_variables(MessageVariables::default().into(), &self.chat) else {
+            return Vec::new();
+        };
+        lint::lint(&expression, |identifier| {
+            variables.contains_variable(identifier)
+        })
+    }
+
+    fn undeclared_identifiers(&self, expression: &Expression) -> Vec<String> {
+        let Ok(expression) = self.chat.definitions.expand(expression) else {
+            return Vec::new();
+        };
+
+        let Ok(variables) = filter_variables(MessageVariables::default().into(), &self.chat) else {
+            return Vec::new();
+        };
+        expression
+            .identifiers()
+            .into_iter()
+            .filter(|identifier| !variables.contains_variable(identifier))
+            .map(|identifier| format!("\"{identifier}\""))
+            .collect()
+    }
+
+    /// Updates the title and, when they were fetched, the administrators of
+    /// the chat. The known administrators are kept if they couldn't be.
+    fn update_chat_info(&mut self, message: &Message, admin_ids: Option<&[UserId]>) {
+        if message.chat.is_private() {
+            return;
+        }
+
+        self.chat.title = message.chat.title().map(|title| title.to_string());
+
+        if let Some(admin_ids) = admin_ids {
+            self.chat.admin_ids = admin_ids.iter().map(|id| id.0 as i64).collect();
+            self.admins_fetched = Some(Instant::now());
+        }
+    }
+
+    async fn check_forwarded_message(&mut self, message: &Message) -> SendUpdate {
+        let variables = Variables::from(MessageVariables::from(message));
+        let text = format!("{variables}");
+
+        let chats = match &message.from {
+            Some(from) => {
+                let db_lock = self.db.lock().await;
+                let chats = db_lock.find_chats_by_admin(from.id.0 as i64).await;
+                drop(db_lock);
+                match chats {
+                    Ok(chats) => chats,
+                    Err(e) => {
+                        log::error!("Failed to find the chats administered by {}: {e}", from.id);
+                        return SendUpdate::message(format!(
+                            "error [{REQUEST_FAILED}]: failed to find the chats you administer: {e}"
+                        ));
+                    }
+                }
+            }
+            None => Vec::new(),
+        };
+
+        self.pending_check = Some(variables);
+
+        if chats.is_empty() {
+            return SendUpdate::message(format!(
+                "{text}\nyou are not an admin of any chat to check this message against"
+            ));
+        }
+
+        let buttons = chats
+            .iter()
+            .map(|chat| {
+                let label = match &chat.title {
+                    Some(title) => title.clone(),
+                    None => chat.chat_id.to_string(),
+                };
+                let callback = Callback::Check {
+                    chat_id: chat.chat_id,
+                };
+                (label, callback.to_string())
+            })
+            .collect();
+
+        SendUpdate::MessageWithButtons {
+            text: format!("{text}\nchoose a chat to check this message against:"),
+            buttons,
+            reply_to: None,
+        }
+    }
+}
+
+/// Result of an expression typed in an inline query. Inline queries don't
+/// come from a chat, so it is evaluated with the default options and without
+/// variables.
+pub fn evaluate_inline_query(text: &str) -> String {
+    let expression = match ExpressionParser::new().parse(text) {
+        Ok(expression) => expression,
+        Err(e) => return format!("parse error [{}]: {e}", parse_error::number(&e)),
+    };
+    let evaluation = Chat::default().evaluate(
+        &expression,
+        &Variables::new(),
+        &Sandbox::new(&EmptyEnvironment),
+    );
+    match evaluation {
+        Ok(value) => value.to_string(),
+        Err(e) => format!("error [{}]: failed to evalute expression: {e}", e.number()),
+    }
+}
+
+/// Document a message replies to if it is an /import_config command without
+/// an argument, its content is expected by `Session::handle_message`.
+pub fn requested_document(message: &Message) -> Option<&Document> {
+    let (command, arg) = split_first_word(message.text()?, char::is_whitespace);
+    let (command, _) = split_first_word(command, |c| c == '@');
+    if command != "/import_config" || arg.is_some() {
+        return None;
+    }
+
+    message.reply_to_message()?.document()
+}
+
+fn copy_chat_config(source: &Chat, destination: &mut Chat) {
+    destination.filters = source.filters.clone();
+    destination.settings = source.settings.clone();
+    destination.variables = source.variables.clone();
+    destination.definitions = source.definitions.clone();
+}
+
+const REMOVED_LINK_PLACEHOLDER: &str = "[link removed]";
+
+/// Links of the message text and caption, both written out and hidden behind
+/// text.
+fn message_urls(message: &Message) -> Vec<String> {
+    message
+        .parse_entities()
+        .into_iter()
+        .chain(message.parse_caption_entities())
+        .flatten()
+        .filter_map(|entity| match entity.kind() {
+            MessageEntityKind::Url => Some(entity.text().to_string()),
+            MessageEntityKind::TextLink { url } => Some(url.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Unique ids of the files of the message, photos have one per size with the
+/// largest last.
+fn media_ids(message: &Message) -> Vec<String> {
+    if let Some(sizes) = message.photo() {
+        return sizes
+            .iter()
+            .map(|size| size.file.unique_id.clone())
+            .collect();
+    }
+
+    let file = message
+        .sticker()
+        .map(|sticker| &sticker.file)
+        .or_else(|| message.animation().map(|animation| &animation.file))
+        .or_else(|| message.document().map(|document| &document.file))
+        .or_else(|| message.video().map(|video| &video.file))
+        .or_else(|| message.video_note().map(|video_note| &video_note.file))
+        .or_else(|| message.audio().map(|audio| &audio.file))
+        .or_else(|| message.voice().map(|voice| &voice.file));
+    file.map(|file| file.unique_id.clone())
+        .into_iter()
+        .collect()
+}
+
+/// Lowercase host of a link, which may lack the scheme.
+fn domain(url: &str) -> Option<String> {
+    let rest = match url.find("://") {
+        Some(index) => &url[index + 3..],
+        None => url,
+    };
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?;
+    let host = host.split(':').next()?.trim_end_matches('.');
+
+    (!host.is_empty()).then(|| host.to_lowercase())
+}
+
+/// Text or caption of the message with every link replaced by a placeholder,
+/// `None` if it has no links.
+fn remove_links(message: &Message) -> Option<String> {
+    let text = message.text().or_else(|| message.caption())?;
+    let entities = message
+        .parse_entities()
+        .or_else(|| message.parse_caption_entities())?;
+
+    let mut links: Vec<(Range<usize>, bool)> = entities
+        .iter()
+        .filter_map(|entity| match entity.kind() {
+            MessageEntityKind::Url => Some((entity.range(), true)),
+            MessageEntityKind::TextLink { .. } => Some((entity.range(), false)),
+            _ => None,
+        })
+        .collect();
+    if links.is_empty() {
+        return None;
+    }
+    links.sort_by_key(|(range, _)| range.start);
+
+    let mut result = String::with_capacity(text.len());
+    let mut position = 0;
+    for (range, is_url) in links {
+        if range.start < position {
+            continue;
+        }
+        result.push_str(&text[position..range.start]);
+        if is_url {
+            result.push_str(REMOVED_LINK_PLACEHOLDER);
+        } else {
+            result.push_str(&format!(
+                "{} {REMOVED_LINK_PLACEHOLDER}",
+                &text[range.clone()]
+            ));
+        }
+        position = range.end;
+    }
+    result.push_str(&text[position..]);
+
+    Some(result)
+}
+
+/// Author name followed by their id, for reports that outlive usernames.
+fn describe_sender(message: &Message) -> String {
+    match &message.from {
+        Some(from) => format!("{} ({})", author_name(message), from.id),
+        None => author_name(message),
+    }
+}
+
+fn author_name(message: &Message) -> String {
+    match &message.from {
+        Some(from) => match &from.username {
+            Some(username) => format!("@{username}"),
+            None => from.full_name(),
+        },
+        None => "someone".to_string(),
+    }
+}
+
+/// Variables filters of `chat` are evaluated with, on top of the message
+/// variables.
+fn filter_variables(
+    message_variables: Variables,
+    chat: &Chat,
+) -> Result<Variables, EvaluationError> {
+    let mut variables = message_variables;
+    variables.merge(
+        chat.variables.clone().into_namespace(USER_NAMESPACE),
+        MergePolicy::ErrorOnConflict,
+    )?;
+    variables.merge(
+        Variables::from(ChatVariables {
+            id: chat.chat_id,
+            title: chat.title.clone(),
+        }),
+        MergePolicy::ErrorOnConflict,
+    )?;
+
+    Ok(variables)
+}
+
+fn sender_id(message: &Message) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    match &message.from {
+        Some(from) => Ok(from.id.0 as i64),
+        None => Err(Box::new(GenericError::from("unknown sender".to_string()))),
+    }
+}
+
+/// User given by id in `arg`, or else the sender of the message replied to.
+fn target_user_id(message: &Message, arg: Option<&str>) -> Result<i64, String> {
+    match arg {
+        Some(arg) => arg
+            .trim()
+            .parse()
+            .map_err(|_| format!("error [{INVALID_INPUT}]: invalid user id \"{arg}\"")),
+        None => message
+            .reply_to_message()
+            .and_then(|reply| reply.from.as_ref())
+            .map(|from| from.id.0 as i64)
+            .ok_or_else(|| format!("error [{NO_REPLY_MESSAGE}]: no user id and no reply message")),
+    }
+}
+
+fn parse_minutes(arg: &str) -> Result<i64, String> {
+    match arg.trim().parse::<i64>() {
+        Ok(minutes) if minutes > 0 => Ok(minutes),
+        _ => Err(format!(
+            "error [{INVALID_INPUT}]: invalid number of minutes \"{arg}\""
+        )),
+    }
+}
+
+fn minutes_duration(minutes: i64) -> Duration {
+    Duration::from_secs((minutes as u64).saturating_mul(60))
+}
+
+/// Takes all permissions of a user for `minutes`.
+fn mute(user_id: UserId, minutes: i64) -> SendUpdate {
+    SendUpdate::RestrictUser {
+        user_id,
+        duration: minutes_duration(minutes),
+        permissions: ChatPermissions::empty(),
+    }
+}
+
+/// Mute for `/mute`, `arg` is `<minutes> [user_id]`.
+fn mute_command(message: &Message, arg: &str) -> Result<SendUpdate, String> {
+    let (minutes, user) = split_first_word(arg, char::is_whitespace);
+    let minutes = parse_minutes(minutes)?;
+    let user_id = target_user_id(message, user)?;
+
+    Ok(mute(UserId(user_id as u64), minutes))
+}
+
+/// Ban of the sender of the replied message for `/ban`, `arg` is the
+/// optional number of minutes.
+fn ban_command(message: &Message, arg: Option<&str>) -> Result<SendUpdate, String> {
+    let user_id = target_user_id(message, None)?;
+    let duration = arg.map(parse_minutes).transpose()?.map(minutes_duration);
+
+    Ok(SendUpdate::BanUser {
+        user_id: UserId(user_id as u64),
+        duration,
+    })
+}
+
+fn strip_hints(updates: &mut [SendUpdate]) {
+    for update in updates {
+        if let SendUpdate::Message { text, .. } = update {
+            *text = text
+                .lines()
+                .filter(|line| !line.starts_with("hint: "))
+                .collect::<Vec<&str>>()
+                .join("\n");
+        }
+    }
+}
+
+/// Replaces every `{name}` in `template` with the value of the variable, empty
+/// values with nothing. Unknown names are left as they are.
+fn render_template(template: &str, variables: &Variables) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = rest
+            .find('}')
+            .and_then(|end| Some((end, variables.get(&rest[1..end])?)));
+        match value {
+            Some((end, value)) => {
+                match value {
+                    Value::Str(text) => result.push_str(text),
+                    Value::Empty => {}
+                    value => result.push_str(&value.to_string()),
+                }
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/// Makes the messages sent in response to `message_id` replies to it, unless
+/// it is deleted right away. Notices about deleted messages name their sender
+/// instead.
+fn thread_replies(updates: &mut [SendUpdate], message_id: MessageId) {
+    let is_deleted = updates
+        .iter()
+        .any(|update| matches!(update, SendUpdate::DeleteMessage(id) if *id == message_id));
+    if is_deleted {
+        return;
+    }
+
+    for update in updates {
+        if let SendUpdate::Message { reply_to, .. } = update {
+            reply_to.get_or_insert(message_id);
+        }
+    }
+}
+
+/// `text` with the command prefix of the chat replaced by /, or empty if it is
+/// a / command for other bots.
+fn with_slash_prefix<'a>(text: &'a str, bot_username: &str, prefix: &str) -> Cow<'a, str> {
+    if prefix == "/" {
+        return Cow::Borrowed(text);
+    }
+    if let Some(rest) = text.strip_prefix(prefix) {
+        return Cow::Owned(format!("/{rest}"));
+    }
+
+    let (command, _) = split_first_word(text, char::is_whitespace);
+    match split_first_word(command, |c| c == '@') {
+        (_, Some(for_bot_username)) if for_bot_username == bot_username => Cow::Borrowed(text),
+        _ => Cow::Borrowed(""),
+    }
+}
+
+/// Commands of a message, one per line starting with / or `prefix`. Lines
+/// that don't start a command belong to the one before, so arguments can span
+/// lines. A text that doesn't start with a command is returned whole.
+fn split_commands<'a>(text: &'a str, prefix: &str) -> Vec<&'a str> {
+    let starts_command = |line: &str| line.starts_with('/') || line.starts_with(prefix);
+    if !starts_command(text) {
+        return vec![text];
+    }
+
+    let mut commands = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        if offset > 0 && starts_command(line) {
+            commands.push(text[start..offset].trim_end());
+            start = offset;
+        }
+        offset += line.len();
+    }
+    if start == 0 {
+        commands.push(text);
+    } else {
+        commands.push(text[start..].trim_end());
+    }
+
+    commands
+}
+
+/// `text` with the alias it starts with replaced by its command. Commands
+/// aliases expand to are not expanded again.
+fn expand_alias<'a>(
+    text: &'a str,
+    bot_username: &str,
+    aliases: &HashMap<String, String>,
+) -> Cow<'a, str> {
+    let Some(text_without_slash) = text.strip_prefix('/').filter(|rest| !rest.is_empty()) else {
+        return Cow::Borrowed(text);
+    };
+    let (command, arg) = split_first_word(text_without_slash, char::is_whitespace);
+    let (name, for_bot_username) = split_first_word(command, |c| c == '@');
+    if for_bot_username.is_some_and(|for_bot_username| for_bot_username != bot_username) {
+        return Cow::Borrowed(text);
+    }
+
+    match (aliases.get(name), arg) {
+        (Some(command), Some(arg)) => Cow::Owned(format!("{command} {arg}")),
+        (Some(command), None) => Cow::Owned(command.clone()),
+        (None, _) => Cow::Borrowed(text),
+    }
+}
+
+/// Name of a user variable given either unqualified or in the user namespace.
+fn user_variable_name(identifier: &str) -> Option<&str> {
+    match identifier.split_once(NAMESPACE_SEPARATOR) {
+        Some((USER_NAMESPACE, name)) if !name.contains(NAMESPACE_SEPARATOR) => Some(name),
+        Some(_) => None,
+        None => Some(identifier),
+    }
+}
+
+fn describe_actions(message: &Message, action: Action, effects: &[Effect]) -> String {
+    let mut res = match action {
+        Action::Delete => format!("deleting message {}", message.id),
+        Action::Warn => format!("warning about message {}", message.id),
+        Action::Mute(minutes) => format!(
+            "deleting message {} and muting its sender for {minutes} minutes",
+            message.id
+        ),
+        Action::Ban => format!("deleting message {} and banning its sender", message.id),
+        Action::Report => format!("reporting message {}", message.id),
+        Action::Quarantine => format!("quarantining message {}", message.id),
+    };
+    for effect in effects {
+        res.push_str(&format!("\n- {effect}"));
+    }
+
+    res
+}
+
+/// Evaluates filters in order until one matches and returns its name with the
+/// effects of its evaluation. A filter that fails or gives a non-bool value
+/// doesn't stop the evaluation, its error is added to `errors`.
+fn first_match<'a, T>(
+    filters: impl IntoIterator<Item = (&'a str, T)>,
+    mut evaluate: impl FnMut(T) -> (EvaluationResult, Vec<Effect>),
+    errors: &mut Vec<String>,
+) -> Option<(String, Vec<Effect>)> {
+    for (name, filter) in filters {
+        let (result, effects) = evaluate(filter);
+        if is_match(name, &result, errors) {
+            return Some((name.to_string(), effects));
+        }
+    }
+
+    None
+}
+
+/// Whether the evaluation of the filter `name` gave true. A failure or a
+/// non-bool value is added to `errors`.
+fn is_match(name: &str, result: &EvaluationResult, errors: &mut Vec<String>) -> bool {
+    match result {
+        Ok(Value::Bool(matched)) => *matched,
+        Ok(_) => {
+            errors.push(format!(
+                "error [{}]: filter {name} evaluated to non-bool value",
+                error_code::number("type_mismatch")
+            ));
+            false
+        }
+        Err(e) => {
+            errors.push(format!(
+                "error [{}]: failed to evaluate filter {name}: {e}",
+                e.number()
+            ));
+            false
+        }
+    }
+}
+
+fn describe_match(chat: &Chat, matched: Option<String>, errors: Vec<String>) -> String {
+    let mut lines = errors;
+    lines.push(match matched {
+        Some(name) => match chat.filter(&name) {
+            Some(filter) if filter.shadow => {
+                format!("shadow filter {name} matched, it would {}", filter.action)
+            }
+            filter => format!(
+                "filter {name} matched, its action is {}",
+                filter.map_or(Action::Delete, |filter| filter.action)
+            ),
+        },
+        None => "no filter matched".to_string(),
+    });
+
+    lines.join("\n")
+}
+
+fn describe_default_verdict(chat: &Chat) -> String {
+    match chat.settings.default_verdict() {
+        Verdict::Allow => "no filter enabled".to_string(),
+        Verdict::Deny => {
+            "no filter enabled and default_verdict is deny, the message would be deleted"
+                .to_string()
+        }
+    }
+}
+
+fn describe_verdict(chat: &Chat, message_variables: &Variables) -> String {
+    let title = chat
+        .title
+        .clone()
+        .unwrap_or_else(|| chat.chat_id.to_string());
+
+    if chat.enabled_filters().next().is_none() {
+        return format!("{title}: {}", describe_default_verdict(chat));
+    }
+
+    let verdict = match filter_variables(message_variables.clone(), chat) {
+        Ok(variables) => {
+            let mut errors = Vec::new();
+            let matched = first_match(
+                chat.enabled_filters()
+                    .map(|filter| (filter.name.as_str(), filter)),
+                |filter| {
+                    let evaluation = chat.evaluate(
+                        &filter.expression,
+                        &variables,
+                        &Sandbox::new(&EmptyEnvironment),
+                    );
+                    (evaluation, Vec::new())
+                },
+                &mut errors,
+            );
+            describe_match(chat, matched.map(|(name, _)| name), errors)
+        }
+        Err(e) => format!("error [{}]: failed to evaluate filters: {e}", e.number()),
+    };
+
+    if chat.settings.filter_enabled {
+        format!("{title}: {verdict}")
+    } else {
+        format!("{title}: {verdict} (filter is disabled)")
     }
 }
 
@@ -537,6 +4888,14 @@ impl CommandError {
             argument_is_expected,
         }
     }
+
+    /// Stable number of the error, see `error_code`.
+    fn number(&self) -> &'static str {
+        match self {
+            CommandError::InvalidCommand(_) => INVALID_COMMAND,
+            CommandError::InvalidArguments { .. } => INVALID_ARGUMENTS,
+        }
+    }
 }
 
 impl Display for CommandError {
@@ -561,14 +4920,69 @@ type CommandResult = Result<Option<Command>, CommandError>;
 
 enum Command {
     SetFilter(String),
-    GetFilter,
+    AddFilter(String),
+    RemoveFilter(String),
+    EnableFilter(String),
+    ShadowFilter(String),
+    EnforceFilter(String),
+    DisableFilter(String),
+    SetFilterAction(String),
+    SetFilterSchedule(String),
+    SetFilterExpiry(String),
+    FilterHistory,
+    RevertFilter(Option<String>),
+    ListPresets,
+    UsePreset(String),
+    ListFilters,
+    LintFilter,
+    GetFilter(Option<String>),
     SetOption(String),
     GetOptions,
+    GetOption(String),
+    SetFilteredMessage(Option<String>),
+    Alias(String),
+    Unalias(String),
+    ListAliases,
     SetVariable(String),
     UnsetVariable(String),
     GetVariables,
+    ExportVariables,
+    ImportVariables(String),
+    ExportConfig,
+    ImportConfig(Option<String>),
     GetMessageVariables,
+    Define(String),
+    Undefine(String),
+    GetDefinitions,
     Eval(String),
+    TestFilter(Option<String>),
+    Explain(Option<String>),
+    AddTest(String),
+    RemoveTest(String),
+    RunTests,
+    CloneChatConfig(String),
+    ModerationLog(Option<String>),
+    RecomputeStats,
+    Trust(Option<String>),
+    Untrust(Option<String>),
+    Exempt(Option<String>),
+    BlockDomain(String),
+    UnblockDomain(String),
+    ListDomains,
+    BlockForwardSource(String),
+    UnblockForwardSource(String),
+    ListForwardSources,
+    BlockMedia,
+    UnblockMedia,
+    Unexempt(Option<String>),
+    Mute(String),
+    Ban(Option<String>),
+    Kick,
+    Unban(Option<String>),
+    ProjectAdd(String),
+    ProjectRemove(String),
+    Stats(Option<String>),
+    ProjectStats(String),
     Help,
 }
 
@@ -580,15 +4994,21 @@ where
         let first_word = &text[..pos];
         let rest = &text[pos + 1..].trim_start();
         (first_word, if rest.is_empty() { None } else { Some(rest) })
-    } else if !text.is_empty() {
-        (text, None)
     } else {
-        panic!("cannot split empty text")
+        (text, None)
     }
 }
 
 impl Command {
-    fn new(text: &str, bot_username: &str) -> CommandResult {
+    fn new(
+        text: &str,
+        bot_username: &str,
+        prefix: &str,
+        aliases: &HashMap<String, String>,
+    ) -> CommandResult {
+        let text = with_slash_prefix(text, bot_username, prefix);
+        let text = expand_alias(&text, bot_username, aliases);
+        let text = text.as_ref();
         if let Some(ch) = text.chars().nth(0) {
             if ch == '/' {
                 let (command, arg) = split_first_word(text, char::is_whitespace);
@@ -598,12 +5018,135 @@ impl Command {
                     if for_bot_username != bot_username {
                         return Ok(None);
                     }
-                }
-
-                match command {
-                    "/set_filter" => {
+                }
+
+                match command {
+                    "/set_filter" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::SetFilter(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/add_filter" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::AddFilter(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/remove_filter" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::RemoveFilter(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/enable_filter" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::EnableFilter(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/shadow_filter" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::ShadowFilter(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/enforce_filter" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::EnforceFilter(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/disable_filter" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::DisableFilter(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/set_filter_action" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::SetFilterAction(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/set_filter_schedule" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::SetFilterSchedule(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/set_filter_expiry" => {
                         if let Some(arg) = arg {
-                            Ok(Some(Command::SetFilter(arg.to_string())))
+                            Ok(Some(Command::SetFilterExpiry(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/filter_history" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::FilterHistory))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/revert_filter" => {
+                        Ok(Some(Command::RevertFilter(arg.map(|arg| arg.to_string()))))
+                    }
+                    "/list_presets" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::ListPresets))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/use_preset" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::UsePreset(arg.to_string())))
                         } else {
                             Err(CommandError::new_invalid_arguments(
                                 command.to_string(),
@@ -611,9 +5154,20 @@ impl Command {
                             ))
                         }
                     }
-                    "/get_filter" => {
-                        if let None = arg {
-                            Ok(Some(Command::GetFilter))
+                    "/list_filters" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::ListFilters))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/get_filter" => Ok(Some(Command::GetFilter(arg.map(|arg| arg.to_string())))),
+                    "/lint_filter" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::LintFilter))
                         } else {
                             Err(CommandError::new_invalid_arguments(
                                 command.to_string(),
@@ -632,7 +5186,7 @@ impl Command {
                         }
                     }
                     "/get_options" => {
-                        if let None = arg {
+                        if arg.is_none() {
                             Ok(Some(Command::GetOptions))
                         } else {
                             Err(CommandError::new_invalid_arguments(
@@ -641,6 +5195,49 @@ impl Command {
                             ))
                         }
                     }
+                    "/get_option" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::GetOption(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/set_filtered_message" => Ok(Some(Command::SetFilteredMessage(
+                        arg.map(|arg| arg.to_string()),
+                    ))),
+                    "/alias" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::Alias(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/unalias" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::Unalias(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/list_aliases" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::ListAliases))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
                     "/set_variable" => {
                         if let Some(arg) = arg {
                             Ok(Some(Command::SetVariable(arg.to_string())))
@@ -662,7 +5259,7 @@ impl Command {
                         }
                     }
                     "/get_variables" => {
-                        if let None = arg {
+                        if arg.is_none() {
                             Ok(Some(Command::GetVariables))
                         } else {
                             Err(CommandError::new_invalid_arguments(
@@ -671,8 +5268,41 @@ impl Command {
                             ))
                         }
                     }
+                    "/export_variables" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::ExportVariables))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/export_config" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::ExportConfig))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/import_config" => {
+                        Ok(Some(Command::ImportConfig(arg.map(|arg| arg.to_string()))))
+                    }
+                    "/import_variables" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::ImportVariables(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
                     "/get_message_variables" => {
-                        if let None = arg {
+                        if arg.is_none() {
                             Ok(Some(Command::GetMessageVariables))
                         } else {
                             Err(CommandError::new_invalid_arguments(
@@ -681,6 +5311,36 @@ impl Command {
                             ))
                         }
                     }
+                    "/define" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::Define(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/undefine" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::Undefine(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/get_definitions" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::GetDefinitions))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
                     "/eval" => {
                         if let Some(arg) = arg {
                             Ok(Some(Command::Eval(arg.to_string())))
@@ -691,8 +5351,200 @@ impl Command {
                             ))
                         }
                     }
+                    "/test_filter" => Ok(Some(Command::TestFilter(arg.map(|arg| arg.to_string())))),
+                    "/explain" => Ok(Some(Command::Explain(arg.map(|arg| arg.to_string())))),
+                    "/add_test" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::AddTest(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/remove_test" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::RemoveTest(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/run_tests" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::RunTests))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/clone_chat_config" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::CloneChatConfig(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/moderation_log" => {
+                        Ok(Some(Command::ModerationLog(arg.map(|arg| arg.to_string()))))
+                    }
+                    "/recompute_stats" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::RecomputeStats))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/trust" => Ok(Some(Command::Trust(arg.map(|arg| arg.to_string())))),
+                    "/untrust" => Ok(Some(Command::Untrust(arg.map(|arg| arg.to_string())))),
+                    "/exempt" => Ok(Some(Command::Exempt(arg.map(|arg| arg.to_string())))),
+                    "/unexempt" => Ok(Some(Command::Unexempt(arg.map(|arg| arg.to_string())))),
+                    "/block_domain" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::BlockDomain(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/unblock_domain" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::UnblockDomain(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/block_forward_source" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::BlockForwardSource(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/unblock_forward_source" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::UnblockForwardSource(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/block_media" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::BlockMedia))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/unblock_media" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::UnblockMedia))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/list_forward_sources" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::ListForwardSources))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/list_domains" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::ListDomains))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/ban" => Ok(Some(Command::Ban(arg.map(|arg| arg.to_string())))),
+                    "/kick" => {
+                        if arg.is_none() {
+                            Ok(Some(Command::Kick))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                false,
+                            ))
+                        }
+                    }
+                    "/unban" => Ok(Some(Command::Unban(arg.map(|arg| arg.to_string())))),
+                    "/mute" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::Mute(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/project_add" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::ProjectAdd(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/project_remove" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::ProjectRemove(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
+                    "/stats" => Ok(Some(Command::Stats(arg.map(|arg| arg.to_string())))),
+                    "/project_stats" => {
+                        if let Some(arg) = arg {
+                            Ok(Some(Command::ProjectStats(arg.to_string())))
+                        } else {
+                            Err(CommandError::new_invalid_arguments(
+                                command.to_string(),
+                                true,
+                            ))
+                        }
+                    }
                     "/help" => {
-                        if let None = arg {
+                        if arg.is_none() {
                             Ok(Some(Command::Help))
                         } else {
                             Err(CommandError::new_invalid_arguments(
@@ -714,15 +5566,74 @@ impl Command {
     fn requires_admin_rights(&self) -> bool {
         match self {
             Command::SetFilter(_) => true,
+            Command::AddFilter(_) => true,
+            Command::RemoveFilter(_) => true,
+            Command::EnableFilter(_) => true,
+            Command::ShadowFilter(_) => true,
+            Command::EnforceFilter(_) => true,
+            Command::DisableFilter(_) => true,
+            Command::SetFilterAction(_) => true,
+            Command::SetFilterSchedule(_) => true,
+            Command::SetFilterExpiry(_) => true,
+            Command::FilterHistory => false,
+            Command::RevertFilter(_) => true,
+            Command::ListPresets => false,
+            Command::UsePreset(_) => true,
+            Command::ListFilters => false,
+            Command::LintFilter => false,
             Command::SetOption(_) => true,
             Command::GetMessageVariables => false,
             Command::Help => false,
             Command::SetVariable(_) => true,
             Command::UnsetVariable(_) => true,
             Command::GetVariables => false,
+            Command::ExportVariables => false,
+            Command::ImportVariables(_) => true,
+            Command::ExportConfig => true,
+            Command::ImportConfig(_) => true,
             Command::GetOptions => false,
-            Command::GetFilter => false,
+            Command::GetOption(_) => false,
+            Command::SetFilteredMessage(_) => true,
+            Command::Alias(_) => true,
+            Command::Unalias(_) => true,
+            Command::ListAliases => false,
+            Command::GetFilter(_) => false,
+            Command::Define(_) => true,
+            Command::Undefine(_) => true,
+            Command::GetDefinitions => false,
             Command::Eval(_) => false,
+            Command::TestFilter(_) => false,
+            Command::Explain(_) => false,
+            Command::AddTest(_) => true,
+            Command::RemoveTest(_) => true,
+            Command::RunTests => false,
+            Command::CloneChatConfig(_) => false,
+            Command::ModerationLog(_) => true,
+            Command::RecomputeStats => true,
+            Command::Trust(_) => true,
+            Command::Untrust(_) => true,
+            Command::Exempt(_) => true,
+            Command::Unexempt(_) => true,
+            Command::BlockDomain(_) => true,
+            Command::UnblockDomain(_) => true,
+            Command::ListDomains => false,
+            Command::BlockForwardSource(_) => true,
+            Command::UnblockForwardSource(_) => true,
+            Command::ListForwardSources => false,
+            Command::BlockMedia => true,
+            Command::UnblockMedia => true,
+            Command::Mute(_) => true,
+            Command::Ban(_) => true,
+            Command::Kick => true,
+            Command::Unban(_) => true,
+            Command::ProjectAdd(_) => false,
+            Command::ProjectRemove(_) => false,
+            Command::Stats(_) => true,
+            Command::ProjectStats(_) => false,
         }
     }
+
+    fn requires_owner_rights(&self) -> bool {
+        matches!(self, Command::CloneChatConfig(_))
+    }
 }
@@ -0,0 +1,104 @@
+use super::{
+    database::{Db, SECONDS_PER_DAY},
+    digest::Digest,
+};
+use serde::Serialize;
+use std::{error::Error, path::Path};
+
+pub const STATUS_PAGE_DAYS: i64 = 30;
+
+/// Public moderation statistics of a chat. Unlike the digest it leaves out
+/// offenders, since it is readable by anyone.
+#[derive(Serialize, Debug)]
+pub struct StatusReport {
+    chat_id: i64,
+    title: Option<String>,
+    days: i64,
+    filtered: i64,
+    restored: i64,
+    top_filters: Vec<(String, i64)>,
+}
+
+impl StatusReport {
+    pub fn new(chat_id: i64, title: Option<String>, digest: Digest) -> Self {
+        StatusReport {
+            chat_id,
+            title,
+            days: digest.days,
+            filtered: digest.filtered,
+            restored: digest.restored,
+            top_filters: digest.top_filters,
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let title = match &self.title {
+            Some(title) => escape_html(title),
+            None => self.chat_id.to_string(),
+        };
+
+        let mut filters = String::new();
+        for (name, count) in &self.top_filters {
+            filters.push_str(&format!("<li>{}: {count}</li>", escape_html(name)));
+        }
+
+        format!(
+            "<!DOCTYPE html>\n\
+             <html>\n\
+             <head><meta charset=\"utf-8\"><title>{title} moderation report</title></head>\n\
+             <body>\n\
+             <h1>{title}</h1>\n\
+             <p>moderation report for the last {} day(s)</p>\n\
+             <p>messages filtered: {}</p>\n\
+             <p>false positives restored: {}</p>\n\
+             <ul>{filters}</ul>\n\
+             </body>\n\
+             </html>\n",
+            self.days, self.filtered, self.restored
+        )
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '"' => result.push_str("&quot;"),
+            '\'' => result.push_str("&#39;"),
+            _ => result.push(ch),
+        }
+    }
+    result
+}
+
+/// Writes `<chat_id>.json` and `<chat_id>.html` to `directory` for every chat
+/// that opted in with the `public_stats` option, `now` is in unix seconds.
+pub async fn write_status_pages(
+    db: &Db,
+    directory: &Path,
+    now: i64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let first_day = now.div_euclid(SECONDS_PER_DAY) - STATUS_PAGE_DAYS + 1;
+
+    for chat in db.find_chats_with_public_stats().await? {
+        let stats = db.find_stats_since(chat.chat_id, first_day).await?;
+        let report = StatusReport::new(
+            chat.chat_id,
+            chat.title,
+            Digest::from_stats(STATUS_PAGE_DAYS, &stats),
+        );
+
+        let path = directory.join(chat.chat_id.to_string());
+        tokio::fs::write(
+            path.with_extension("json"),
+            serde_json::to_string_pretty(&report)?,
+        )
+        .await?;
+        tokio::fs::write(path.with_extension("html"), report.to_html()).await?;
+    }
+
+    Ok(())
+}